@@ -147,7 +147,6 @@ echo "Mount options: ${MOUNT_OPTS[@]}"
 
 docker run "-${DD}" --rm \
     -p 8080:8080 \
-    -p 6080:6080 \
     -e HOST_UID="$(id -u)" \
     -e HOST_GID="$(id -g)" \
     -e HOST_USER="$(whoami)" \
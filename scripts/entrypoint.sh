@@ -21,17 +21,6 @@ useradd -m -u "$HOST_UID" -g "$HOST_GID" -s /bin/bash "$HOST_USER" 2>/dev/null |
 
 USER_HOME="/home/$HOST_USER"
 
-# Provision workspace (mount or checkout) before copying configs
-# This ensures WORKSPACE_PATH is available for agent execution
-if [ -f /app/scripts/provision-workspace.sh ]; then
-  echo "Provisioning workspace..."
-  source /app/scripts/provision-workspace.sh
-  WORKSPACE_PATH=${WORKSPACE_PATH:-/repo}
-  echo "Workspace path: $WORKSPACE_PATH"
-else
-  WORKSPACE_PATH=/repo
-fi
-
 # Copy config files to user's home
 # setup gitconfig
 if [ -f /tmp/host-gitconfig ]; then
@@ -162,6 +151,6 @@ export PATH="/usr/local/bin:/usr/bin:/bin:\$PATH"
 BASHRC
 chown "$HOST_UID:$HOST_GID" "$USER_HOME/.bashrc"
 
-# Use 'su' without '-' to preserve more environment, but still set critical vars
-# Pass WORKSPACE_PATH to vnccc so it can use the correct workspace
-exec su "$HOST_USER" -c "export HOME=$USER_HOME && export PATH=/usr/local/bin:/usr/bin:/bin:\$PATH && export WORKSPACE_PATH=$WORKSPACE_PATH && /usr/local/bin/vnccc $*"
+# Use 'su' without '-' to preserve more environment, but still set critical vars.
+# vnccc provisions the workspace itself from the WORKSPACE_* variables, as this user.
+exec su "$HOST_USER" -c "export HOME=$USER_HOME && export PATH=/usr/local/bin:/usr/bin:/bin:\$PATH && /usr/local/bin/vnccc $*"
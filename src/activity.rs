@@ -0,0 +1,313 @@
+//! Whether a session's agent is working, waiting for input or asking a
+//! question. Inferred from the bottom of its screen when the terminal can
+//! tell us what it shows, from the CPU use of the terminal's process tree,
+//! and from changes to the screen: its text, or its pixels when the text
+//! cannot be read.
+
+use serde::Serialize;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use crate::config;
+
+/// Lines at the bottom of the screen the recognizers look at.
+const SCREEN_LINES: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentState {
+    Busy,
+    /// Waiting for the next prompt.
+    Idle,
+    /// Asking something, e.g. for permission to run a command.
+    Attention,
+    /// Stopped with SIGSTOP by the user.
+    Paused,
+    /// No terminal running, or the session is asleep.
+    Offline,
+}
+
+impl AgentState {
+    pub fn name(self) -> &'static str {
+        match self {
+            AgentState::Busy => "busy",
+            AgentState::Idle => "idle",
+            AgentState::Attention => "attention",
+            AgentState::Paused => "paused",
+            AgentState::Offline => "offline",
+        }
+    }
+}
+
+/// What an agent's state is worked out from. Questions are only recognized
+/// in the screen's text, which not every terminal can provide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Watch {
+    /// The text on screen, plus CPU use.
+    Text,
+    /// Screenshots of the display, plus CPU use.
+    Pixels,
+    /// CPU use alone.
+    Cpu,
+}
+
+/// Text each agent shows while working, while waiting for a prompt and when
+/// it asks a question. Agents not listed only get the generic busy hints.
+fn builtin_patterns(agent: &str) -> [&'static [&'static str]; 3] {
+    match agent {
+        "claude" => [
+            &["esc to interrupt"],
+            &["? for shortcuts"],
+            &[
+                "do you want to proceed?",
+                "do you want to make this edit",
+                "do you want to create",
+            ],
+        ],
+        "codex" => [
+            &["esc to interrupt"],
+            &[],
+            &["allow command?", "yes, proceed"],
+        ],
+        "gemini" | "qwen-code" => [
+            &["esc to cancel"],
+            &["type your message"],
+            &["allow execution", "waiting for user confirmation"],
+        ],
+        _ => [&["esc to interrupt", "esc to cancel"], &[], &[]],
+    }
+}
+
+/// Patterns for one agent, built in and configured, lowercased.
+#[derive(Debug, Clone, Default)]
+pub struct Recognizer {
+    busy: Vec<String>,
+    idle: Vec<String>,
+    attention: Vec<String>,
+}
+
+impl Recognizer {
+    pub fn new(agent: &str, config: &config::Activity) -> Recognizer {
+        let [busy, idle, attention] = builtin_patterns(agent);
+        let mut recognizer = Recognizer {
+            busy: busy.iter().map(|p| p.to_string()).collect(),
+            idle: idle.iter().map(|p| p.to_string()).collect(),
+            attention: attention.iter().map(|p| p.to_string()).collect(),
+        };
+        for name in ["*", agent] {
+            if let Some(patterns) = config.patterns.get(name) {
+                let lower = |list: &[String]| -> Vec<String> {
+                    list.iter().map(|p| p.to_lowercase()).collect()
+                };
+                recognizer.busy.extend(lower(&patterns.busy));
+                recognizer.idle.extend(lower(&patterns.idle));
+                recognizer.attention.extend(lower(&patterns.attention));
+            }
+        }
+        recognizer
+    }
+
+    /// What the bottom of `screen` says about the agent, if anything. A
+    /// question beats a busy hint, which beats an idle one.
+    pub fn recognize(&self, screen: &str) -> Option<AgentState> {
+        let lines: Vec<&str> = screen.lines().filter(|l| !l.trim().is_empty()).collect();
+        let bottom = lines[lines.len().saturating_sub(SCREEN_LINES)..]
+            .join("\n")
+            .to_lowercase();
+        let shows = |patterns: &[String]| patterns.iter().any(|p| bottom.contains(p.as_str()));
+        if shows(&self.attention) {
+            Some(AgentState::Attention)
+        } else if shows(&self.busy) {
+            Some(AgentState::Busy)
+        } else if shows(&self.idle) {
+            Some(AgentState::Idle)
+        } else {
+            None
+        }
+    }
+}
+
+/// One look at the agent.
+pub struct Observation<'a> {
+    /// CPU % of the terminal's process tree since the last look.
+    pub cpu_percent: f64,
+    /// Text on screen, when the terminal can capture it.
+    pub screen: Option<&'a str>,
+    /// Hash of the screen's pixels, used when there is no text.
+    pub pixels: Option<u64>,
+}
+
+impl Observation<'_> {
+    pub fn watch(&self) -> Watch {
+        match (self.screen, self.pixels) {
+            (Some(_), _) => Watch::Text,
+            (None, Some(_)) => Watch::Pixels,
+            (None, None) => Watch::Cpu,
+        }
+    }
+}
+
+/// Turns observations a few seconds apart into an [`AgentState`].
+pub struct Tracker {
+    recognizer: Recognizer,
+    cpu_percent: f64,
+    idle_after: Duration,
+    /// Last time a prompt was sent.
+    prompted_at: Instant,
+    /// Last time the agent was seen working.
+    busy_at: Instant,
+    screen: Option<u64>,
+}
+
+impl Tracker {
+    pub fn new(recognizer: Recognizer, config: &config::Activity) -> Tracker {
+        let now = Instant::now();
+        Tracker {
+            recognizer,
+            cpu_percent: config.cpu_percent,
+            idle_after: Duration::from_secs(config.idle_seconds),
+            prompted_at: now,
+            busy_at: now,
+            screen: None,
+        }
+    }
+
+    /// A prompt was just typed in; the agent is about to get busy.
+    pub fn prompted(&mut self, now: Instant) {
+        self.prompted_at = now;
+        self.busy_at = now;
+    }
+
+    pub fn observe(&mut self, observation: &Observation, now: Instant) -> AgentState {
+        let recognized = observation
+            .screen
+            .and_then(|screen| self.recognizer.recognize(screen));
+        let screen = observation
+            .screen
+            .map(|screen| {
+                let mut hasher = DefaultHasher::new();
+                screen.hash(&mut hasher);
+                hasher.finish()
+            })
+            .or(observation.pixels);
+        let changed = screen.is_some() && self.screen.is_some() && screen != self.screen;
+        self.screen = screen;
+        if changed || observation.cpu_percent >= self.cpu_percent {
+            self.busy_at = now;
+        }
+        let settled = |since: Instant| now.duration_since(since) >= self.idle_after;
+        match recognized {
+            Some(AgentState::Attention) => AgentState::Attention,
+            Some(AgentState::Busy) => {
+                self.busy_at = now;
+                AgentState::Busy
+            }
+            // The prompt box shows while other things in the terminal, like
+            // a dev server the agent started, keep the CPU busy
+            Some(AgentState::Idle) if !changed && settled(self.prompted_at) => AgentState::Idle,
+            _ if settled(self.busy_at) => AgentState::Idle,
+            _ => AgentState::Busy,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> config::Activity {
+        let mut config = config::Activity::default();
+        config.patterns.insert(
+            "claude".to_string(),
+            config::Patterns {
+                attention: vec!["Approve?".to_string()],
+                ..Default::default()
+            },
+        );
+        config
+    }
+
+    #[test]
+    fn test_recognize() {
+        let claude = Recognizer::new("claude", &config());
+        let screen = |bottom: &str| {
+            format!("Do you want to proceed?\n{}\n", "old output\n".repeat(30)) + bottom
+        };
+        assert_eq!(
+            claude.recognize(&screen("✻ Working… (esc to interrupt)")),
+            Some(AgentState::Busy)
+        );
+        assert_eq!(
+            claude.recognize(&screen("> \n  ? for shortcuts")),
+            Some(AgentState::Idle)
+        );
+        assert_eq!(
+            claude.recognize("Run it?\n APPROVE?\n esc to interrupt"),
+            Some(AgentState::Attention)
+        );
+        assert_eq!(claude.recognize(&screen("")), None);
+        let other = Recognizer::new("crush", &config());
+        assert_eq!(other.recognize("ESC to cancel"), Some(AgentState::Busy));
+        assert_eq!(other.recognize("Approve?"), None);
+    }
+
+    #[test]
+    fn test_tracker() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut tracker = Tracker::new(Recognizer::new("claude", &config()), &config());
+        let look =
+            |cpu_percent: f64, screen: Option<&'static str>, pixels: Option<u64>| Observation {
+                cpu_percent,
+                screen,
+                pixels,
+            };
+
+        assert_eq!(look(0.0, Some(""), Some(1)).watch(), Watch::Text);
+        assert_eq!(look(0.0, None, Some(1)).watch(), Watch::Pixels);
+        assert_eq!(look(0.0, None, None).watch(), Watch::Cpu);
+
+        // Pixels changing or CPU in use keep it busy until things settle
+        assert_eq!(
+            tracker.observe(&look(0.0, None, Some(1)), at(2)),
+            AgentState::Busy
+        );
+        assert_eq!(
+            tracker.observe(&look(0.0, None, Some(2)), at(4)),
+            AgentState::Busy
+        );
+        assert_eq!(
+            tracker.observe(&look(50.0, None, Some(2)), at(8)),
+            AgentState::Busy
+        );
+        assert_eq!(
+            tracker.observe(&look(0.0, None, Some(2)), at(11)),
+            AgentState::Busy
+        );
+        assert_eq!(
+            tracker.observe(&look(0.0, None, Some(2)), at(13)),
+            AgentState::Idle
+        );
+
+        // The prompt box means idle despite CPU use, once the prompt settled
+        tracker.prompted(at(20));
+        let idle = Some("> \n ? for shortcuts");
+        assert_eq!(
+            tracker.observe(&look(90.0, idle, None), at(22)),
+            AgentState::Busy
+        );
+        assert_eq!(
+            tracker.observe(&look(90.0, idle, None), at(26)),
+            AgentState::Idle
+        );
+        assert_eq!(
+            tracker.observe(&look(0.0, Some("esc to interrupt"), None), at(40)),
+            AgentState::Busy
+        );
+        assert_eq!(
+            tracker.observe(&look(0.0, Some("Do you want to proceed?"), None), at(42)),
+            AgentState::Attention
+        );
+    }
+}
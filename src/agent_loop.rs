@@ -0,0 +1,248 @@
+//! `vnccc agent-loop`: runs inside the terminal and keeps the agent going.
+//!
+//! The agent is started in its own process group, which is handed the
+//! terminal so Ctrl+C and job control reach it. The group id is written to the
+//! session's control directory so the daemon can signal the whole agent tree
+//! (restart, pause, resume) without touching the terminal.
+//!
+//! Resource limits are set on the loop itself, before the first agent starts,
+//! so every agent inherits them: `--cgroup` joins the session's cgroup and
+//! each `--rlimit name=value` sets an rlimit.
+//!
+//! `--sandbox-rw`/`--sandbox-ro` paths turn on the Landlock sandbox. Unlike the
+//! limits it is applied to each agent just before exec, so the loop itself can
+//! still read the control directory. `--sandbox-tmp` gives the agent a private
+//! TMPDIR inside the sandbox. If the sandbox cannot be set up the agent is not
+//! started at all.
+
+use std::fs;
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use crate::config;
+use crate::limits::{self, Rlimit};
+use crate::sandbox::{self, Rules, Ruleset};
+
+/// File in the control directory holding the agent's process group id.
+pub const PGID_FILE: &str = "agent.pgid";
+/// While this file exists in the control directory the agent is not restarted.
+pub const HOLD_FILE: &str = "hold";
+
+/// Quote `s` for a POSIX shell command line.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Process group recorded in `control_dir`, if that group still exists.
+pub fn read_pgid(control_dir: &Path) -> Option<i32> {
+    let pgid: i32 = fs::read_to_string(control_dir.join(PGID_FILE))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    // SAFETY: signal 0 only checks that the group exists
+    let alive = pgid > 1 && unsafe { libc::kill(-pgid, 0) } == 0;
+    alive.then_some(pgid)
+}
+
+const USAGE: &str = "Usage: vnccc agent-loop [--cgroup PATH] [--rlimit NAME=VALUE]... \
+                     [--sandbox-rw PATH]... [--sandbox-ro PATH]... [--sandbox-tmp PATH] \
+                     <control-dir> <command>";
+
+/// Entry point for `vnccc agent-loop`. Never returns.
+pub fn run(args: &[String]) -> ! {
+    let mut cgroup: Option<PathBuf> = None;
+    let mut rlimits = Vec::new();
+    let mut sandbox: Option<Rules> = None;
+    let mut sandbox_tmp: Option<PathBuf> = None;
+    let mut rest = args;
+    loop {
+        match rest {
+            [flag, value, tail @ ..] if flag == "--cgroup" => {
+                cgroup = Some(PathBuf::from(value));
+                rest = tail;
+            }
+            [flag, value, tail @ ..] if flag == "--rlimit" => {
+                match Rlimit::parse(value) {
+                    Ok(rlimit) => rlimits.push(rlimit),
+                    Err(e) => {
+                        eprintln!("{}\n{}", e, USAGE);
+                        std::process::exit(2);
+                    }
+                }
+                rest = tail;
+            }
+            [flag, value, tail @ ..] if flag == "--sandbox-rw" || flag == "--sandbox-ro" => {
+                sandbox.get_or_insert_default().parse_flag(flag, value);
+                rest = tail;
+            }
+            [flag, value, tail @ ..] if flag == "--sandbox-tmp" => {
+                sandbox_tmp = Some(PathBuf::from(value));
+                rest = tail;
+            }
+            _ => break,
+        }
+    }
+    let [control_dir, command] = rest else {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    };
+    let control_dir = Path::new(control_dir);
+    if let Err(e) = fs::create_dir_all(control_dir) {
+        eprintln!("Failed to create {}: {}", control_dir.display(), e);
+        std::process::exit(1);
+    }
+
+    if let Some(cgroup) = &cgroup
+        && let Err(e) = limits::join_cgroup(cgroup)
+    {
+        eprintln!("{}", e);
+    }
+    for rlimit in &rlimits {
+        if let Err(e) = rlimit.apply() {
+            eprintln!(
+                "Failed to set {} limit to {}: {}",
+                rlimit.name, rlimit.value, e
+            );
+        }
+    }
+
+    if let Some(tmp) = &sandbox_tmp {
+        if let Err(e) = fs::create_dir_all(tmp) {
+            eprintln!("Failed to create {}: {}", tmp.display(), e);
+        }
+        sandbox.get_or_insert_default().read_write.push(tmp.clone());
+    }
+    let ruleset = sandbox.as_ref().map(|rules| match Ruleset::build(rules) {
+        Ok(ruleset) => {
+            print_sandbox(rules, &ruleset);
+            ruleset
+        }
+        Err(e) => {
+            // Running the agent unconfined when a sandbox was asked for
+            // would be worse than not running it
+            eprintln!("\r\n{}\r\nNot starting the agent without its sandbox.", e);
+            loop {
+                thread::sleep(Duration::from_secs(3600));
+            }
+        }
+    });
+    let sandbox_fd = ruleset.as_ref().map(|r| r.as_raw_fd());
+
+    // Giving the terminal to another group from here would otherwise stop us
+    // SAFETY: installing SIG_IGN has no memory-safety preconditions
+    unsafe {
+        libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+    }
+
+    loop {
+        if control_dir.join(HOLD_FILE).exists() {
+            println!("\r\nAgent stopped. Resume it from the web UI to start it again.");
+            while control_dir.join(HOLD_FILE).exists() {
+                thread::sleep(Duration::from_millis(500));
+            }
+            println!("Resuming agent...");
+        }
+
+        let mut cmd = Command::new("bash");
+        cmd.args(["-c", command]);
+        if let Some(tmp) = &sandbox_tmp {
+            cmd.env("TMPDIR", tmp);
+        }
+        // SAFETY: only async-signal-safe libc calls between fork and exec
+        unsafe {
+            cmd.pre_exec(move || {
+                libc::setpgid(0, 0);
+                // Take the terminal before exec so the agent never reads from
+                // a background group and gets stopped by SIGTTIN
+                libc::tcsetpgrp(0, libc::getpid());
+                libc::signal(libc::SIGTTOU, libc::SIG_DFL);
+                match sandbox_fd {
+                    Some(fd) => sandbox::restrict_self(fd),
+                    None => Ok(()),
+                }
+            });
+        }
+
+        match cmd.spawn() {
+            Ok(mut child) => {
+                let pgid = child.id() as i32;
+                // Also set from this side, whichever runs first wins the race
+                // SAFETY: plain syscalls on our own child
+                unsafe {
+                    libc::setpgid(pgid, pgid);
+                    libc::tcsetpgrp(0, pgid);
+                }
+                let pgid_path = control_dir.join(PGID_FILE);
+                if let Err(e) = config::write_atomic(&pgid_path, &pgid.to_string()) {
+                    eprintln!("{}", e);
+                }
+
+                let status = child.wait();
+                let _ = fs::remove_file(&pgid_path);
+                // SAFETY: reclaiming the terminal for our own group
+                unsafe {
+                    libc::tcsetpgrp(0, libc::getpgrp());
+                }
+                match status {
+                    Ok(status) => println!("\r\nAgent exited ({}), restarting...", status),
+                    Err(e) => eprintln!("\r\nError waiting for agent: {}", e),
+                }
+            }
+            Err(e) => eprintln!("Failed to start agent: {}", e),
+        }
+
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+fn print_sandbox(rules: &Rules, ruleset: &Ruleset) {
+    let list = |paths: &[PathBuf]| {
+        paths
+            .iter()
+            .filter(|p| !ruleset.skipped.contains(p))
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    println!("Sandbox read-write: {}\r", list(&rules.read_write));
+    println!("Sandbox read-only: {}\r", list(&rules.read_only));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("/repo"), "'/repo'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        let output = Command::new("sh")
+            .args(["-c", &format!("printf %s {}", shell_quote("a b'c $HOME"))])
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "a b'c $HOME");
+    }
+
+    #[test]
+    fn test_read_pgid() {
+        let dir = env::temp_dir().join(format!("vnccc-test-pgid-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(read_pgid(&dir), None);
+
+        // SAFETY: getpgrp has no preconditions
+        let own = unsafe { libc::getpgrp() };
+        fs::write(dir.join(PGID_FILE), format!("{}\n", own)).unwrap();
+        assert_eq!(read_pgid(&dir), Some(own));
+
+        fs::write(dir.join(PGID_FILE), "garbage").unwrap();
+        assert_eq!(read_pgid(&dir), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,321 @@
+//! Workspace checkpoints: a snapshot of the repo taken before each prompt,
+//! kept as a private ref under `refs/vncaa/checkpoints/`.
+//!
+//! Snapshots go through a temporary index, so the user's index, branch and
+//! stash are never touched. Tracked and untracked files are included, ignored
+//! files are not.
+
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::git::{self, Diff, TempIndex, git, toplevel};
+
+pub const REF_PREFIX: &str = "refs/vncaa/checkpoints/";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Checkpoint {
+    /// Creation time in milliseconds, also the ref name.
+    pub id: String,
+    pub commit: String,
+    /// Unix seconds.
+    pub created: u64,
+    /// The prompt sent right after the snapshot was taken.
+    pub prompt: String,
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
+
+/// Tree object of the work tree as it is now.
+fn snapshot_tree(top: &Path) -> Result<String, String> {
+    let index = TempIndex::copy_of_index(top)?;
+    index.git(top, &["add", "--all", "--", "."])?;
+    Ok(index.git(top, &["write-tree"])?.trim().to_string())
+}
+
+/// Snapshot the work tree containing `repo` before `prompt` is sent, keeping
+/// the newest `keep` checkpoints. `None` when `repo` is not in a git repo.
+pub fn create(repo: &Path, prompt: &str, keep: usize) -> Result<Option<Checkpoint>, String> {
+    let Some(top) = toplevel(repo) else {
+        return Ok(None);
+    };
+    let tree = snapshot_tree(&top)?;
+    let mut args = vec!["commit-tree", tree.as_str(), "-F", "-"];
+    let head = git::head(&top);
+    if let Some(head) = &head {
+        args.extend(["-p", head]);
+    }
+    let mut cmd = git::command(&top, &args);
+    cmd.env("GIT_AUTHOR_NAME", "vncaa")
+        .env("GIT_AUTHOR_EMAIL", "vncaa@localhost")
+        .env("GIT_COMMITTER_NAME", "vncaa")
+        .env("GIT_COMMITTER_EMAIL", "vncaa@localhost");
+    let commit = git::run(cmd, Some(prompt.as_bytes()))?.trim().to_string();
+
+    // An empty old value makes update-ref refuse to replace an existing ref,
+    // so two checkpoints in the same millisecond get consecutive ids
+    let mut millis = now_millis();
+    while git(
+        &top,
+        &[
+            "update-ref",
+            &format!("{}{}", REF_PREFIX, millis),
+            &commit,
+            "",
+        ],
+    )
+    .is_err()
+    {
+        if resolve(&top, &millis.to_string()).is_err() {
+            return Err("Failed to store checkpoint".to_string());
+        }
+        millis += 1;
+    }
+    let id = millis.to_string();
+    prune(&top, keep)?;
+    Ok(Some(Checkpoint {
+        id,
+        commit,
+        created: (millis / 1000) as u64,
+        prompt: prompt.to_string(),
+    }))
+}
+
+/// Parse `git for-each-ref` output in the format used by `list`.
+fn parse_refs(output: &str) -> Vec<Checkpoint> {
+    output
+        .split("\0\n")
+        .filter_map(|record| {
+            let mut fields = record.splitn(4, '\0');
+            let id = fields.next()?.trim_start_matches('\n').to_string();
+            if id.is_empty() {
+                return None;
+            }
+            Some(Checkpoint {
+                id,
+                commit: fields.next()?.to_string(),
+                created: fields.next()?.parse().ok()?,
+                prompt: fields.next()?.trim_end().to_string(),
+            })
+        })
+        .collect()
+}
+
+fn list_in(top: &Path) -> Result<Vec<Checkpoint>, String> {
+    let output = git(
+        top,
+        &[
+            "for-each-ref",
+            "--sort=-refname",
+            "--format=%(refname:lstrip=3)%00%(objectname)%00%(creatordate:unix)%00%(contents)%00",
+            REF_PREFIX,
+        ],
+    )?;
+    Ok(parse_refs(&output))
+}
+
+/// Checkpoints of the repo containing `repo`, newest first.
+pub fn list(repo: &Path) -> Result<Vec<Checkpoint>, String> {
+    let top = toplevel(repo).ok_or("Not a git repository")?;
+    list_in(&top)
+}
+
+fn prune(top: &Path, keep: usize) -> Result<(), String> {
+    let stale: String = list_in(top)?
+        .iter()
+        .skip(keep)
+        .map(|c| format!("delete {}{}\n", REF_PREFIX, c.id))
+        .collect();
+    if !stale.is_empty() {
+        git::run(
+            git::command(top, &["update-ref", "--stdin"]),
+            Some(stale.as_bytes()),
+        )?;
+    }
+    Ok(())
+}
+
+/// Commit of checkpoint `id`, rejecting anything that is not a checkpoint name.
+fn resolve(top: &Path, id: &str) -> Result<String, String> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("Invalid checkpoint '{}'", id));
+    }
+    let spec = format!("{}{}^{{commit}}", REF_PREFIX, id);
+    git(top, &["rev-parse", "--verify", "-q", &spec])
+        .map(|commit| commit.trim().to_string())
+        .map_err(|_| format!("No checkpoint '{}'", id))
+}
+
+/// What changed in the work tree since checkpoint `id`.
+pub fn diff(repo: &Path, id: &str) -> Result<Diff, String> {
+    let top = toplevel(repo).ok_or("Not a git repository")?;
+    let commit = resolve(&top, id)?;
+    let tree = snapshot_tree(&top)?;
+    Diff::collect(|format| {
+        git(
+            &top,
+            &[
+                "diff",
+                "--no-color",
+                "--no-ext-diff",
+                format,
+                &commit,
+                &tree,
+            ],
+        )
+    })
+}
+
+/// Parse `git diff --name-status -z` output into (status, path) pairs.
+fn parse_name_status(output: &str) -> Vec<(char, &str)> {
+    let mut fields = output.split('\0');
+    let mut changes = Vec::new();
+    while let (Some(status), Some(path)) = (fields.next(), fields.next()) {
+        if let Some(status) = status.chars().next() {
+            changes.push((status, path));
+        }
+    }
+    changes
+}
+
+/// Put the work tree back the way it was at checkpoint `id`. The current
+/// state is checkpointed first, so a restore can itself be undone. The index
+/// and branch are left alone.
+pub fn restore(repo: &Path, id: &str, keep: usize) -> Result<String, String> {
+    let top = toplevel(repo).ok_or("Not a git repository")?;
+    let commit = resolve(&top, id)?;
+    let before = create(
+        &top,
+        &format!("Before restoring checkpoint {}", id),
+        keep + 1,
+    )?
+    .ok_or("Not a git repository")?;
+
+    let changes = git(
+        &top,
+        &[
+            "diff",
+            "--name-status",
+            "--no-renames",
+            "-z",
+            &before.commit,
+            &commit,
+        ],
+    )?;
+    let changes = parse_name_status(&changes);
+
+    let mut checkout = Vec::new();
+    for &(status, path) in &changes {
+        if status == 'D' {
+            let file = top.join(path);
+            fs::remove_file(&file)
+                .map_err(|e| format!("Failed to remove {}: {}", file.display(), e))?;
+            // Drop directories the removal left empty
+            let mut dir = file.parent();
+            while let Some(d) = dir.filter(|d| *d != top) {
+                if fs::remove_dir(d).is_err() {
+                    break;
+                }
+                dir = d.parent();
+            }
+        } else {
+            checkout.extend_from_slice(path.as_bytes());
+            checkout.push(0);
+        }
+    }
+    if !checkout.is_empty() {
+        let index = TempIndex::new(&top)?;
+        index.git(&top, &["read-tree", &commit])?;
+        git::run(
+            index.command(&top, &["checkout-index", "--force", "-z", "--stdin"]),
+            Some(&checkout),
+        )?;
+    }
+    Ok(format!(
+        "Restored checkpoint {} ({} files changed). The previous state is checkpoint {}",
+        id,
+        changes.len(),
+        before.id
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::tests::temp_repo;
+    use std::env;
+
+    #[test]
+    fn test_parse_refs() {
+        let output = "1700000000001\0abc\x001700000000\0Fix the bug\n\nfor real\n\0\n\
+                      1700000000000\0def\x001700000000\0first\n\0\n";
+        let refs = parse_refs(output);
+        assert_eq!(refs.len(), 2);
+        assert_eq!(refs[0].id, "1700000000001");
+        assert_eq!(refs[0].prompt, "Fix the bug\n\nfor real");
+        assert_eq!(refs[1].commit, "def");
+        assert!(parse_refs("").is_empty());
+    }
+
+    #[test]
+    fn test_parse_name_status() {
+        assert_eq!(
+            parse_name_status("M\0src/a.rs\0D\0b c.txt\0"),
+            vec![('M', "src/a.rs"), ('D', "b c.txt")]
+        );
+    }
+
+    #[test]
+    fn test_create_diff_restore() {
+        let repo = temp_repo("checkpoint");
+        fs::write(repo.join("tracked.txt"), "one\n").unwrap();
+        git(&repo, &["add", "tracked.txt"]).unwrap();
+        git(&repo, &["commit", "-qm", "initial"]).unwrap();
+        fs::write(repo.join("untracked.txt"), "draft\n").unwrap();
+
+        let checkpoint = create(&repo, "Refactor everything", 10).unwrap().unwrap();
+        // The user's index is untouched
+        let status = git(&repo, &["status", "--porcelain"]).unwrap();
+        assert_eq!(status, "?? untracked.txt\n");
+
+        fs::write(repo.join("tracked.txt"), "mangled\n").unwrap();
+        fs::remove_file(repo.join("untracked.txt")).unwrap();
+        fs::create_dir_all(repo.join("new/dir")).unwrap();
+        fs::write(repo.join("new/dir/file.txt"), "junk\n").unwrap();
+
+        let diff = diff(&repo, &checkpoint.id).unwrap();
+        assert!(diff.patch.contains("+mangled"));
+        assert!(diff.stat.contains("untracked.txt"));
+
+        restore(&repo, &checkpoint.id, 10).unwrap();
+        assert_eq!(
+            fs::read_to_string(repo.join("tracked.txt")).unwrap(),
+            "one\n"
+        );
+        assert_eq!(
+            fs::read_to_string(repo.join("untracked.txt")).unwrap(),
+            "draft\n"
+        );
+        assert!(!repo.join("new").exists());
+
+        let checkpoints = list(&repo).unwrap();
+        assert_eq!(checkpoints.len(), 2);
+        assert_eq!(checkpoints[1].prompt, "Refactor everything");
+        assert!(restore(&repo, "../HEAD", 10).is_err());
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn test_outside_git() {
+        let dir = env::temp_dir().join(format!("vnccc-test-nogit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(create(&dir, "hi", 10).unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,352 @@
+//! `vnccc ctl`: drive a running vnccc through its HTTP API, so scripts and
+//! OliveTin can manage sessions without restarting containers.
+
+use serde_json::Value;
+use std::env;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::config::Config;
+
+const USAGE: &str = "\
+Usage: vnccc ctl [--url URL] [--token TOKEN] <command> [args]
+
+Commands:
+  list                          List sessions
+  status <id>                   Show one session
+  start <repo-path> [id]        Start a session
+  start-worktree <repo-path> [id]
+                                Start a session in a new worktree of the repo
+  stop <id> [keep|merge|delete] Stop a session and its display, and keep, merge
+                                or delete its worktree (default keep)
+  restart-agent <id>            Restart the agent only
+  restart-terminal <id>         Restart the terminal and agent
+  restart-display <id>          Restart Xvnc, the window manager, terminal and agent
+  pause <id>                    Stop the agent's process group (SIGSTOP)
+  resume <id>                   Continue a paused agent, or start it after stop-agent
+  stop-agent <id>               Emergency stop: kill the agent's process tree and
+                                keep it from restarting until resumed
+  sandbox <id> [path]           Show what the sandboxed agent may read and write
+
+URL defaults to $VNCAA_URL or http://127.0.0.1:8080. The token defaults to
+$VNCAA_API_TOKEN or api_token from config.toml.";
+
+struct Endpoint {
+    host: String,
+    port: u16,
+}
+
+/// Split `http://host[:port][/]` into host and port.
+fn parse_url(url: &str) -> Result<Endpoint, String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("Only http:// URLs are supported, got '{}'", url))?;
+    let authority = rest.trim_end_matches('/');
+    if authority.contains('/') {
+        return Err(format!("URL must not have a path, got '{}'", url));
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Ok(Endpoint {
+            host: host.to_string(),
+            port: port
+                .parse()
+                .map_err(|_| format!("Invalid port in '{}'", url))?,
+        }),
+        None => Ok(Endpoint {
+            host: authority.to_string(),
+            port: 80,
+        }),
+    }
+}
+
+/// Status code and body of a raw HTTP/1.0 response.
+fn parse_response(response: &str) -> Result<(u16, &str), String> {
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or("Malformed HTTP response")?;
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or("Malformed HTTP status line")?;
+    Ok((status, body))
+}
+
+/// Minimal blocking HTTP client. HTTP/1.0 keeps the server from chunking the
+/// body and closes the connection when the response is complete.
+fn request(
+    endpoint: &Endpoint,
+    token: Option<&str>,
+    method: &str,
+    path: &str,
+    body: Option<&Value>,
+) -> Result<(u16, Value), String> {
+    let addr = format!("{}:{}", endpoint.host, endpoint.port);
+    let mut stream =
+        TcpStream::connect(&addr).map_err(|e| format!("Failed to connect to {}: {}", addr, e))?;
+    // Restarting a display takes a moment, everything else is quick
+    stream
+        .set_read_timeout(Some(Duration::from_secs(30)))
+        .map_err(|e| e.to_string())?;
+
+    let body = body.map(|b| b.to_string()).unwrap_or_default();
+    let mut req = format!("{} {} HTTP/1.0\r\nHost: {}\r\n", method, path, addr);
+    if let Some(token) = token {
+        req.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    if !body.is_empty() {
+        req.push_str("Content-Type: application/json\r\n");
+    }
+    req.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+    stream
+        .write_all(req.as_bytes())
+        .map_err(|e| format!("Failed to send request: {}", e))?;
+
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .map_err(|e| format!("Failed to read response: {}", e))?;
+    let response = String::from_utf8_lossy(&response);
+    let (status, body) = parse_response(&response)?;
+    let json = serde_json::from_str(body).unwrap_or_else(|_| Value::String(body.to_string()));
+    Ok((status, json))
+}
+
+fn print_session(session: &Value) {
+    let text = |key: &str| session[key].as_str().unwrap_or("-").to_string();
+    let power = session["power"].as_str().unwrap_or("awake");
+    let state = if power != "awake" {
+        power
+    } else if session["held"].as_bool() == Some(true) {
+        "held"
+    } else if session["paused"].as_bool() == Some(true) {
+        "paused"
+    } else if session["running"].as_bool() == Some(true) {
+        session["agent_state"].as_str().unwrap_or("running")
+    } else {
+        "starting"
+    };
+    println!(
+        "{:<12} :{:<4} {:<10} {:<10} {:<9} {}",
+        text("id"),
+        session["display"],
+        text("agent"),
+        text("terminal"),
+        state,
+        text("repo_path")
+    );
+}
+
+fn print_usage(usage: &Value) {
+    if usage.is_null() {
+        return;
+    }
+    let rss_mib = usage["rss_bytes"].as_f64().unwrap_or(0.0) / (1024.0 * 1024.0);
+    println!(
+        "{} processes, {:.1}% CPU, {:.0} MiB RSS, {} open files",
+        usage["processes"],
+        usage["cpu_percent"].as_f64().unwrap_or(0.0),
+        rss_mib,
+        usage["open_files"]
+    );
+}
+
+fn print_limit_hits(hits: &Value) {
+    let Value::Object(hits) = hits else {
+        return;
+    };
+    let nonzero: Vec<String> = hits
+        .iter()
+        .filter(|(_, count)| count.as_u64().is_some_and(|n| n > 0))
+        .map(|(name, count)| format!("{} {}", name, count))
+        .collect();
+    if !nonzero.is_empty() {
+        println!("Limit hits: {}", nonzero.join(", "));
+    }
+}
+
+fn print_sandbox(sandbox: &Value) {
+    if sandbox["rules"].is_null() {
+        println!("Sandbox is disabled");
+        return;
+    }
+    match sandbox["abi"].as_i64() {
+        Some(abi) => println!("Landlock ABI {}", abi),
+        None => println!("Landlock is not available, the agent will not start"),
+    }
+    for probe in sandbox["probes"].as_array().into_iter().flatten() {
+        let access = |key: &str| probe[key].as_str().unwrap_or("-").to_string();
+        println!(
+            "{:<8} {:<8} {}",
+            access("read"),
+            access("write"),
+            probe["path"].as_str().unwrap_or("-")
+        );
+    }
+}
+
+/// Percent-encode `s` for a URL query value.
+fn encode_query(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Run a `vnccc ctl` command and return the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let mut url = env::var("VNCAA_URL").unwrap_or_else(|_| "http://127.0.0.1:8080".to_string());
+    let mut token = env::var("VNCAA_API_TOKEN").ok().filter(|t| !t.is_empty());
+    let mut rest = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--url" => url = iter.next().cloned().unwrap_or_default(),
+            "--token" => token = iter.next().cloned(),
+            "-h" | "--help" | "help" => {
+                println!("{}", USAGE);
+                return 0;
+            }
+            _ => rest.push(arg.as_str()),
+        }
+    }
+    if token.is_none() {
+        token = Config::load().ok().and_then(|c| c.api_token);
+    }
+
+    let endpoint = match parse_url(&url) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+
+    let (method, path, body) = match rest.as_slice() {
+        ["list"] => ("GET", "/api/sessions".to_string(), None),
+        ["status", id] => ("GET", format!("/api/sessions/{}", id), None),
+        ["start", repo] => (
+            "POST",
+            "/api/sessions".to_string(),
+            Some(serde_json::json!({ "repo_path": repo })),
+        ),
+        ["start", repo, id] => (
+            "POST",
+            "/api/sessions".to_string(),
+            Some(serde_json::json!({ "repo_path": repo, "id": id })),
+        ),
+        ["start-worktree", repo] => (
+            "POST",
+            "/api/sessions".to_string(),
+            Some(serde_json::json!({ "repo_path": repo, "worktree": true })),
+        ),
+        ["start-worktree", repo, id] => (
+            "POST",
+            "/api/sessions".to_string(),
+            Some(serde_json::json!({ "repo_path": repo, "id": id, "worktree": true })),
+        ),
+        ["stop", id] => ("DELETE", format!("/api/sessions/{}", id), None),
+        ["stop", id, how] => (
+            "DELETE",
+            format!("/api/sessions/{}?worktree={}", id, encode_query(how)),
+            None,
+        ),
+        ["restart-agent", id] => ("POST", format!("/s/{}/api/agent/restart", id), None),
+        ["restart-terminal", id] => ("POST", format!("/s/{}/api/terminal/restart", id), None),
+        ["restart-display", id] => ("POST", format!("/s/{}/api/display/restart", id), None),
+        ["pause", id] => ("POST", format!("/s/{}/api/agent/pause", id), None),
+        ["resume", id] => ("POST", format!("/s/{}/api/agent/resume", id), None),
+        ["stop-agent", id] => ("POST", format!("/s/{}/api/agent/stop", id), None),
+        ["sandbox", id] => ("GET", format!("/s/{}/api/sandbox", id), None),
+        ["sandbox", id, path] => (
+            "GET",
+            format!("/s/{}/api/sandbox?path={}", id, encode_query(path)),
+            None,
+        ),
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+
+    let (status, json) = match request(&endpoint, token.as_deref(), method, &path, body.as_ref()) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+
+    let ok = (200..300).contains(&status);
+    match (&json, rest[0]) {
+        (Value::Array(sessions), "list") => sessions.iter().for_each(print_session),
+        (Value::Object(_), "status") if ok => {
+            print_session(&json);
+            if let Some(branch) = json["worktree"]["branch"].as_str() {
+                println!(
+                    "Worktree {} on branch {}",
+                    json["worktree"]["path"].as_str().unwrap_or("-"),
+                    branch
+                );
+            }
+            if let Some(watch) = json["agent_watch"].as_str().filter(|w| *w != "text") {
+                println!(
+                    "Agent state from {} only: {} cannot tell when the agent asks something",
+                    watch,
+                    json["terminal"].as_str().unwrap_or("the terminal")
+                );
+            }
+            print_usage(&json["usage"]);
+            print_limit_hits(&json["limit_hits"]);
+        }
+        (Value::Object(_), "sandbox") if ok => print_sandbox(&json),
+        _ => {
+            let message = json["message"]
+                .as_str()
+                .map(str::to_string)
+                .unwrap_or_else(|| json.to_string());
+            if ok {
+                println!("{}", message);
+            } else {
+                eprintln!("Error ({}): {}", status, message);
+            }
+        }
+    }
+    if ok { 0 } else { 1 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        let endpoint = parse_url("http://127.0.0.1:8080").unwrap();
+        assert_eq!((endpoint.host.as_str(), endpoint.port), ("127.0.0.1", 8080));
+        let endpoint = parse_url("http://vncaa.local/").unwrap();
+        assert_eq!((endpoint.host.as_str(), endpoint.port), ("vncaa.local", 80));
+        assert!(parse_url("https://example.com").is_err());
+        assert!(parse_url("http://host:port").is_err());
+        assert!(parse_url("http://host:8080/api").is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let response =
+            "HTTP/1.0 409 Conflict\r\ncontent-type: application/json\r\n\r\n{\"success\":false}";
+        assert_eq!(parse_response(response), Ok((409, "{\"success\":false}")));
+        assert!(parse_response("garbage").is_err());
+    }
+
+    #[test]
+    fn test_encode_query() {
+        assert_eq!(encode_query("/home/dev/.ssh"), "/home/dev/.ssh");
+        assert_eq!(encode_query("/a b&c=ü"), "/a%20b%26c%3D%C3%BC");
+    }
+}
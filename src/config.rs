@@ -0,0 +1,578 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::Write as _;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::display::Geometry;
+use crate::limits;
+use crate::notify::Event;
+
+/// Optional settings read from `~/.config/vncaa/config.toml`.
+///
+/// Every field has a default so an empty or missing file behaves exactly like
+/// running without one. Environment variables override file values.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Terminal emulator backend (alacritty, kitty, wezterm, xterm, urxvt, st).
+    /// Auto-detected from PATH when unset.
+    pub terminal: Option<String>,
+    /// Named display sizes offered by `/api/display/geometry`, e.g.
+    /// `phone = "720x1280"`.
+    pub geometry_presets: BTreeMap<String, String>,
+    /// Bearer token required by the session lifecycle endpoints. Unset means
+    /// no authentication, which is only sensible on a trusted network.
+    pub api_token: Option<String>,
+    pub emergency_stop: EmergencyStop,
+    pub idle: Idle,
+    pub limits: Limits,
+    pub sandbox: Sandbox,
+    pub checkpoints: Checkpoints,
+    pub git: Git,
+    pub uploads: Uploads,
+    pub activity: Activity,
+    pub notify: Notify,
+}
+
+/// Where to tell someone that an agent finished, needs an answer or keeps
+/// crashing.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Notify {
+    /// Address the web UI is reached at, for links in notifications, e.g.
+    /// `https://vncaa.example.ts.net`. Links are relative without it.
+    pub base_url: Option<String>,
+    /// Only report an agent as done when it was busy at least this long.
+    pub done_after_seconds: u64,
+    /// Restarts of the terminal or agent within `crash_loop_seconds` that
+    /// make a crash loop.
+    pub crash_loop_count: usize,
+    pub crash_loop_seconds: u64,
+    /// Send Web Push notifications to browsers that asked for them.
+    pub web_push: bool,
+    /// Contact the push services can reach the sender at, a `mailto:` or
+    /// `https:` URL.
+    pub subject: String,
+    pub webhooks: Vec<Webhook>,
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify {
+            base_url: None,
+            done_after_seconds: 30,
+            crash_loop_count: 3,
+            crash_loop_seconds: 60,
+            web_push: true,
+            subject: "mailto:vncaa@localhost".to_string(),
+            webhooks: Vec::new(),
+        }
+    }
+}
+
+/// An HTTP endpoint events are POSTed to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    pub url: String,
+    /// Events to send; all of them when empty.
+    #[serde(default)]
+    pub events: Vec<Event>,
+    /// Request body with `{{variables}}`; the event as JSON when unset.
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    /// Extra headers, which may use the same variables.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+fn default_content_type() -> String {
+    "application/json".to_string()
+}
+
+/// How vnccc tells whether the agent is working or waiting for input.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Activity {
+    /// CPU % of the terminal's process tree that counts as working.
+    pub cpu_percent: f64,
+    /// Seconds without CPU use or screen changes before the agent is idle.
+    pub idle_seconds: u64,
+    /// Text to look for at the bottom of the screen, per agent, added to the
+    /// built-in recognizers. `"*"` applies to every agent.
+    pub patterns: BTreeMap<String, Patterns>,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity {
+            cpu_percent: 3.0,
+            idle_seconds: 5,
+            patterns: BTreeMap::new(),
+        }
+    }
+}
+
+/// Text an agent shows in each state, matched ignoring case.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Patterns {
+    pub busy: Vec<String>,
+    pub idle: Vec<String>,
+    /// The agent is asking something, e.g. for permission to run a command.
+    pub attention: Vec<String>,
+}
+
+/// Files uploaded from the web UI, e.g. screenshots for the agent.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Uploads {
+    /// Directory relative to the session's repo path.
+    pub dir: String,
+    /// Largest upload request in MiB.
+    pub max_mb: u64,
+}
+
+impl Default for Uploads {
+    fn default() -> Self {
+        Uploads {
+            dir: ".vncaa/uploads".to_string(),
+            max_mb: 25,
+        }
+    }
+}
+
+/// The git panel of the web UI.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Git {
+    /// Allow stage, unstage, discard and commit. Off means read-only.
+    pub actions: bool,
+}
+
+/// Git snapshots of the workspace taken before each prompt.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Checkpoints {
+    pub enabled: bool,
+    /// How many checkpoints to keep per repo; older ones are deleted.
+    pub keep: usize,
+}
+
+impl Default for Checkpoints {
+    fn default() -> Self {
+        Checkpoints {
+            enabled: true,
+            keep: 100,
+        }
+    }
+}
+
+/// Landlock filesystem sandbox for the agent. Off by default.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sandbox {
+    pub enabled: bool,
+    /// Start from the built-in rules: read-write on the workspace and the
+    /// agent's config, read-only on system and toolchain directories.
+    pub defaults: bool,
+    /// Extra directories or files the agent may change. `~` is `$HOME`.
+    pub read_write: Vec<String>,
+    /// Extra directories or files the agent may read and execute.
+    pub read_only: Vec<String>,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            enabled: false,
+            defaults: true,
+            read_write: Vec::new(),
+            read_only: Vec::new(),
+        }
+    }
+}
+
+/// Resource limits for each session's agent. Unset fields are unlimited.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Memory for the agent's process tree, e.g. `"4G"`.
+    pub memory: Option<String>,
+    /// CPU time as a number of cores, e.g. `1.5`. Needs cgroup delegation.
+    pub cpus: Option<f64>,
+    pub max_processes: Option<u64>,
+    /// Open files per process.
+    pub open_files: Option<u64>,
+}
+
+impl Limits {
+    pub fn memory_bytes(&self) -> Option<u64> {
+        self.memory
+            .as_deref()
+            .and_then(|m| limits::parse_size(m).ok())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_none()
+            && self.cpus.is_none()
+            && self.max_processes.is_none()
+            && self.open_files.is_none()
+    }
+}
+
+/// How long the emergency stop waits at each step before escalating.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmergencyStop {
+    /// Seconds between SIGINT and SIGTERM.
+    pub interrupt_timeout: f64,
+    /// Seconds between SIGTERM and SIGKILL.
+    pub term_timeout: f64,
+}
+
+impl Default for EmergencyStop {
+    fn default() -> Self {
+        EmergencyStop {
+            interrupt_timeout: 3.0,
+            term_timeout: 5.0,
+        }
+    }
+}
+
+/// What to do with sessions nobody has used for a while.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Idle {
+    /// Minutes without prompt, VNC or page activity before a session is put
+    /// to sleep. 0 disables the idle timeout.
+    pub timeout_minutes: u64,
+    pub action: IdleAction,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleAction {
+    /// SIGSTOP the agent, terminal and X server, keeping their memory.
+    #[default]
+    Suspend,
+    /// Shut the terminal and X server down and start them again on wake.
+    Stop,
+}
+
+/// Directory holding vncaa's own config files (`$VNCAA_CONFIG_DIR` or
+/// `~/.config/vncaa`).
+pub fn config_dir() -> Option<PathBuf> {
+    if let Ok(dir) = env::var("VNCAA_CONFIG_DIR") {
+        return Some(PathBuf::from(dir));
+    }
+    let home = env::var("HOME").ok()?;
+    Some(PathBuf::from(home).join(".config").join("vncaa"))
+}
+
+/// Directory for state vncaa keeps between runs (`$VNCAA_STATE_DIR`,
+/// `$XDG_STATE_HOME/vncaa` or `~/.local/state/vncaa`).
+pub fn state_dir() -> Option<PathBuf> {
+    if let Ok(dir) = env::var("VNCAA_STATE_DIR") {
+        return Some(PathBuf::from(dir));
+    }
+    if let Ok(dir) = env::var("XDG_STATE_HOME")
+        && !dir.is_empty()
+    {
+        return Some(PathBuf::from(dir).join("vncaa"));
+    }
+    let home = env::var("HOME").ok()?;
+    Some(
+        PathBuf::from(home)
+            .join(".local")
+            .join("state")
+            .join("vncaa"),
+    )
+}
+
+impl Config {
+    /// Load the config file, falling back to defaults when it does not exist.
+    pub fn load() -> Result<Config, String> {
+        let mut config = match config_dir().map(|dir| dir.join("config.toml")) {
+            Some(path) if path.exists() => {
+                let contents = fs::read_to_string(&path)
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                Config::parse(&contents)
+                    .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?
+            }
+            _ => Config::default(),
+        };
+        config.apply_env();
+        Ok(config)
+    }
+
+    fn parse(contents: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(contents).map_err(|e| e.to_string())?;
+        config.geometry_presets()?;
+        for (name, secs) in [
+            ("interrupt_timeout", config.emergency_stop.interrupt_timeout),
+            ("term_timeout", config.emergency_stop.term_timeout),
+        ] {
+            if !(0.0..=600.0).contains(&secs) {
+                return Err(format!(
+                    "emergency_stop.{} must be between 0 and 600 seconds, got {}",
+                    name, secs
+                ));
+            }
+        }
+        if !(config.activity.cpu_percent >= 0.0 && config.activity.cpu_percent.is_finite()) {
+            return Err(format!(
+                "activity.cpu_percent must not be negative, got {}",
+                config.activity.cpu_percent
+            ));
+        }
+        if let Some(webhook) = config
+            .notify
+            .webhooks
+            .iter()
+            .find(|w| !w.url.starts_with("http://") && !w.url.starts_with("https://"))
+        {
+            return Err(format!(
+                "notify.webhooks: '{}' is not an http(s) URL",
+                webhook.url
+            ));
+        }
+        if let Some(memory) = &config.limits.memory {
+            limits::parse_size(memory).map_err(|e| format!("limits.memory: {}", e))?;
+        }
+        if let Some(cpus) = config.limits.cpus
+            && !(cpus > 0.0 && cpus.is_finite())
+        {
+            return Err(format!("limits.cpus must be positive, got {}", cpus));
+        }
+        Ok(config)
+    }
+
+    /// Geometry presets, parsed.
+    pub fn geometry_presets(&self) -> Result<BTreeMap<String, Geometry>, String> {
+        self.geometry_presets
+            .iter()
+            .map(|(name, geometry)| {
+                Geometry::parse(geometry)
+                    .map(|g| (name.clone(), g))
+                    .map_err(|e| format!("geometry_presets.{}: {}", name, e))
+            })
+            .collect()
+    }
+
+    fn apply_env(&mut self) {
+        if let Ok(terminal) = env::var("VNCAA_TERMINAL")
+            && !terminal.is_empty()
+        {
+            self.terminal = Some(terminal);
+        }
+        if let Ok(token) = env::var("VNCAA_API_TOKEN")
+            && !token.is_empty()
+        {
+            self.api_token = Some(token);
+        }
+    }
+}
+
+/// Replace `path` with `contents` via a temp file in the same directory and a
+/// rename, so readers watching the file never see a partial write. Symlinks
+/// are followed so the link itself is kept.
+pub fn write_atomic(path: &Path, contents: &str) -> Result<(), String> {
+    write_with_mode(path, contents, 0o666)
+}
+
+/// [`write_atomic`] for secrets: the file is only ever readable by its owner.
+pub fn write_private(path: &Path, contents: &str) -> Result<(), String> {
+    write_with_mode(path, contents, 0o600)
+}
+
+/// Temp files are created with `mode`, less the umask, and keep it through
+/// the rename.
+fn write_with_mode(path: &Path, contents: &str, mode: u32) -> Result<(), String> {
+    let target = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let dir = target
+        .parent()
+        .ok_or_else(|| format!("{} has no parent directory", target.display()))?;
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+
+    let file_name = target
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let tmp = dir.join(format!(".{}.{}.tmp", file_name, std::process::id()));
+    // A temp file left over from a crash would keep its old mode
+    let _ = fs::remove_file(&tmp);
+    let result = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, &target));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(format!("Failed to write {}: {}", target.display(), e));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_empty_config() {
+        let config = Config::parse("").unwrap();
+        assert!(config.terminal.is_none());
+    }
+
+    #[test]
+    fn test_parse_terminal() {
+        let config = Config::parse("terminal = \"kitty\"").unwrap();
+        assert_eq!(config.terminal.as_deref(), Some("kitty"));
+    }
+
+    #[test]
+    fn test_parse_geometry_presets() {
+        let config =
+            Config::parse("[geometry_presets]\nphone = \"720x1280\"\nwide = \"2560x1080\"")
+                .unwrap();
+        let presets = config.geometry_presets().unwrap();
+        assert_eq!(presets["phone"], Geometry::new(720, 1280).unwrap());
+        assert_eq!(presets.len(), 2);
+
+        let err = Config::parse("[geometry_presets]\nphone = \"tall\"").unwrap_err();
+        assert!(err.contains("geometry_presets.phone"));
+    }
+
+    #[test]
+    fn test_parse_emergency_stop() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.emergency_stop.interrupt_timeout, 3.0);
+        let config = Config::parse("[emergency_stop]\nterm_timeout = 0.5").unwrap();
+        assert_eq!(config.emergency_stop.interrupt_timeout, 3.0);
+        assert_eq!(config.emergency_stop.term_timeout, 0.5);
+        assert!(Config::parse("[emergency_stop]\nterm_timeout = -1").is_err());
+    }
+
+    #[test]
+    fn test_parse_idle() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.idle.timeout_minutes, 0);
+        assert_eq!(config.idle.action, IdleAction::Suspend);
+        let config = Config::parse("[idle]\ntimeout_minutes = 30\naction = \"stop\"").unwrap();
+        assert_eq!(config.idle.timeout_minutes, 30);
+        assert_eq!(config.idle.action, IdleAction::Stop);
+        assert!(Config::parse("[idle]\naction = \"hibernate\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        assert!(Config::parse("").unwrap().limits.is_empty());
+        let config =
+            Config::parse("[limits]\nmemory = \"2G\"\ncpus = 1.5\nopen_files = 4096").unwrap();
+        assert_eq!(config.limits.memory_bytes(), Some(2 << 30));
+        assert_eq!(config.limits.cpus, Some(1.5));
+        assert_eq!(config.limits.max_processes, None);
+        assert!(Config::parse("[limits]\nmemory = \"lots\"").is_err());
+        assert!(Config::parse("[limits]\ncpus = 0").is_err());
+    }
+
+    #[test]
+    fn test_parse_sandbox() {
+        let config = Config::parse("").unwrap();
+        assert!(!config.sandbox.enabled);
+        assert!(config.sandbox.defaults);
+        let config =
+            Config::parse("[sandbox]\nenabled = true\nread_write = [\"~/.cache\"]").unwrap();
+        assert!(config.sandbox.enabled);
+        assert_eq!(config.sandbox.read_write, vec!["~/.cache"]);
+        assert!(Config::parse("[sandbox]\nwrite = []").is_err());
+    }
+
+    #[test]
+    fn test_parse_checkpoints() {
+        let config = Config::parse("").unwrap();
+        assert!(config.checkpoints.enabled);
+        assert_eq!(config.checkpoints.keep, 100);
+        assert!(!config.git.actions);
+        let config = Config::parse("[checkpoints]\nkeep = 20").unwrap();
+        assert!(config.checkpoints.enabled);
+        assert_eq!(config.checkpoints.keep, 20);
+    }
+
+    #[test]
+    fn test_parse_uploads() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.uploads.dir, ".vncaa/uploads");
+        assert_eq!(config.uploads.max_mb, 25);
+        let config = Config::parse("[uploads]\ndir = \"tmp/in\"").unwrap();
+        assert_eq!(config.uploads.dir, "tmp/in");
+        assert_eq!(config.uploads.max_mb, 25);
+    }
+
+    #[test]
+    fn test_parse_activity() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.activity.idle_seconds, 5);
+        let config = Config::parse(
+            "[activity]\nidle_seconds = 10\n[activity.patterns.claude]\nattention = [\"Approve?\"]",
+        )
+        .unwrap();
+        assert_eq!(config.activity.idle_seconds, 10);
+        assert_eq!(
+            config.activity.patterns["claude"].attention,
+            vec!["Approve?"]
+        );
+        assert!(Config::parse("[activity]\ncpu_percent = -1").is_err());
+    }
+
+    #[test]
+    fn test_parse_notify() {
+        let config = Config::parse("").unwrap();
+        assert!(config.notify.web_push);
+        assert!(config.notify.webhooks.is_empty());
+        let config = Config::parse(
+            "[[notify.webhooks]]\nurl = \"https://ntfy.sh/agents\"\nevents = [\"done\", \"crash_loop\"]\n",
+        )
+        .unwrap();
+        let webhook = &config.notify.webhooks[0];
+        assert_eq!(webhook.events, vec![Event::Done, Event::CrashLoop]);
+        assert_eq!(webhook.content_type, "application/json");
+        assert!(Config::parse("[[notify.webhooks]]\nurl = \"ntfy.sh\"").is_err());
+        assert!(
+            Config::parse("[[notify.webhooks]]\nurl = \"http://x\"\nevents = [\"boom\"]").is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_keys() {
+        assert!(Config::parse("terminl = \"kitty\"").is_err());
+    }
+
+    #[test]
+    fn test_write_atomic_follows_symlink() {
+        let dir = env::temp_dir().join(format!("vnccc-test-atomic-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let real = dir.join("real.toml");
+        let link = dir.join("link.toml");
+        fs::write(&real, "old").unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        write_atomic(&link, "new").unwrap();
+
+        assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+        assert_eq!(fs::read_to_string(&real).unwrap(), "new");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
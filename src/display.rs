@@ -0,0 +1,168 @@
+use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::process::Command;
+
+/// Screen size of the X display in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Geometry {
+    const MIN: (u32, u32) = (320, 240);
+    const MAX: (u32, u32) = (7680, 4320);
+
+    pub fn new(width: u32, height: u32) -> Result<Geometry, String> {
+        let (min_w, min_h) = Self::MIN;
+        let (max_w, max_h) = Self::MAX;
+        if !(min_w..=max_w).contains(&width) || !(min_h..=max_h).contains(&height) {
+            return Err(format!(
+                "Geometry {}x{} out of range ({}x{} to {}x{})",
+                width, height, min_w, min_h, max_w, max_h
+            ));
+        }
+        Ok(Geometry { width, height })
+    }
+
+    /// Parse `WIDTHxHEIGHT`, e.g. `1024x768`.
+    pub fn parse(s: &str) -> Result<Geometry, String> {
+        let invalid = || format!("Invalid geometry '{}', expected WIDTHxHEIGHT", s);
+        let (width, height) = s.split_once('x').ok_or_else(invalid)?;
+        let width = width.parse().map_err(|_| invalid())?;
+        let height = height.parse().map_err(|_| invalid())?;
+        Geometry::new(width, height)
+    }
+}
+
+impl fmt::Display for Geometry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+fn xrandr(display: u32, args: &[&str]) -> Result<String, String> {
+    let output = Command::new("xrandr")
+        .env("DISPLAY", format!(":{}", display))
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run xrandr: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "xrandr {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Name of the first connected output and the modes it lists, from
+/// `xrandr --query` output. Xvnc has a single output (`VNC-0`).
+fn parse_output(query: &str) -> Option<(String, Vec<String>)> {
+    let mut lines = query
+        .lines()
+        .skip_while(|line| !line.contains(" connected"));
+    let name = lines.next()?.split_whitespace().next()?.to_string();
+    let modes = lines
+        .take_while(|line| line.starts_with(' '))
+        .filter_map(|line| line.split_whitespace().next())
+        .map(str::to_string)
+        .collect();
+    Some((name, modes))
+}
+
+/// Resize the running X server through RandR, adding a mode for `geometry`
+/// when the server does not already offer one, then have ratpoison lay its
+/// frames out again so the terminal fills the new screen.
+pub fn resize(display: u32, geometry: Geometry) -> Result<(), String> {
+    let query = xrandr(display, &["--query"])?;
+    let (output, modes) = parse_output(&query).ok_or("No connected RandR output found")?;
+    let mode = geometry.to_string();
+
+    if !modes.contains(&mode) {
+        let (width, height) = (geometry.width.to_string(), geometry.height.to_string());
+        // Xvnc ignores timings, so a zeroed modeline is enough. This fails
+        // harmlessly when the mode exists but isn't attached to the output.
+        let _ = xrandr(
+            display,
+            &[
+                "--newmode",
+                &mode,
+                "0",
+                &width,
+                "0",
+                "0",
+                "0",
+                &height,
+                "0",
+                "0",
+                "0",
+            ],
+        );
+        xrandr(display, &["--addmode", &output, &mode])?;
+    }
+    xrandr(display, &["--output", &output, "--mode", &mode])?;
+
+    if let Err(e) = Command::new("ratpoison")
+        .env("DISPLAY", format!(":{}", display))
+        .args(["-c", "redisplay"])
+        .output()
+    {
+        eprintln!("Failed to ask ratpoison to redisplay: {}", e);
+    }
+    Ok(())
+}
+
+/// Hash of the whole screen's pixels, to notice that something on it changed.
+/// `None` when `xwd` is not installed or fails.
+pub fn screen_hash(display: u32) -> Option<u64> {
+    let output = Command::new("xwd")
+        .args(["-root", "-silent", "-display", &format!(":{}", display)])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let mut hasher = DefaultHasher::new();
+    output.stdout.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_geometry() {
+        assert_eq!(
+            Geometry::parse("1024x768"),
+            Ok(Geometry {
+                width: 1024,
+                height: 768
+            })
+        );
+        assert_eq!(Geometry::parse("1024x768").unwrap().to_string(), "1024x768");
+        assert!(Geometry::parse("1024").is_err());
+        assert!(Geometry::parse("axb").is_err());
+        assert!(
+            Geometry::parse("100x100")
+                .unwrap_err()
+                .contains("out of range")
+        );
+        assert!(Geometry::parse("10000x1000").is_err());
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let query = "\
+Screen 0: minimum 32 x 32, current 1024 x 1024, maximum 32768 x 32768
+VNC-0 connected primary 1024x1024+0+0 0mm x 0mm
+   1024x1024     60.00*+
+   1920x1080     60.00
+   800x600       60.00
+";
+        let (output, modes) = parse_output(query).unwrap();
+        assert_eq!(output, "VNC-0");
+        assert_eq!(modes, vec!["1024x1024", "1920x1080", "800x600"]);
+        assert!(parse_output("Screen 0: minimum 32 x 32\n").is_none());
+    }
+}
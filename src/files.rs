@@ -0,0 +1,543 @@
+//! Access to files under a session's repo path: read-only browsing and
+//! downloads, plus saving uploads into one directory.
+//!
+//! Every path is resolved and checked to still be inside the root after
+//! symlinks are followed, so neither `..` nor a link can reach outside it.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Read as _, Write as _};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::UNIX_EPOCH;
+
+/// Text shown in the viewer is cut off beyond this many bytes.
+pub const MAX_TEXT: u64 = 1 << 20;
+/// Largest file served raw, e.g. for image previews.
+pub const MAX_RAW: u64 = 10 << 20;
+/// Directories list at most this many entries.
+const MAX_ENTRIES: usize = 2000;
+/// Bytes checked for NULs to tell binary files from text.
+const SNIFF: usize = 8192;
+
+/// Whether `rel` stays below the directory it is relative to, lexically.
+fn is_relative(rel: &str) -> bool {
+    Path::new(rel)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Resolve `rel` under `root`, rejecting absolute paths, `..` and symlinks
+/// that lead outside `root`.
+pub fn resolve(root: &Path, rel: &str) -> Result<PathBuf, String> {
+    let invalid = || format!("Invalid path '{}'", rel);
+    if !is_relative(rel) {
+        return Err(invalid());
+    }
+    let root = fs::canonicalize(root).map_err(|e| format!("{}: {}", root.display(), e))?;
+    let path = fs::canonicalize(root.join(rel)).map_err(|_| format!("No such file '{}'", rel))?;
+    if !path.starts_with(&root) {
+        return Err(invalid());
+    }
+    Ok(path)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    File,
+    Dir,
+    /// A symlink that is broken or points outside the root.
+    Link,
+    Other,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    pub name: String,
+    pub kind: Kind,
+    pub size: u64,
+    /// Unix seconds.
+    pub modified: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Listing {
+    /// Directory relative to the root, empty for the root itself.
+    pub path: String,
+    pub entries: Vec<Entry>,
+    pub truncated: bool,
+}
+
+/// Entries of directory `rel`, directories first. `.git` is left out.
+pub fn list(root: &Path, rel: &str) -> Result<Listing, String> {
+    let dir = if rel.is_empty() {
+        resolve(root, ".")?
+    } else {
+        resolve(root, rel)?
+    };
+    let canonical_root = fs::canonicalize(root).map_err(|e| e.to_string())?;
+    let read = fs::read_dir(&dir).map_err(|e| format!("Failed to list '{}': {}", rel, e))?;
+    let mut entries: Vec<Entry> = read
+        .flatten()
+        .filter(|e| e.file_name() != ".git")
+        .map(|e| {
+            let path = e.path();
+            // Follow links, but only to places inside the root
+            let target = fs::canonicalize(&path)
+                .ok()
+                .filter(|t| t.starts_with(&canonical_root))
+                .and_then(|t| fs::metadata(t).ok());
+            let kind = match &target {
+                Some(m) if m.is_dir() => Kind::Dir,
+                Some(m) if m.is_file() => Kind::File,
+                Some(_) => Kind::Other,
+                None => Kind::Link,
+            };
+            Entry {
+                name: e.file_name().to_string_lossy().into_owned(),
+                kind,
+                size: target.as_ref().map(|m| m.len()).unwrap_or(0),
+                modified: target
+                    .and_then(|m| m.modified().ok())
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs()),
+            }
+        })
+        .collect();
+    entries.sort_by(|a, b| {
+        (a.kind != Kind::Dir)
+            .cmp(&(b.kind != Kind::Dir))
+            .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+    });
+    let truncated = entries.len() > MAX_ENTRIES;
+    entries.truncate(MAX_ENTRIES);
+    Ok(Listing {
+        path: rel.trim_matches('/').to_string(),
+        entries,
+        truncated,
+    })
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Content {
+    pub path: String,
+    pub size: u64,
+    /// Contains NUL bytes or is not UTF-8, so no text is returned.
+    pub binary: bool,
+    /// Only the first `MAX_TEXT` bytes are in `text`.
+    pub truncated: bool,
+    pub text: Option<String>,
+    /// MIME type when the file can be previewed as an image via the raw endpoint.
+    pub image: Option<&'static str>,
+}
+
+/// MIME type for image extensions browsers can show.
+pub fn image_type(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    Some(match ext.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "bmp" => "image/bmp",
+        "ico" => "image/x-icon",
+        _ => return None,
+    })
+}
+
+/// Whether `data` looks like a binary file rather than text.
+fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(SNIFF)].contains(&0)
+}
+
+/// Up to `limit` bytes of `path`, after checking it is a regular file.
+fn read_file(path: &Path, rel: &str, limit: u64) -> Result<(u64, Vec<u8>), String> {
+    let meta = fs::metadata(path).map_err(|e| format!("{}: {}", rel, e))?;
+    if !meta.is_file() {
+        return Err(format!("'{}' is not a file", rel));
+    }
+    let mut data = Vec::new();
+    fs::File::open(path)
+        .and_then(|f| f.take(limit).read_to_end(&mut data))
+        .map_err(|e| format!("Failed to read '{}': {}", rel, e))?;
+    Ok((meta.len(), data))
+}
+
+/// Text of file `rel`, or what kind of file it is when it is not text.
+pub fn read(root: &Path, rel: &str) -> Result<Content, String> {
+    let path = resolve(root, rel)?;
+    let (size, mut data) = read_file(&path, rel, MAX_TEXT)?;
+    let truncated = size > data.len() as u64;
+    // A cut may land inside a multi-byte character
+    if truncated {
+        while !data.is_empty() && std::str::from_utf8(&data).is_err_and(|e| e.error_len().is_none())
+        {
+            data.pop();
+        }
+    }
+    let text = (!is_binary(&data))
+        .then(|| String::from_utf8(data).ok())
+        .flatten();
+    Ok(Content {
+        path: rel.to_string(),
+        size,
+        binary: text.is_none(),
+        truncated,
+        text,
+        image: image_type(&path),
+    })
+}
+
+/// Bytes of file `rel` and its MIME type, refusing files over `MAX_RAW`.
+pub fn raw(root: &Path, rel: &str) -> Result<(&'static str, Vec<u8>), String> {
+    let path = resolve(root, rel)?;
+    let (size, data) = read_file(&path, rel, MAX_RAW)?;
+    if size > MAX_RAW {
+        return Err(format!("'{}' is larger than {} MiB", rel, MAX_RAW >> 20));
+    }
+    let mime = image_type(&path).unwrap_or(if is_binary(&data) {
+        "application/octet-stream"
+    } else {
+        "text/plain; charset=utf-8"
+    });
+    Ok((mime, data))
+}
+
+/// Whether `rel` can be archived from `root` without reaching outside it:
+/// its parent directory must resolve inside `root`. `rel` itself may be a
+/// symlink, since archives store links rather than follow them.
+pub fn confined(root: &Path, rel: &str) -> bool {
+    let parent = Path::new(rel).parent().unwrap_or(Path::new(""));
+    let parent = parent.to_string_lossy();
+    is_relative(rel) && resolve(root, if parent.is_empty() { "." } else { &parent }).is_ok()
+}
+
+/// Archive formats for downloading directories and sets of files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum Format {
+    #[default]
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::TarGz => "tar.gz",
+            Format::Zip => "zip",
+        }
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            Format::TarGz => "application/gzip",
+            Format::Zip => "application/zip",
+        }
+    }
+}
+
+/// An archiver run over files relative to one directory.
+pub struct Archive {
+    pub command: Command,
+    /// Names to feed the command on stdin.
+    pub input: Vec<u8>,
+    /// File the archive is written to, or `None` when it goes to stdout.
+    /// Info-ZIP cannot store symlinks as links when writing to a pipe.
+    pub output: Option<PathBuf>,
+}
+
+/// Archive `names`, relative to `dir`. Directories are included recursively,
+/// except `.git`, and symlinks are stored as links, never followed.
+pub fn archive(dir: &Path, format: Format, names: &[String]) -> Result<Archive, String> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut input = Vec::new();
+    let (mut command, output) = match format {
+        Format::TarGz => {
+            let mut cmd = Command::new("tar");
+            cmd.args(["-czf", "-", "--exclude=.git", "--null"]).args([
+                "--verbatim-files-from",
+                "-T",
+                "-",
+            ]);
+            for name in names {
+                input.extend_from_slice(name.as_bytes());
+                input.push(0);
+            }
+            (cmd, None)
+        }
+        Format::Zip => {
+            let output = std::env::temp_dir().join(format!(
+                "vnccc-download-{}-{}.zip",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            let mut cmd = Command::new("zip");
+            cmd.args(["-q", "-r", "-y"])
+                .arg(&output)
+                .args(["-@", "-x", ".git/*", "*/.git/*"]);
+            for name in names {
+                if name.contains('\n') {
+                    return Err(format!("Cannot zip '{}'; use tar.gz", name.escape_debug()));
+                }
+                input.extend_from_slice(name.as_bytes());
+                input.push(b'\n');
+            }
+            (cmd, Some(output))
+        }
+    };
+    command.current_dir(dir);
+    Ok(Archive {
+        command,
+        input,
+        output,
+    })
+}
+
+/// Create upload directory `dir` under `root` if needed. A new directory gets
+/// a `.gitignore` so uploads stay out of commits.
+pub fn upload_dir(root: &Path, dir: &str) -> Result<PathBuf, String> {
+    if dir.is_empty() || !is_relative(dir) {
+        return Err(format!("Upload directory '{}' must be relative", dir));
+    }
+    // One level at a time, so a symlink in the workspace cannot have
+    // directories created outside it
+    let mut rel = PathBuf::new();
+    let mut created = false;
+    for component in Path::new(dir).components() {
+        rel.push(component);
+        let path = root.join(&rel);
+        if !path.exists() && fs::symlink_metadata(&path).is_err() {
+            fs::create_dir(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            created = true;
+        }
+        resolve(root, &rel.to_string_lossy())?;
+    }
+    let path = resolve(root, dir)?;
+    if created {
+        fs::write(path.join(".gitignore"), "*\n")
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    Ok(path)
+}
+
+/// A safe file name from one supplied by the browser: no directories, no
+/// leading dots and only portable characters.
+pub fn sanitize_name(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let clean: String = base
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(100)
+        .collect();
+    let clean = clean.trim_start_matches('.');
+    if clean.is_empty() {
+        "upload".to_string()
+    } else {
+        clean.to_string()
+    }
+}
+
+/// Write `data` to a new file in `dir` named after `name`, adding `-1`,
+/// `-2`... before the extension instead of overwriting.
+pub fn save_upload(dir: &Path, name: &str, data: &[u8]) -> Result<PathBuf, String> {
+    let name = sanitize_name(name);
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name.as_str(), String::new()),
+    };
+    for n in 0..1000 {
+        let candidate = if n == 0 {
+            dir.join(&name)
+        } else {
+            dir.join(format!("{}-{}{}", stem, n, ext))
+        };
+        match fs::File::create_new(&candidate) {
+            Ok(mut file) => {
+                file.write_all(data)
+                    .map_err(|e| format!("Failed to write {}: {}", candidate.display(), e))?;
+                return Ok(candidate);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(format!("Failed to create {}: {}", candidate.display(), e)),
+        }
+    }
+    Err(format!("Too many uploads named '{}'", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_resolve_rejects_escapes() {
+        let base = env::temp_dir().join(format!("vnccc-test-files-{}", std::process::id()));
+        let root = base.join("repo");
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}\n").unwrap();
+        fs::write(base.join("secret"), "key").unwrap();
+        symlink(base.join("secret"), root.join("leak")).unwrap();
+        symlink("src/main.rs", root.join("inside")).unwrap();
+
+        assert!(resolve(&root, "src/main.rs").is_ok());
+        assert!(resolve(&root, "inside").is_ok());
+        assert!(resolve(&root, "../secret").is_err());
+        assert!(resolve(&root, "src/../../secret").is_err());
+        assert!(resolve(&root, "/etc/passwd").is_err());
+        assert!(resolve(&root, "leak").is_err());
+
+        let listing = list(&root, "").unwrap();
+        let names: Vec<&str> = listing.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["src", "inside", "leak"]);
+        assert_eq!(listing.entries[1].kind, Kind::File);
+        assert_eq!(listing.entries[2].kind, Kind::Link);
+        assert!(read(&root, "leak").is_err());
+
+        assert!(confined(&root, "src/main.rs"));
+        assert!(confined(&root, "leak"));
+        assert!(!confined(&root, "../secret"));
+        fs::create_dir_all(base.join("outside")).unwrap();
+        symlink(base.join("outside"), root.join("out")).unwrap();
+        assert!(!confined(&root, "out/file"));
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_read_text_and_binary() {
+        let root = env::temp_dir().join(format!("vnccc-test-read-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("notes.md"), "# Hi\n").unwrap();
+        fs::write(root.join("logo.png"), b"\x89PNG\r\n\x1a\n\0\0").unwrap();
+        fs::write(root.join("big.log"), "é".repeat(MAX_TEXT as usize)).unwrap();
+
+        let notes = read(&root, "notes.md").unwrap();
+        assert_eq!(notes.text.as_deref(), Some("# Hi\n"));
+        assert!(!notes.binary && notes.image.is_none());
+
+        let logo = read(&root, "logo.png").unwrap();
+        assert!(logo.binary && logo.text.is_none());
+        assert_eq!(logo.image, Some("image/png"));
+        assert_eq!(raw(&root, "logo.png").unwrap().0, "image/png");
+
+        let big = read(&root, "big.log").unwrap();
+        assert!(big.truncated && !big.binary);
+        assert!(big.text.unwrap().len() <= MAX_TEXT as usize);
+        assert!(read(&root, ".").is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_sanitize_name() {
+        assert_eq!(sanitize_name("shot.png"), "shot.png");
+        assert_eq!(sanitize_name("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_name("C:\\Users\\me\\log file.txt"), "log_file.txt");
+        assert_eq!(sanitize_name(".."), "upload");
+        assert_eq!(sanitize_name(".bashrc"), "bashrc");
+    }
+
+    #[test]
+    fn test_save_upload() {
+        let root = env::temp_dir().join(format!("vnccc-test-upload-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        assert!(upload_dir(&root, "../up").is_err());
+        assert!(upload_dir(&root, "/tmp").is_err());
+
+        let dir = upload_dir(&root, ".vncaa/uploads").unwrap();
+        assert_eq!(fs::read_to_string(dir.join(".gitignore")).unwrap(), "*\n");
+        let first = save_upload(&dir, "shot.png", b"one").unwrap();
+        let second = save_upload(&dir, "shot.png", b"two").unwrap();
+        assert_eq!(first, dir.join("shot.png"));
+        assert_eq!(second, dir.join("shot-1.png"));
+        assert_eq!(fs::read(first).unwrap(), b"one");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_archive_tar() {
+        let root = env::temp_dir().join(format!("vnccc-test-archive-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("dir/.git")).unwrap();
+        fs::write(root.join("dir/-a.txt"), "a").unwrap();
+        fs::write(root.join("dir/.git/HEAD"), "ref").unwrap();
+        symlink("/etc/passwd", root.join("dir/link")).unwrap();
+
+        let archive = archive(&root, Format::TarGz, &["dir".to_string()]).unwrap();
+        assert!(archive.output.is_none());
+        let mut command = archive.command;
+        let mut child = command
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(&archive.input)
+            .unwrap();
+        let tgz = child.wait_with_output().unwrap().stdout;
+        let mut list = Command::new("tar")
+            .args(["-tvzf", "-"])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        list.stdin.take().unwrap().write_all(&tgz).unwrap();
+        let listing = String::from_utf8(list.wait_with_output().unwrap().stdout).unwrap();
+        assert!(listing.contains("dir/-a.txt"));
+        assert!(listing.contains("dir/link -> /etc/passwd"));
+        assert!(!listing.contains(".git"));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_archive_zip() {
+        let root = env::temp_dir().join(format!("vnccc-test-zip-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("dir/.git")).unwrap();
+        fs::write(root.join("dir/-a.txt"), "a").unwrap();
+        fs::write(root.join("dir/.git/HEAD"), "ref").unwrap();
+        symlink("/etc/passwd", root.join("dir/link")).unwrap();
+
+        let zip = archive(&root, Format::Zip, &["dir".to_string()]).unwrap();
+        let output = zip.output.clone().unwrap();
+        let mut command = zip.command;
+        let mut child = command.stdin(std::process::Stdio::piped()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(&zip.input).unwrap();
+        assert!(child.wait().unwrap().success());
+        let listing = Command::new("unzip")
+            .arg("-Z")
+            .arg(&output)
+            .output()
+            .unwrap();
+        let listing = String::from_utf8(listing.stdout).unwrap();
+        assert!(listing.contains("dir/-a.txt"));
+        // Stored as a link, not the file it points to
+        assert!(
+            listing
+                .lines()
+                .any(|line| line.starts_with('l') && line.ends_with("dir/link"))
+        );
+        assert!(!listing.contains(".git"));
+        fs::remove_file(output).unwrap();
+
+        // Info-ZIP reads one name per line
+        assert!(archive(&root, Format::Zip, &["a\nb".to_string()]).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,583 @@
+//! Read-only views of the workspace repo (status, diffs, log, files changed
+//! since a ref) and the optional stage, unstage, discard and commit actions
+//! behind them.
+
+use serde::Serialize;
+use std::fs;
+use std::io::Write as _;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Diffs are cut off beyond this many bytes.
+const MAX_DIFF: usize = 2 << 20;
+const MAX_LOG: usize = 200;
+
+/// `git <args>` in `dir`. Pathspecs are taken literally, so file names from
+/// the API never act as pathspec magic.
+pub fn command(dir: &Path, args: &[&str]) -> Command {
+    let mut cmd = Command::new("git");
+    cmd.current_dir(dir)
+        .args(args)
+        .env("GIT_LITERAL_PATHSPECS", "1")
+        .env("GIT_OPTIONAL_LOCKS", "0");
+    cmd
+}
+
+/// Run a git command, feeding it `input`, and return its stdout.
+pub fn run(mut cmd: Command, input: Option<&[u8]>) -> Result<String, String> {
+    let name = cmd
+        .get_args()
+        .next()
+        .map(|a| a.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    cmd.stdin(if input.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    })
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped());
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| format!("Failed to run git: {}", e))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin
+            .write_all(input)
+            .map_err(|e| format!("Failed to write to git: {}", e))?;
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Failed to run git: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+pub fn git(dir: &Path, args: &[&str]) -> Result<String, String> {
+    run(command(dir, args), None)
+}
+
+/// Top level of the work tree containing `repo`, or `None` outside git.
+pub fn toplevel(repo: &Path) -> Option<PathBuf> {
+    git(repo, &["rev-parse", "--show-toplevel"])
+        .ok()
+        .map(|dir| PathBuf::from(dir.trim()))
+}
+
+fn require_toplevel(repo: &Path) -> Result<PathBuf, String> {
+    toplevel(repo).ok_or_else(|| "Not a git repository".to_string())
+}
+
+/// Commit HEAD points at, `None` before the first commit.
+pub fn head(top: &Path) -> Option<String> {
+    git(top, &["rev-parse", "--verify", "-q", "HEAD^{commit}"])
+        .ok()
+        .map(|commit| commit.trim().to_string())
+}
+
+/// Branch checked out in `repo`, `None` when HEAD is detached.
+pub fn branch(repo: &Path) -> Option<String> {
+    git(repo, &["symbolic-ref", "--short", "-q", "HEAD"])
+        .ok()
+        .map(|branch| branch.trim().to_string())
+}
+
+/// A throwaway index file in the git dir, removed on drop.
+pub struct TempIndex(PathBuf);
+
+impl TempIndex {
+    pub fn new(top: &Path) -> Result<TempIndex, String> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let git_dir = git(top, &["rev-parse", "--absolute-git-dir"])?;
+        let path = Path::new(git_dir.trim()).join(format!(
+            "vncaa-index-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        Ok(TempIndex(path))
+    }
+
+    /// A temporary index starting out as a copy of the real one, which also
+    /// lets git skip files whose stat is unchanged.
+    pub fn copy_of_index(top: &Path) -> Result<TempIndex, String> {
+        let index = TempIndex::new(top)?;
+        let real = index.0.with_file_name("index");
+        if real.exists() {
+            fs::copy(&real, &index.0).map_err(|e| format!("Failed to copy the index: {}", e))?;
+        }
+        Ok(index)
+    }
+
+    /// `git <args>` in `top` against this index.
+    pub fn command(&self, top: &Path, args: &[&str]) -> Command {
+        let mut cmd = command(top, args);
+        cmd.env("GIT_INDEX_FILE", &self.0);
+        cmd
+    }
+
+    pub fn git(&self, top: &Path, args: &[&str]) -> Result<String, String> {
+        run(self.command(top, args), None)
+    }
+}
+
+impl Drop for TempIndex {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Diff {
+    pub stat: String,
+    pub patch: String,
+    pub truncated: bool,
+}
+
+impl Diff {
+    /// Run `diff` with `--stat` and with `--patch`, cutting the patch short
+    /// when it is too large to send.
+    pub fn collect(diff: impl Fn(&'static str) -> Result<String, String>) -> Result<Diff, String> {
+        let stat = diff("--stat")?;
+        let mut patch = diff("--patch")?;
+        let truncated = patch.len() > MAX_DIFF;
+        if truncated {
+            let mut end = MAX_DIFF;
+            while !patch.is_char_boundary(end) {
+                end -= 1;
+            }
+            patch.truncate(end);
+        }
+        Ok(Diff {
+            stat,
+            patch,
+            truncated,
+        })
+    }
+}
+
+/// One changed path. `index` and `worktree` are git's status letters
+/// (`M`, `A`, `D`, `R`, ...) with `.` for no change and `?` for untracked.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileStatus {
+    pub path: String,
+    /// Where a renamed or copied file came from.
+    pub orig_path: Option<String>,
+    pub index: String,
+    pub worktree: String,
+    /// Unmerged after a conflicting merge, rebase or cherry-pick.
+    pub conflicted: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Status {
+    /// Null when HEAD is detached.
+    pub branch: Option<String>,
+    pub upstream: Option<String>,
+    pub ahead: u32,
+    pub behind: u32,
+    pub files: Vec<FileStatus>,
+}
+
+/// Parse `git status --porcelain=v2 --branch -z`.
+fn parse_status(output: &str) -> Status {
+    let mut status = Status::default();
+    let mut records = output.split('\0');
+    while let Some(record) = records.next() {
+        let (kind, rest) = record.split_once(' ').unwrap_or((record, ""));
+        let file = |xy: &str, path: &str, orig_path: Option<&str>, conflicted| {
+            let mut letters = xy.chars().map(String::from);
+            FileStatus {
+                path: path.to_string(),
+                orig_path: orig_path.map(str::to_string),
+                index: letters.next().unwrap_or_default(),
+                worktree: letters.next().unwrap_or_default(),
+                conflicted,
+            }
+        };
+        match kind {
+            "#" => match rest.split_once(' ') {
+                Some(("branch.head", head)) => {
+                    status.branch = (head != "(detached)").then(|| head.to_string())
+                }
+                Some(("branch.upstream", upstream)) => status.upstream = Some(upstream.to_string()),
+                Some(("branch.ab", ab)) => {
+                    for count in ab.split(' ') {
+                        if let Some(n) = count.strip_prefix('+') {
+                            status.ahead = n.parse().unwrap_or(0);
+                        } else if let Some(n) = count.strip_prefix('-') {
+                            status.behind = n.parse().unwrap_or(0);
+                        }
+                    }
+                }
+                _ => {}
+            },
+            "1" => {
+                let fields: Vec<&str> = rest.splitn(8, ' ').collect();
+                if let [xy, .., path] = fields[..] {
+                    status.files.push(file(xy, path, None, false));
+                }
+            }
+            "2" => {
+                let fields: Vec<&str> = rest.splitn(9, ' ').collect();
+                if let [xy, .., path] = fields[..] {
+                    let orig_path = records.next();
+                    status.files.push(file(xy, path, orig_path, false));
+                }
+            }
+            "u" => {
+                let fields: Vec<&str> = rest.splitn(10, ' ').collect();
+                if let [xy, .., path] = fields[..] {
+                    status.files.push(file(xy, path, None, true));
+                }
+            }
+            "?" => status.files.push(file("??", rest, None, false)),
+            _ => {}
+        }
+    }
+    status
+}
+
+pub fn status(repo: &Path) -> Result<Status, String> {
+    let top = require_toplevel(repo)?;
+    let output = git(
+        &top,
+        &[
+            "status",
+            "--porcelain=v2",
+            "--branch",
+            "--untracked-files=all",
+            "-z",
+        ],
+    )?;
+    Ok(parse_status(&output))
+}
+
+/// Staged changes, or unstaged ones including new untracked files, for the
+/// whole repo or only `path`.
+pub fn diff(repo: &Path, staged: bool, path: Option<&str>) -> Result<Diff, String> {
+    let top = require_toplevel(repo)?;
+    if let Some(path) = path {
+        check_path(path)?;
+    }
+    let args = |format| {
+        let mut args = vec!["diff", "--no-color", "--no-ext-diff", format];
+        if staged {
+            args.push("--cached");
+        }
+        if let Some(path) = path {
+            args.extend(["--", path]);
+        }
+        args
+    };
+    if staged {
+        return Diff::collect(|format| git(&top, &args(format)));
+    }
+    // Marking untracked files as intent-to-add in a scratch index makes them
+    // show up as new files without touching the real index
+    let index = TempIndex::copy_of_index(&top)?;
+    index.git(&top, &["add", "--intent-to-add", "--", "."])?;
+    Diff::collect(|format| index.git(&top, &args(format)))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Commit {
+    pub hash: String,
+    pub short: String,
+    pub author: String,
+    /// Unix seconds.
+    pub time: u64,
+    pub subject: String,
+}
+
+/// Parse `git log` output in the format used by `log`.
+fn parse_log(output: &str) -> Vec<Commit> {
+    output
+        .split("\0\n")
+        .filter_map(|record| {
+            let mut fields = record.trim_start_matches('\n').splitn(5, '\0');
+            Some(Commit {
+                hash: fields.next().filter(|h| !h.is_empty())?.to_string(),
+                short: fields.next()?.to_string(),
+                author: fields.next()?.to_string(),
+                time: fields.next()?.parse().ok()?,
+                subject: fields.next()?.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// The newest `limit` commits on HEAD.
+pub fn log(repo: &Path, limit: usize) -> Result<Vec<Commit>, String> {
+    let top = require_toplevel(repo)?;
+    if head(&top).is_none() {
+        return Ok(Vec::new());
+    }
+    let limit = format!("-n{}", limit.min(MAX_LOG));
+    let output = git(
+        &top,
+        &["log", &limit, "--format=%H%x00%h%x00%an%x00%at%x00%s%x00"],
+    )?;
+    Ok(parse_log(&output))
+}
+
+/// Files under `path` that differ from `since` and still exist, untracked
+/// ones included. Paths are relative to `repo`, which may be a subdirectory
+/// of the work tree.
+pub fn changed_since(repo: &Path, since: &str, path: &str) -> Result<Vec<String>, String> {
+    require_toplevel(repo)?;
+    if since.is_empty() || since.starts_with('-') {
+        return Err(format!("Invalid ref '{}'", since));
+    }
+    let spec = format!("{}^{{commit}}", since);
+    let commit = git(repo, &["rev-parse", "--verify", "-q", &spec])
+        .map_err(|_| format!("Unknown ref '{}'", since))?;
+    let path = if path.is_empty() { "." } else { path };
+    let changed = git(
+        repo,
+        &[
+            "diff",
+            "--name-only",
+            "--relative",
+            "--no-renames",
+            "-z",
+            commit.trim(),
+            "--",
+            path,
+        ],
+    )?;
+    let untracked = git(
+        repo,
+        &[
+            "ls-files",
+            "--others",
+            "--exclude-standard",
+            "-z",
+            "--",
+            path,
+        ],
+    )?;
+    let mut files: Vec<String> = changed
+        .split('\0')
+        .chain(untracked.split('\0'))
+        .filter(|f| !f.is_empty() && fs::symlink_metadata(repo.join(f)).is_ok())
+        .map(str::to_string)
+        .collect();
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Reject paths that are absolute or climb out of the repo.
+fn check_path(path: &str) -> Result<(), String> {
+    let ok = !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if ok {
+        Ok(())
+    } else {
+        Err(format!("Invalid path '{}'", path))
+    }
+}
+
+fn check_paths(paths: &[String]) -> Result<Vec<&str>, String> {
+    if paths.is_empty() {
+        return Err("No paths given".to_string());
+    }
+    paths
+        .iter()
+        .map(|p| check_path(p).map(|()| p.as_str()))
+        .collect()
+}
+
+/// `paths` for a message: the path itself when there is only one.
+fn describe(paths: &[&str]) -> String {
+    match paths {
+        [path] => path.to_string(),
+        _ => format!("{} paths", paths.len()),
+    }
+}
+
+pub fn stage(repo: &Path, paths: &[String]) -> Result<String, String> {
+    let top = require_toplevel(repo)?;
+    let paths = check_paths(paths)?;
+    git(&top, &[&["add", "--all", "--"][..], &paths].concat())?;
+    Ok(format!("Staged {}", describe(&paths)))
+}
+
+pub fn unstage(repo: &Path, paths: &[String]) -> Result<String, String> {
+    let top = require_toplevel(repo)?;
+    let paths = check_paths(paths)?;
+    // Before the first commit there is no HEAD to reset the index to
+    let args: &[&str] = if head(&top).is_some() {
+        &["restore", "--staged", "--"]
+    } else {
+        &["rm", "--cached", "-r", "-q", "--"]
+    };
+    git(&top, &[args, &paths].concat())?;
+    Ok(format!("Unstaged {}", describe(&paths)))
+}
+
+/// Throw away unstaged changes to `paths`, deleting them if untracked.
+/// Staged changes are kept.
+pub fn discard(repo: &Path, paths: &[String]) -> Result<String, String> {
+    let top = require_toplevel(repo)?;
+    let paths = check_paths(paths)?;
+    let status = status(&top)?;
+    let mut tracked = Vec::new();
+    for &path in &paths {
+        let Some(file) = status.files.iter().find(|f| f.path == path) else {
+            return Err(format!("'{}' has no changes", path));
+        };
+        if file.worktree == "?" {
+            let full = top.join(path);
+            fs::remove_file(&full)
+                .map_err(|e| format!("Failed to remove {}: {}", full.display(), e))?;
+        } else if file.worktree != "." {
+            tracked.push(path);
+        }
+    }
+    if !tracked.is_empty() {
+        git(
+            &top,
+            &[&["restore", "--worktree", "--"][..], &tracked].concat(),
+        )?;
+    }
+    Ok(format!("Discarded changes to {}", describe(&paths)))
+}
+
+/// Commit what is staged, as the user's configured identity.
+pub fn commit(repo: &Path, message: &str) -> Result<String, String> {
+    let top = require_toplevel(repo)?;
+    if message.trim().is_empty() {
+        return Err("Commit message is empty".to_string());
+    }
+    run(
+        command(&top, &["commit", "-q", "-F", "-"]),
+        Some(message.as_bytes()),
+    )?;
+    let short = git(&top, &["rev-parse", "--short", "HEAD"])?;
+    Ok(format!("Committed {}", short.trim()))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::env;
+
+    /// A fresh repo with a committer identity of its own.
+    pub fn temp_repo(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("vnccc-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        git(&dir, &["init", "-q"]).unwrap();
+        git(&dir, &["config", "user.name", "Test"]).unwrap();
+        git(&dir, &["config", "user.email", "test@example.com"]).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let output = "# branch.oid abc\0# branch.head main\0# branch.upstream origin/main\0\
+                      # branch.ab +2 -1\0\
+                      1 .M N... 100644 100644 100644 aaa bbb src/my file.rs\0\
+                      2 R. N... 100644 100644 100644 aaa bbb R100 new.rs\0old.rs\0\
+                      u UU N... 100644 100644 100644 100644 aaa bbb ccc both.rs\0\
+                      ? notes.txt\0";
+        let status = parse_status(output);
+        assert_eq!(status.branch.as_deref(), Some("main"));
+        assert_eq!(status.upstream.as_deref(), Some("origin/main"));
+        assert_eq!((status.ahead, status.behind), (2, 1));
+        assert_eq!(status.files.len(), 4);
+        assert_eq!(status.files[0].path, "src/my file.rs");
+        assert_eq!(
+            (
+                status.files[0].index.as_str(),
+                status.files[0].worktree.as_str()
+            ),
+            (".", "M")
+        );
+        assert_eq!(status.files[1].orig_path.as_deref(), Some("old.rs"));
+        assert!(status.files[2].conflicted);
+        assert_eq!(status.files[3].worktree, "?");
+
+        let detached = parse_status("# branch.head (detached)\0");
+        assert_eq!(detached.branch, None);
+    }
+
+    #[test]
+    fn test_parse_log() {
+        let output = "abc123\0abc\0Dev\x001700000000\0Fix it\0\n\
+                      def456\0def\0Dev\x001600000000\0Start\0\n";
+        let log = parse_log(output);
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].subject, "Fix it");
+        assert_eq!(log[1].time, 1600000000);
+        assert!(parse_log("").is_empty());
+    }
+
+    #[test]
+    fn test_check_path() {
+        assert!(check_path("src/main.rs").is_ok());
+        assert!(check_path("./a").is_ok());
+        assert!(check_path("../etc/passwd").is_err());
+        assert!(check_path("/etc/passwd").is_err());
+        assert!(check_path("").is_err());
+    }
+
+    #[test]
+    fn test_status_diff_and_actions() {
+        let repo = temp_repo("git");
+        assert!(log(&repo, 10).unwrap().is_empty());
+        fs::write(repo.join("a.txt"), "one\n").unwrap();
+        stage(&repo, &["a.txt".to_string()]).unwrap();
+        assert!(diff(&repo, true, None).unwrap().patch.contains("+one"));
+        commit(&repo, "First").unwrap();
+        assert_eq!(log(&repo, 10).unwrap()[0].subject, "First");
+
+        fs::write(repo.join("a.txt"), "two\n").unwrap();
+        fs::write(repo.join("new.txt"), "fresh\n").unwrap();
+        let status = status(&repo).unwrap();
+        assert_eq!(status.files.len(), 2);
+        let unstaged = diff(&repo, false, None).unwrap();
+        assert!(unstaged.patch.contains("+two"));
+        assert!(unstaged.patch.contains("+fresh"));
+        let one = diff(&repo, false, Some("new.txt")).unwrap();
+        assert!(!one.patch.contains("a.txt"));
+        // Untracked files stay untracked in the real index
+        assert_eq!(super::status(&repo).unwrap().files[1].index, "?");
+
+        stage(&repo, &["a.txt".to_string()]).unwrap();
+        unstage(&repo, &["a.txt".to_string()]).unwrap();
+        discard(&repo, &["a.txt".to_string(), "new.txt".to_string()]).unwrap();
+        assert_eq!(fs::read_to_string(repo.join("a.txt")).unwrap(), "one\n");
+        assert!(!repo.join("new.txt").exists());
+        assert!(discard(&repo, &["a.txt".to_string()]).is_err());
+
+        fs::create_dir_all(repo.join("sub")).unwrap();
+        fs::write(repo.join("sub/b.txt"), "b\n").unwrap();
+        fs::write(repo.join("c.txt"), "c\n").unwrap();
+        fs::write(repo.join("a.txt"), "three\n").unwrap();
+        assert_eq!(
+            changed_since(&repo, "HEAD", "").unwrap(),
+            vec!["a.txt", "c.txt", "sub/b.txt"]
+        );
+        assert_eq!(
+            changed_since(&repo, "HEAD", "sub").unwrap(),
+            vec!["sub/b.txt"]
+        );
+        assert_eq!(
+            changed_since(&repo.join("sub"), "HEAD", "").unwrap(),
+            vec!["b.txt"]
+        );
+        assert!(changed_since(&repo, "--output=x", "").is_err());
+        assert!(changed_since(&repo, "nope", "").is_err());
+        fs::remove_dir_all(repo).unwrap();
+    }
+}
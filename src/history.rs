@@ -0,0 +1,245 @@
+//! Prompts sent from the web UI, kept per workspace in the state directory
+//! as one JSON line per prompt, so they can be searched and sent again from
+//! any device.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::config;
+
+/// Most entries a search returns.
+const MAX_RESULTS: usize = 500;
+/// Most entries kept per workspace; older ones are dropped as new ones come.
+const MAX_ENTRIES: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Seconds since the epoch.
+    pub time: u64,
+    pub session: String,
+    pub workspace: String,
+    pub agent: Option<String>,
+    pub text: String,
+    /// Whether the prompt was typed into the terminal.
+    pub delivered: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The prompt history, or nothing when there is no state directory.
+pub struct History {
+    dir: Option<PathBuf>,
+    /// Keeps appended lines from interleaving.
+    lock: Mutex<()>,
+}
+
+/// File name for a workspace's history: its path with separators and other
+/// unusual characters replaced, so the directory stays readable.
+fn file_name(workspace: &str) -> String {
+    let name: String = workspace
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("{}.jsonl", name)
+}
+
+/// Entries in one history file, oldest first. Lines that do not parse, such
+/// as one cut short by a crash, are skipped.
+fn read(path: &Path) -> Vec<Entry> {
+    fs::read_to_string(path)
+        .map(|contents| {
+            contents
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl History {
+    pub fn new(dir: Option<PathBuf>) -> History {
+        History {
+            dir,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Append `entry` to its workspace's history, keeping only the newest
+    /// `MAX_ENTRIES`.
+    pub fn record(&self, entry: &Entry) -> Result<(), String> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let line = serde_json::to_string(entry).map_err(|e| e.to_string())?;
+        let path = dir.join(file_name(&entry.workspace));
+        let _guard = self.lock.lock().unwrap();
+        fs::create_dir_all(dir)
+            .and_then(|()| fs::OpenOptions::new().create(true).append(true).open(&path))
+            .and_then(|mut file| writeln!(file, "{}", line))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let lines: Vec<&str> = contents.lines().collect();
+        if lines.len() <= MAX_ENTRIES {
+            return Ok(());
+        }
+        let kept = &lines[lines.len() - MAX_ENTRIES..];
+        config::write_atomic(&path, &format!("{}\n", kept.join("\n")))
+    }
+
+    /// Up to `limit` prompts containing all words of `query`, ignoring case,
+    /// newest first. Only those sent in `workspace` when it is given.
+    pub fn search(&self, query: &str, workspace: Option<&str>, limit: usize) -> Vec<Entry> {
+        let Some(dir) = &self.dir else {
+            return Vec::new();
+        };
+        let mut entries: Vec<Entry> = match workspace.filter(|w| !w.is_empty()) {
+            Some(workspace) => read(&dir.join(file_name(workspace)))
+                .into_iter()
+                // Two paths can map to the same file name
+                .filter(|e| e.workspace == workspace)
+                .collect(),
+            None => fs::read_dir(dir)
+                .map(|files| {
+                    files
+                        .flatten()
+                        .filter(|f| f.path().extension().is_some_and(|ext| ext == "jsonl"))
+                        .flat_map(|f| read(&f.path()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+        let words: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        entries.retain(|e| {
+            let text = e.text.to_lowercase();
+            words.iter().all(|word| text.contains(word))
+        });
+        // Stable, so prompts sent within the same second keep their order
+        entries.reverse();
+        entries.sort_by_key(|e| std::cmp::Reverse(e.time));
+        entries.truncate(limit.min(MAX_RESULTS));
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn entry(time: u64, workspace: &str, text: &str) -> Entry {
+        Entry {
+            time,
+            session: "1".to_string(),
+            workspace: workspace.to_string(),
+            agent: Some("claude".to_string()),
+            text: text.to_string(),
+            delivered: true,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_record_and_search() {
+        let dir = env::temp_dir().join(format!("vnccc-test-history-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let history = History::new(Some(dir.clone()));
+        history.record(&entry(1, "/repo", "Run the tests")).unwrap();
+        history
+            .record(&entry(2, "/repo", "Fix the failing TESTS"))
+            .unwrap();
+        history.record(&entry(2, "/other", "open a PR")).unwrap();
+        assert!(dir.join("-repo.jsonl").exists());
+
+        let search = |q: &str, workspace: Option<&str>| -> Vec<String> {
+            history
+                .search(q, workspace, 50)
+                .into_iter()
+                .map(|e| e.text)
+                .collect()
+        };
+        assert_eq!(
+            search("", Some("/repo")),
+            ["Fix the failing TESTS", "Run the tests"]
+        );
+        assert_eq!(search("tests fix", None), ["Fix the failing TESTS"]);
+        assert_eq!(search("", None).len(), 3);
+        assert!(search("", Some("/nowhere")).is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn temp_history(name: &str) -> (PathBuf, History) {
+        let dir = env::temp_dir().join(format!(
+            "vnccc-test-history-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        (dir.clone(), History::new(Some(dir)))
+    }
+
+    #[test]
+    fn test_recall_order() {
+        let (dir, history) = temp_history("order");
+        history.record(&entry(5, "/repo", "first")).unwrap();
+        history.record(&entry(5, "/repo", "second")).unwrap();
+        history.record(&entry(7, "/repo", "third")).unwrap();
+        // A clock set back still lists by time
+        history.record(&entry(6, "/repo", "fourth")).unwrap();
+        let recall: Vec<String> = history
+            .search("", Some("/repo"), 3)
+            .into_iter()
+            .map(|e| e.text)
+            .collect();
+        assert_eq!(recall, ["third", "fourth", "second"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_lines() {
+        let (dir, history) = temp_history("corrupt");
+        history.record(&entry(1, "/repo", "before")).unwrap();
+        // A line cut short by a crash, then one that is not an entry at all
+        let path = dir.join("-repo.jsonl");
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        write!(
+            file,
+            "{{\"time\":2,\"session\":\"1\",\"text\":\"cut\n[1, 2]\n"
+        )
+        .unwrap();
+        history.record(&entry(3, "/repo", "after")).unwrap();
+        let texts: Vec<String> = history
+            .search("", Some("/repo"), 10)
+            .into_iter()
+            .map(|e| e.text)
+            .collect();
+        assert_eq!(texts, ["after", "before"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_cap() {
+        let (dir, history) = temp_history("cap");
+        for i in 0..MAX_ENTRIES as u64 + 5 {
+            history
+                .record(&entry(i, "/repo", &format!("prompt {}", i)))
+                .unwrap();
+        }
+        history.record(&entry(0, "/other", "elsewhere")).unwrap();
+        let path = dir.join("-repo.jsonl");
+        assert_eq!(read(&path).len(), MAX_ENTRIES);
+        assert_eq!(read(&path)[0].text, "prompt 5");
+        assert_eq!(history.search("", Some("/other"), 10).len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
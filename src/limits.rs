@@ -0,0 +1,362 @@
+//! Resource limits for the agent: rlimits that `vnccc agent-loop` sets on
+//! itself so every agent it starts inherits them, and a per-session cgroup
+//! when the container has cgroup v2 delegated to it.
+
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::config::Limits;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Leaf that vnccc's own processes move to, so the cgroup above it can hand
+/// controllers down to session cgroups (cgroup v2 forbids processes in a
+/// cgroup whose children have controllers enabled).
+const LEAF: &str = "vnccc";
+/// cpu.max period, in microseconds.
+const CPU_PERIOD: u64 = 100_000;
+
+/// Parse a byte size such as `512M`, `4G` or `1073741824`.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid size '{}', expected e.g. 512M or 4G", s);
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(invalid()),
+    };
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    number.checked_mul(multiplier).ok_or_else(invalid)
+}
+
+/// One rlimit for `agent-loop`, as passed with `--rlimit name=value`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rlimit {
+    pub name: &'static str,
+    pub value: u64,
+}
+
+impl Rlimit {
+    pub fn parse(arg: &str) -> Result<Rlimit, String> {
+        let (name, value) = arg
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid rlimit '{}', expected name=value", arg))?;
+        let name = ["as", "nofile", "nproc"]
+            .into_iter()
+            .find(|n| *n == name)
+            .ok_or_else(|| format!("Unknown rlimit '{}'", name))?;
+        let value = value
+            .parse()
+            .map_err(|_| format!("Invalid rlimit value in '{}'", arg))?;
+        Ok(Rlimit { name, value })
+    }
+
+    /// Set this limit on the calling process; its children inherit it.
+    pub fn apply(&self) -> std::io::Result<()> {
+        let resource = match self.name {
+            "as" => libc::RLIMIT_AS,
+            "nofile" => libc::RLIMIT_NOFILE,
+            _ => libc::RLIMIT_NPROC,
+        };
+        let limit = libc::rlimit {
+            rlim_cur: self.value as libc::rlim_t,
+            rlim_max: self.value as libc::rlim_t,
+        };
+        // SAFETY: setrlimit reads a valid rlimit struct
+        if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// rlimits for the agent. Memory and process count go through the cgroup when
+/// there is one: RLIMIT_AS caps address space rather than memory use, and
+/// RLIMIT_NPROC counts every process of the user, vnccc's included.
+pub fn rlimits(limits: &Limits, has_cgroup: bool) -> Vec<Rlimit> {
+    let mut rlimits = Vec::new();
+    if let Some(open_files) = limits.open_files {
+        rlimits.push(Rlimit {
+            name: "nofile",
+            value: open_files,
+        });
+    }
+    if !has_cgroup {
+        if let Some(memory) = limits.memory_bytes() {
+            rlimits.push(Rlimit {
+                name: "as",
+                value: memory,
+            });
+        }
+        if let Some(max_processes) = limits.max_processes {
+            rlimits.push(Rlimit {
+                name: "nproc",
+                value: max_processes,
+            });
+        }
+    }
+    rlimits
+}
+
+/// Our own cgroup, from the `0::/path` line of `/proc/self/cgroup`.
+fn own_cgroup(contents: &str) -> Option<PathBuf> {
+    let path = contents.lines().find_map(|line| line.strip_prefix("0::"))?;
+    Some(Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')))
+}
+
+/// Make the cgroup vnccc was started in hand its memory, cpu and pids
+/// controllers to children, moving its processes into a leaf first.
+fn delegate() -> Result<PathBuf, String> {
+    let contents = fs::read_to_string("/proc/self/cgroup")
+        .map_err(|e| format!("Failed to read /proc/self/cgroup: {}", e))?;
+    let mut base = own_cgroup(&contents).ok_or("Not running under cgroup v2")?;
+    // Already delegated by an earlier vnccc in this container
+    if base.file_name().is_some_and(|name| name == LEAF) {
+        base.pop();
+    }
+
+    let available = fs::read_to_string(base.join("cgroup.controllers"))
+        .map_err(|e| format!("No cgroup v2 hierarchy at {}: {}", base.display(), e))?;
+    let wanted: Vec<&str> = ["memory", "cpu", "pids"]
+        .into_iter()
+        .filter(|c| available.split_whitespace().any(|a| a == *c))
+        .collect();
+    if wanted.is_empty() {
+        return Err(format!("No usable controllers in {}", base.display()));
+    }
+
+    let leaf = base.join(LEAF);
+    fs::create_dir_all(&leaf).map_err(|e| format!("Failed to create {}: {}", leaf.display(), e))?;
+    let procs = fs::read_to_string(base.join("cgroup.procs")).unwrap_or_default();
+    for pid in procs.lines() {
+        // Kernel threads and exiting processes cannot move, which is fine
+        let _ = fs::write(leaf.join("cgroup.procs"), pid);
+    }
+
+    let enable: Vec<String> = wanted.iter().map(|c| format!("+{}", c)).collect();
+    fs::write(base.join("cgroup.subtree_control"), enable.join(" ")).map_err(|e| {
+        format!(
+            "Failed to enable {} in {}: {}",
+            wanted.join(", "),
+            base.display(),
+            e
+        )
+    })?;
+    Ok(base)
+}
+
+/// The delegated cgroup session cgroups are created under, set up on first use.
+fn delegated_base() -> Option<&'static Path> {
+    static BASE: OnceLock<Option<PathBuf>> = OnceLock::new();
+    BASE.get_or_init(|| match delegate() {
+        Ok(base) => {
+            println!("Agent cgroups under {}", base.display());
+            Some(base)
+        }
+        Err(e) => {
+            println!(
+                "cgroup v2 delegation unavailable, using rlimits only: {}",
+                e
+            );
+            None
+        }
+    })
+    .as_deref()
+}
+
+/// Create the cgroup for session `id` with the configured limits, or `None`
+/// when cgroups are not delegated to us.
+pub fn create_cgroup(id: &str, limits: &Limits) -> Option<PathBuf> {
+    let base = delegated_base()?;
+    let path = base.join(format!("session-{}", id));
+    if let Err(e) = fs::create_dir_all(&path) {
+        eprintln!("Failed to create {}: {}", path.display(), e);
+        return None;
+    }
+    let mut settings = Vec::new();
+    if let Some(memory) = limits.memory_bytes() {
+        settings.push(("memory.max", memory.to_string()));
+    }
+    if let Some(cpus) = limits.cpus {
+        let quota = (cpus * CPU_PERIOD as f64).round() as u64;
+        settings.push(("cpu.max", format!("{} {}", quota, CPU_PERIOD)));
+    }
+    if let Some(max_processes) = limits.max_processes {
+        settings.push(("pids.max", max_processes.to_string()));
+    }
+    for (file, value) in settings {
+        if let Err(e) = fs::write(path.join(file), &value) {
+            eprintln!("Failed to set {} to {}: {}", file, value, e);
+        }
+    }
+    Some(path)
+}
+
+/// Remove a session cgroup. Fails while processes are still in it.
+pub fn remove_cgroup(path: &Path) -> std::io::Result<()> {
+    match fs::remove_dir(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Move the calling process into `cgroup`; its children follow.
+pub fn join_cgroup(cgroup: &Path) -> Result<(), String> {
+    fs::write(cgroup.join("cgroup.procs"), std::process::id().to_string())
+        .map_err(|e| format!("Failed to join {}: {}", cgroup.display(), e))
+}
+
+/// How often the agent has run into its limits.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct LimitHits {
+    /// Times memory use reached memory.max.
+    pub memory_max: u64,
+    /// Processes killed by the OOM killer.
+    pub oom_kills: u64,
+    /// Forks refused by pids.max.
+    pub pids_max: u64,
+    /// Periods in which the agent was throttled by cpu.max.
+    pub cpu_throttled: u64,
+    /// Processes currently at their open-files limit.
+    pub open_files_at_limit: usize,
+}
+
+impl LimitHits {
+    /// Counters from a cgroup's `memory.events`, `pids.events` and `cpu.stat`.
+    pub fn read(cgroup: &Path) -> LimitHits {
+        let read = |file: &str| fs::read_to_string(cgroup.join(file)).unwrap_or_default();
+        let memory = read("memory.events");
+        let pids = read("pids.events");
+        let cpu = read("cpu.stat");
+        LimitHits {
+            memory_max: counter(&memory, "max"),
+            oom_kills: counter(&memory, "oom_kill"),
+            pids_max: counter(&pids, "max"),
+            cpu_throttled: counter(&cpu, "nr_throttled"),
+            open_files_at_limit: 0,
+        }
+    }
+
+    /// Descriptions of the counters that went up since `before`.
+    pub fn increases(&self, before: &LimitHits) -> Vec<String> {
+        let mut found = Vec::new();
+        let mut check = |now: u64, then: u64, what: &str| {
+            if now > then {
+                found.push(format!("{} {}", now - then, what));
+            }
+        };
+        check(
+            self.memory_max,
+            before.memory_max,
+            "times at the memory limit",
+        );
+        check(self.oom_kills, before.oom_kills, "processes OOM-killed");
+        check(
+            self.pids_max,
+            before.pids_max,
+            "forks refused at the process limit",
+        );
+        check(
+            self.cpu_throttled,
+            before.cpu_throttled,
+            "periods throttled by the CPU limit",
+        );
+        check(
+            self.open_files_at_limit as u64,
+            before.open_files_at_limit as u64,
+            "more processes at the open-files limit",
+        );
+        found
+    }
+}
+
+/// Value of `key` in a flat-keyed cgroup file such as `memory.events`.
+fn counter(contents: &str, key: &str) -> u64 {
+    contents
+        .lines()
+        .find_map(|line| {
+            let (k, v) = line.split_once(' ')?;
+            (k == key).then(|| v.trim().parse().ok())?
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_size("4G"), Ok(4 << 30));
+        assert_eq!(parse_size("2 GiB"), Ok(2 << 30));
+        assert!(parse_size("4X").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_rlimit_parse() {
+        assert_eq!(
+            Rlimit::parse("nofile=4096"),
+            Ok(Rlimit {
+                name: "nofile",
+                value: 4096
+            })
+        );
+        assert!(Rlimit::parse("stack=1").is_err());
+        assert!(Rlimit::parse("nofile").is_err());
+        assert!(Rlimit::parse("nofile=lots").is_err());
+    }
+
+    #[test]
+    fn test_rlimits_defer_to_cgroup() {
+        let limits = Limits {
+            memory: Some("1G".to_string()),
+            cpus: Some(2.0),
+            max_processes: Some(256),
+            open_files: Some(1024),
+        };
+        let names = |rlimits: Vec<Rlimit>| rlimits.iter().map(|r| r.name).collect::<Vec<_>>();
+        assert_eq!(names(rlimits(&limits, true)), vec!["nofile"]);
+        assert_eq!(
+            names(rlimits(&limits, false)),
+            vec!["nofile", "as", "nproc"]
+        );
+    }
+
+    #[test]
+    fn test_own_cgroup() {
+        assert_eq!(
+            own_cgroup("0::/docker/abc\n"),
+            Some(PathBuf::from("/sys/fs/cgroup/docker/abc"))
+        );
+        assert_eq!(own_cgroup("0::/\n"), Some(PathBuf::from("/sys/fs/cgroup/")));
+        assert_eq!(own_cgroup("12:memory:/foo\n"), None);
+    }
+
+    #[test]
+    fn test_limit_hits() {
+        let events = "low 0\nhigh 0\nmax 7\noom 1\noom_kill 1\n";
+        assert_eq!(counter(events, "max"), 7);
+        assert_eq!(counter(events, "oom_kill"), 1);
+        assert_eq!(counter(events, "missing"), 0);
+
+        let before = LimitHits::default();
+        let after = LimitHits {
+            oom_kills: 2,
+            ..LimitHits::default()
+        };
+        assert_eq!(after.increases(&before), vec!["2 processes OOM-killed"]);
+        assert!(before.increases(&after).is_empty());
+    }
+}
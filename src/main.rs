@@ -1,231 +1,334 @@
+mod activity;
+mod agent_loop;
+mod checkpoint;
+mod cli;
+mod config;
+mod display;
+mod files;
+mod git;
+mod history;
+mod limits;
+mod notify;
+mod procfs;
+mod queue;
+mod rfb;
+mod sandbox;
+mod session;
+mod snippets;
+mod terminal;
+mod theme;
+mod webpush;
+mod workspace;
+
 use axum::{
     Router,
-    extract::State,
     extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    http::StatusCode,
-    response::{Html, IntoResponse, Json},
-    routing::{get, post},
+    extract::{DefaultBodyLimit, FromRequestParts, Multipart, Path as UrlPath, Query, State},
+    http::request::Parts,
+    http::{StatusCode, header},
+    response::{Html, IntoResponse, Json, Redirect},
+    routing::{get, post, put},
 };
-use futures::StreamExt;
+use futures::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::env;
-use std::fs;
-use std::io::{Read as _, Write as _};
-use std::mem;
 use std::path::Path;
-use std::process::{Child, Command, Stdio};
+use std::process::Command;
 use std::sync::Arc;
-use std::thread;
 use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 use tokio::signal;
-use tokio::sync::Mutex;
 use tower_http::services::ServeDir;
 
+use activity::{AgentState, Watch};
+use checkpoint::Checkpoint;
+use config::Config;
+use display::Geometry;
+use limits::LimitHits;
+use procfs::{ProcessUsage, UsageTotals};
+use sandbox::{Probe, Rules};
+use session::{Power, Session, SessionManager, SessionOptions};
+use terminal::{Applied, BackendError, FontSpec, Palette, TerminalBackend, TerminalContext};
+
+/// Where the distribution's noVNC package installs its web client.
+const NOVNC_DIR: &str = "/usr/share/novnc";
+
 struct AppState {
-    display: u32,
+    sessions: SessionManager,
+    /// Terminal used for sessions that do not pick one.
+    terminal: Arc<dyn TerminalBackend>,
+    /// Geometry used for sessions that do not pick one.
+    geometry: Geometry,
+    geometry_presets: BTreeMap<String, Geometry>,
+    /// Bearer token for the lifecycle endpoints, if authentication is on.
+    api_token: Option<String>,
+    emergency_stop: config::EmergencyStop,
+    limits: config::Limits,
+    sandbox: config::Sandbox,
+    checkpoints: config::Checkpoints,
+    activity: config::Activity,
+    notifier: Arc<notify::Notifier>,
+    /// Whether the git panel may change the repo.
+    git_actions: bool,
+    uploads: config::Uploads,
+    workspace: Arc<workspace::Provisioning>,
+    history: history::History,
+}
+
+/// Guard for endpoints that start, stop or signal things. Passes when no
+/// token is configured.
+struct Authorized;
+
+impl FromRequestParts<Arc<AppState>> for Authorized {
+    type Rejection = (StatusCode, Json<ApiResponse>);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(token) = &state.api_token else {
+            return Ok(Authorized);
+        };
+        let provided = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        match provided {
+            Some(provided) if tokens_match(provided, token) => Ok(Authorized),
+            _ => Err((
+                StatusCode::UNAUTHORIZED,
+                Json(ApiResponse {
+                    success: false,
+                    message: "Missing or invalid API token".to_string(),
+                }),
+            )),
+        }
+    }
 }
 
-struct Processes {
-    vnc: Child,
-    websockify: Child,
+/// Compare without returning early so timing does not reveal the prefix.
+fn tokens_match(provided: &str, expected: &str) -> bool {
+    provided.len() == expected.len()
+        && provided
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
 }
 
-#[derive(Deserialize)]
-struct FontSizeRequest {
-    size: f32,
+/// The session named by the `{id}` segment of a `/s/{id}/...` route.
+struct SessionRef(Arc<Session>);
+
+impl FromRequestParts<Arc<AppState>> for SessionRef {
+    type Rejection = (StatusCode, Json<ApiResponse>);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let not_found = |id: &str| {
+            (
+                StatusCode::NOT_FOUND,
+                Json(ApiResponse {
+                    success: false,
+                    message: format!("No session '{}'", id),
+                }),
+            )
+        };
+        let UrlPath(params) = UrlPath::<HashMap<String, String>>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| not_found(""))?;
+        let id = params.get("id").map(String::as_str).unwrap_or_default();
+        state
+            .sessions
+            .get(id)
+            .map(SessionRef)
+            .ok_or_else(|| not_found(id))
+    }
 }
 
 #[derive(Serialize)]
-struct FontSizeResponse {
+struct ApiResponse {
     success: bool,
     message: String,
 }
 
-fn find_available_display() -> u32 {
-    for display in 1..100 {
-        let lock_file = format!("/tmp/.X{}-lock", display);
-        if !Path::new(&lock_file).exists() {
-            return display;
-        }
-    }
-    panic!("No available display found");
+#[derive(Deserialize)]
+struct CreateSessionRequest {
+    repo_path: String,
+    #[serde(default)]
+    id: Option<String>,
+    /// `WIDTHxHEIGHT` or a preset name.
+    #[serde(default)]
+    geometry: Option<String>,
+    #[serde(default)]
+    terminal: Option<String>,
+    #[serde(default)]
+    agent: Option<String>,
+    /// Run in a new git worktree of the repo instead of the repo itself.
+    #[serde(default)]
+    worktree: bool,
+    /// The worktree's branch, `vncaa/<id>` by default.
+    #[serde(default)]
+    branch: Option<String>,
+    /// Where the worktree's branch starts, HEAD by default.
+    #[serde(default, rename = "ref")]
+    git_ref: Option<String>,
 }
 
-fn start_vnc_server(display: u32, geometry: &str) -> Child {
-    let vnc = Command::new("Xvnc")
-        .args([
-            &format!(":{}", display),
-            "-geometry",
-            geometry,
-            "-depth",
-            "24",
-            "-SecurityTypes",
-            "None",
-        ])
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn();
-
-    match vnc {
-        Ok(child) => child,
-        Err(_) => {
-            eprintln!("Xvnc not found, trying vncserver...");
-            Command::new("vncserver")
-                .args([
-                    &format!(":{}", display),
-                    "-geometry",
-                    geometry,
-                    "-depth",
-                    "24",
-                ])
-                .stdout(Stdio::null())
-                .stderr(Stdio::null())
-                .spawn()
-                .expect("Failed to start VNC server. Install tigervnc.")
-        }
-    }
+#[derive(Deserialize)]
+struct DeleteSessionQuery {
+    /// What to do with the session's worktree.
+    #[serde(default)]
+    worktree: workspace::Teardown,
 }
 
-fn start_websockify(vnc_port: u32, ws_port: u32) -> Child {
-    Command::new("websockify")
-        .args([
-            "--web=/usr/share/novnc",
-            &format!("0.0.0.0:{}", ws_port),
-            &format!("localhost:{}", vnc_port),
-        ])
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()
-        .expect("Failed to start websockify. Install novnc.")
+#[derive(Serialize)]
+struct SessionInfo {
+    id: String,
+    url: String,
+    repo_path: String,
+    agent: Option<String>,
+    /// The session's own worktree and branch, if it has one.
+    worktree: Option<workspace::Worktree>,
+    terminal: String,
+    display: u32,
+    geometry: String,
+    /// Whether the terminal is currently up (it is briefly down while restarting).
+    running: bool,
+    /// Process group of the agent, null while it is not running.
+    agent_pgid: Option<i32>,
+    paused: bool,
+    /// Stopped with the emergency stop and not restarted until resumed.
+    held: bool,
+    /// awake, or suspended/stopped by the idle timeout, or waking.
+    power: &'static str,
+    /// busy, idle, attention (asking a question), paused or offline.
+    agent_state: &'static str,
+    /// What `agent_state` is worked out from: text, pixels or cpu. Only text
+    /// shows when the agent asks something. Null before the first look.
+    agent_watch: Option<Watch>,
+    /// Resource use of the terminal's process tree, null while it is not running.
+    usage: Option<UsageTotals>,
+    /// How often the agent ran into its resource limits, null without limits.
+    limit_hits: Option<LimitHits>,
 }
 
-fn start_window_manager(display: u32) -> Child {
-    let display_env = format!(":{}", display);
-    Command::new("ratpoison")
-        .env("DISPLAY", &display_env)
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()
-        .expect("Failed to start ratpoison window manager")
+impl SessionInfo {
+    fn new(session: &Session) -> SessionInfo {
+        SessionInfo {
+            id: session.id.clone(),
+            url: format!("/s/{}/", session.id),
+            repo_path: session.repo_path(),
+            agent: session.agent.clone(),
+            worktree: session.worktree.clone(),
+            terminal: session.terminal.name().to_string(),
+            display: session.display,
+            geometry: session.geometry.lock().unwrap().to_string(),
+            running: session.is_running(),
+            agent_pgid: session.agent_pgid(),
+            paused: session.paused.load(std::sync::atomic::Ordering::SeqCst),
+            held: session.is_held(),
+            power: session.power().name(),
+            agent_state: session.agent_state().name(),
+            agent_watch: session.agent_watch(),
+            usage: session
+                .process_usage()
+                .map(|processes| UsageTotals::sum(&processes)),
+            limit_hits: session.limit_hits(),
+        }
+    }
 }
 
-fn find_terminal() -> &'static str {
-    "alacritty"
+#[derive(Serialize)]
+struct SessionResponse {
+    success: bool,
+    message: String,
+    session: Option<SessionInfo>,
 }
 
-fn start_terminal_with_agent(
-    display: u32,
-    repo_path: &str,
-    terminal: &str,
-    geometry: &str,
-) -> Child {
-    let display_env = format!(":{}", display);
+#[derive(Deserialize)]
+struct FontSizeRequest {
+    size: f32,
+    /// Restart the terminal when the backend cannot change the size live.
+    #[serde(default)]
+    restart: bool,
+}
 
-    // Check if DEBUG mode is enabled
-    let debug_mode = env::var("DEBUG").unwrap_or_default() == "true";
-
-    let (agent_cmd, _log_file_opt) = if debug_mode {
-        // Use debug launcher script for verbose logging
-        // Redirect stdout/stderr to a log file that we can monitor
-        let log_file = format!("/tmp/agent-debug-{}.log", std::process::id());
-        let debug_launcher = "/usr/local/bin/debug-agent-launcher.sh";
-        let cmd = format!("{} {} > {} 2>&1", debug_launcher, repo_path, log_file);
-        println!("=== DEBUG MODE ENABLED ===");
-        println!("Agent output will be logged to: {}", log_file);
-        println!("=== Terminal Launch Debug ===");
-        println!("Display: {}", display_env);
-        println!("Repo path: {}", repo_path);
-        println!("Terminal: {}", terminal);
-        (cmd, Some(log_file))
-    } else {
-        // Standard mode: use agent wrapper directly
-        let cmd = format!("cd -- {} && /usr/local/bin/agent", repo_path);
-        (cmd, None)
-    };
+#[derive(Deserialize)]
+struct FontRequest {
+    family: String,
+    #[serde(default)]
+    style: Option<String>,
+    #[serde(default)]
+    restart: bool,
+}
 
-    if debug_mode {
-        println!("Agent command: {}", agent_cmd);
-    }
+/// Either a theme name from `/api/themes` or a full custom palette.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ThemeRequest {
+    Named { name: String },
+    Custom(Box<Palette>),
+}
 
-    let mut cmd = Command::new(terminal);
-    cmd.env("DISPLAY", &display_env);
+#[derive(Serialize)]
+struct SettingResponse {
+    success: bool,
+    message: String,
+    /// Whether the running terminal already shows the new setting.
+    live: bool,
+}
 
-    // Capture stdout and stderr for debugging - NOTE: terminal emulators typically
-    // don't output to stdout/stderr, but we capture them anyway to catch any errors
-    cmd.stdout(Stdio::piped());
-    cmd.stderr(Stdio::piped());
+#[derive(Serialize)]
+struct TerminalSettingsResponse {
+    terminal: String,
+    /// Current font size, or null when the emulator's default is in use.
+    font_size: Option<f32>,
+    font_family: Option<String>,
+    font_style: Option<String>,
+    /// Selected theme name, null for the emulator's default or a custom palette.
+    theme: Option<String>,
+}
 
-    if debug_mode {
-        println!("Command to execute: {:?}", cmd);
-    }
+/// A preset name from config or an explicit size.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GeometryRequest {
+    Preset { preset: String },
+    Size { width: u32, height: u32 },
+}
 
-    // Pass through important env vars
-    if let Ok(claude_dir) = env::var("CLAUDE_CONFIG_DIR") {
-        println!("Passing CLAUDE_CONFIG_DIR={} to terminal", claude_dir);
-        cmd.env("CLAUDE_CONFIG_DIR", &claude_dir);
-    }
-    if let Ok(home) = env::var("HOME") {
-        if debug_mode {
-            println!("Passing HOME={} to terminal", home);
-        }
-        cmd.env("HOME", &home);
-    }
+#[derive(Serialize)]
+struct GeometryResponse {
+    success: bool,
+    message: String,
+    geometry: String,
+}
 
-    // Log AGENT env var if present
-    if let Ok(agent) = env::var("AGENT") {
-        println!("Detected AGENT={}", agent);
-        cmd.env("AGENT", &agent);
-    }
+#[derive(Serialize)]
+struct DisplaySettingsResponse {
+    geometry: String,
+    presets: BTreeMap<String, String>,
+}
 
-    match terminal {
-        "wezterm" => {
-            cmd.args([
-                "start",
-                "--always-new-process",
-                "--",
-                "bash",
-                "-c",
-                &agent_cmd,
-            ]);
-        }
-        "alacritty" => {
-            cmd.args(["-e", "bash", "-c", &agent_cmd]);
-        }
-        "kitty" => {
-            cmd.args(["--start-as", "fullscreen", "bash", "-c", &agent_cmd]);
-        }
-        "xterm" => {
-            cmd.args([
-                "-maximized",
-                "-fa",
-                "Monospace",
-                "-fs",
-                "14",
-                "-bg",
-                "black",
-                "-fg",
-                "white",
-                "-e",
-                "bash",
-                "-c",
-                &agent_cmd,
-            ]);
-        }
-        "urxvt" => {
-            cmd.args(["-geometry", geometry, "-e", "bash", "-c", &agent_cmd]);
-        }
-        "st" => {
-            cmd.args(["-e", "bash", "-c", &agent_cmd]);
-        }
-        _ => {
-            cmd.args(["-e", "bash", "-c", &agent_cmd]);
-        }
-    }
+#[derive(Serialize)]
+struct FontListResponse {
+    families: Vec<String>,
+}
 
-    cmd.spawn().expect("Failed to start terminal")
+#[derive(Serialize)]
+struct ScrollbackResponse {
+    success: bool,
+    message: String,
+    text: String,
 }
 
-async fn send_text_to_display(display: u32, text: &str) {
+/// Type `text` into the terminal and press Enter.
+async fn send_text_to_display(display: u32, window_class: &str, text: &str) -> Result<(), String> {
     let display_env = format!(":{}", display);
 
     println!("Sending text to display :{} - '{}'", display, text);
@@ -235,7 +338,7 @@ async fn send_text_to_display(display: u32, text: &str) {
     for attempt in 0..10 {
         let output = Command::new("xdotool")
             .env("DISPLAY", &display_env)
-            .args(["search", "--class", "Alacritty"])
+            .args(["search", "--class", window_class])
             .output();
 
         if let Ok(out) = &output
@@ -244,7 +347,7 @@ async fn send_text_to_display(display: u32, text: &str) {
             // Window found, now focus it
             let focus_result = Command::new("xdotool")
                 .env("DISPLAY", &display_env)
-                .args(["search", "--class", "Alacritty", "windowfocus", "--sync"])
+                .args(["search", "--class", window_class, "windowfocus", "--sync"])
                 .status();
             println!(
                 "xdotool focus result (attempt {}): {:?}",
@@ -262,7 +365,7 @@ async fn send_text_to_display(display: u32, text: &str) {
 
     if !focus_ok {
         println!("Failed to focus terminal window after retries");
-        return;
+        return Err("Terminal window not found".to_string());
     }
 
     // Type the text and press Enter
@@ -277,6 +380,15 @@ async fn send_text_to_display(display: u32, text: &str) {
         .args(["key", "Return"])
         .status();
     println!("xdotool key result: {:?}", key_result);
+
+    match (type_result, key_result) {
+        (Ok(typed), Ok(pressed)) if typed.success() && pressed.success() => Ok(()),
+        (Err(e), _) | (_, Err(e)) => Err(format!("Failed to run xdotool: {}", e)),
+        (typed, _) => Err(format!(
+            "xdotool failed: {}",
+            typed.map(|s| s.to_string()).unwrap_or_default()
+        )),
+    }
 }
 
 async fn get_clipboard_contents(display: u32) -> String {
@@ -334,23 +446,220 @@ async fn get_clipboard_contents(display: u32) -> String {
     }
 }
 
-async fn index_handler() -> Html<&'static str> {
+async fn landing_handler() -> Html<&'static str> {
+    Html(include_str!("sessions.html"))
+}
+
+/// Serve the session page, waking the session in the background if the idle
+/// timeout put it to sleep. The page shows progress until it is back.
+async fn session_index_handler(SessionRef(session): SessionRef) -> Html<&'static str> {
+    session.touch();
+    if session.power() != Power::Awake {
+        tokio::spawn(async move {
+            if let Err(e) = session.wake().await {
+                eprintln!("Failed to wake session {}: {}", session.id, e);
+            }
+        });
+    }
     Html(include_str!("index.html"))
 }
 
+/// The session page uses relative URLs, so it must be served with a trailing slash.
+async fn session_redirect_handler(SessionRef(session): SessionRef) -> Redirect {
+    Redirect::permanent(&format!("/s/{}/", session.id))
+}
+
 async fn prompt_ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppState>>,
+    SessionRef(session): SessionRef,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_prompt_socket(socket, state))
+    if let Err(e) = session.wake().await {
+        eprintln!("Failed to wake session {}: {}", session.id, e);
+    }
+    ws.on_upgrade(|socket| handle_prompt_socket(socket, state, session))
+}
+
+/// Fill in the built-in snippet variables used in `text`. The branch and
+/// clipboard are only looked up when asked for.
+async fn expand_builtins(session: &Session, text: &str) -> String {
+    let used = snippets::variables(text);
+    let uses = |name: &str| used.iter().any(|v| v == name);
+    if !snippets::BUILTINS.iter().any(|name| uses(name)) {
+        return text.to_string();
+    }
+    let branch = if uses("branch") {
+        session
+            .with_repo(|repo| Ok(git::branch(repo)))
+            .await
+            .ok()
+            .flatten()
+    } else {
+        None
+    };
+    let clipboard = if uses("clipboard") {
+        Some(get_clipboard_contents(session.display).await)
+    } else {
+        None
+    };
+    snippets::expand(text, |name| match name {
+        "branch" => branch.clone(),
+        "workspace" => Some(session.repo_path()),
+        "session" => Some(session.id.clone()),
+        "agent" => session.agent.clone().or_else(|| env::var("AGENT").ok()),
+        "clipboard" => clipboard.clone(),
+        _ => None,
+    })
+}
+
+/// The text to type for a prompt. Built-ins are only filled in for prompts
+/// from the snippet picker; anything else is sent as written.
+async fn prompt_text(session: &Session, text: &str, snippet: bool) -> String {
+    if snippet {
+        expand_builtins(session, text).await
+    } else {
+        text.to_string()
+    }
+}
+
+/// Type `text` into the session's terminal after taking a checkpoint, and
+/// record it in the history.
+async fn deliver_prompt(
+    state: &Arc<AppState>,
+    session: &Session,
+    text: &str,
+    snippet: bool,
+) -> history::Entry {
+    let text = prompt_text(session, text, snippet).await;
+    session.checkpoint(&text).await;
+    session.touch();
+    session.mark_busy();
+    // Use xdotool to type into the X display
+    let result =
+        send_text_to_display(session.display, session.terminal.window_class(), &text).await;
+    let entry = history::Entry {
+        time: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        session: session.id.clone(),
+        workspace: session.repo_path(),
+        agent: session.agent.clone().or_else(|| env::var("AGENT").ok()),
+        text,
+        delivered: result.is_ok(),
+        error: result.err(),
+    };
+    let state = state.clone();
+    let record = entry.clone();
+    let _ = tokio::task::spawn_blocking(move || {
+        if let Err(e) = state.history.record(&record) {
+            eprintln!("Failed to record prompt: {}", e);
+        }
+    })
+    .await;
+    entry
+}
+
+/// Check what each session's agent is doing and, when it is idle, send its
+/// next queued prompt.
+async fn watch_agents(state: &Arc<AppState>) {
+    for session in state.sessions.list() {
+        let checked = session.clone();
+        let agent_state = tokio::task::spawn_blocking(move || checked.check_activity())
+            .await
+            .unwrap_or(AgentState::Offline);
+        if session.queue.is_empty() {
+            continue;
+        }
+        // Typed now, prompts would sit in the terminal until the user resumes
+        // the agent, which then reads them all at once
+        if session.paused.load(std::sync::atomic::Ordering::SeqCst) || session.is_held() {
+            continue;
+        }
+        // The idle timeout does not wait for the queue
+        if session.power() != Power::Awake {
+            if let Err(e) = session.wake().await {
+                eprintln!("Failed to wake session {}: {}", session.id, e);
+            }
+            continue;
+        }
+        if agent_state != AgentState::Idle {
+            continue;
+        }
+        let target = &*session;
+        let sent = session
+            .queue
+            .send_next(|item| async move {
+                println!("Sending queued prompt {} to session {}", item.id, target.id);
+                let entry = deliver_prompt(state, target, &item.text, item.snippet).await;
+                entry.error.map_or(Ok(()), Err)
+            })
+            .await;
+        if let Some((item, Err(e))) = sent {
+            eprintln!(
+                "Failed to send queued prompt {} to session {}, keeping it queued: {}",
+                item.id, session.id, e
+            );
+        }
+    }
+}
+
+/// A prompt sent on the prompt WebSocket. The page sends JSON; any other
+/// message is taken as the prompt's text.
+#[derive(Debug, PartialEq, Deserialize)]
+struct Prompt {
+    text: String,
+    /// Inserted from the snippet picker, so built-ins are filled in.
+    #[serde(default)]
+    snippet: bool,
+}
+
+impl Prompt {
+    fn parse(message: &str) -> Prompt {
+        serde_json::from_str(message).unwrap_or_else(|_| Prompt {
+            text: message.to_string(),
+            snippet: false,
+        })
+    }
 }
 
-async fn handle_prompt_socket(mut socket: WebSocket, state: Arc<AppState>) {
-    let display = state.display;
-    println!("Prompt WebSocket connected");
-    while let Some(msg) = socket.next().await {
+async fn handle_prompt_socket(mut socket: WebSocket, state: Arc<AppState>, session: Arc<Session>) {
+    let display = session.display;
+    println!("Prompt WebSocket connected (session {})", session.id);
+    let mut agent_state = session.watch_agent_state();
+    agent_state.mark_changed();
+    loop {
+        let msg = tokio::select! {
+            msg = socket.next() => msg,
+            changed = agent_state.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let state = *agent_state.borrow_and_update();
+                let response = serde_json::json!({
+                    "type": "agent",
+                    "state": state,
+                    "watch": session.agent_watch(),
+                });
+                if socket
+                    .send(Message::Text(response.to_string().into()))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+        };
+        let Some(msg) = msg else {
+            break;
+        };
         println!("WebSocket received: {:?}", msg);
         if let Ok(Message::Text(text)) = msg {
+            // The page may have sat open while the session went to sleep
+            if let Err(e) = session.wake().await {
+                eprintln!("Failed to wake session {}: {}", session.id, e);
+            }
             // Check if this is a special command
             if text == "GET_CLIPBOARD" {
                 // Get clipboard contents from the X display
@@ -363,272 +672,1944 @@ async fn handle_prompt_socket(mut socket: WebSocket, state: Arc<AppState>) {
                     .send(Message::Text(response.to_string().into()))
                     .await;
             } else {
-                // Use xdotool to type into the X display
-                send_text_to_display(display, &text).await;
+                let prompt = Prompt::parse(&text);
+                let entry = deliver_prompt(&state, &session, &prompt.text, prompt.snippet).await;
+                let response = serde_json::json!({
+                    "type": "sent",
+                    "delivered": entry.delivered,
+                    "error": entry.error,
+                });
+                let _ = socket
+                    .send(Message::Text(response.to_string().into()))
+                    .await;
             }
         }
     }
     println!("Prompt WebSocket disconnected");
 }
 
-async fn font_size_handler(
-    State(_state): State<Arc<AppState>>,
-    Json(request): Json<FontSizeRequest>,
-) -> Result<Json<FontSizeResponse>, (StatusCode, Json<FontSizeResponse>)> {
-    println!("Font size change request: {}", request.size);
+/// noVNC's websocket, bridged to the session's VNC server. This replaces a
+/// websockify process per display.
+async fn vnc_ws_handler(
+    ws: WebSocketUpgrade,
+    SessionRef(session): SessionRef,
+) -> impl IntoResponse {
+    if let Err(e) = session.wake().await {
+        eprintln!("Failed to wake session {}: {}", session.id, e);
+    }
+    ws.protocols(["binary"])
+        .on_upgrade(move |socket| proxy_vnc(socket, session))
+}
 
-    // Validate the font size
-    let size = match validate_font_size(request.size) {
-        Ok(s) => s,
+async fn proxy_vnc(socket: WebSocket, session: Arc<Session>) {
+    let port = session.vnc_port();
+    let stream = match TcpStream::connect(("127.0.0.1", port)).await {
+        Ok(stream) => stream,
         Err(e) => {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                Json(FontSizeResponse {
-                    success: false,
-                    message: e,
-                }),
-            ));
+            eprintln!("Failed to connect to VNC on port {}: {}", port, e);
+            return;
         }
     };
+    let (mut vnc_read, mut vnc_write) = stream.into_split();
+    let (mut ws_write, mut ws_read) = socket.split();
+
+    // Only keys, pointer and clipboard count as activity; noVNC asks for
+    // screen updates whether or not anyone is looking
+    let to_vnc = async {
+        let mut client = rfb::ClientStream::default();
+        while let Some(Ok(msg)) = ws_read.next().await {
+            match msg {
+                Message::Binary(data) => {
+                    if client.feed(&data) {
+                        session.touch();
+                    }
+                    if vnc_write.write_all(&data).await.is_err() {
+                        break;
+                    }
+                }
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+    };
+    let from_vnc = async {
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            match vnc_read.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    let data = buffer[..n].to_vec();
+                    if ws_write.send(Message::Binary(data.into())).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        let _ = ws_write.close().await;
+    };
 
-    // Update the config file
-    // Alacritty will automatically reload the config when the file changes (live_config_reload)
-    if let Err(e) = update_alacritty_config(size) {
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(FontSizeResponse {
-                success: false,
-                message: e,
-            }),
-        ));
+    // Either side closing ends the bridge
+    tokio::select! {
+        _ = to_vnc => {}
+        _ = from_vnc => {}
     }
+}
 
-    Ok(Json(FontSizeResponse {
-        success: true,
-        message: format!("Font size updated to {}", size),
-    }))
+async fn list_sessions_handler(State(state): State<Arc<AppState>>) -> Json<Vec<SessionInfo>> {
+    Json(
+        state
+            .sessions
+            .list()
+            .iter()
+            .map(|s| SessionInfo::new(s))
+            .collect(),
+    )
 }
 
-fn validate_args(args: &[String]) -> Result<(String, String, u16), String> {
-    if args.len() < 2 {
-        return Err("Usage: vnccc <repo-path> [geometry] [web-port]".to_string());
-    }
+async fn get_session_handler(SessionRef(session): SessionRef) -> Json<SessionInfo> {
+    Json(SessionInfo::new(&session))
+}
 
-    let repo_path = args[1].clone();
-    let geometry = args
-        .get(2)
-        .map(|s| s.as_str())
-        .unwrap_or("1024x1024")
-        .to_string();
-    let web_port: u16 = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(8080);
+#[derive(Serialize)]
+struct ProcessesResponse {
+    /// Null while the terminal is not running.
+    totals: Option<UsageTotals>,
+    processes: Vec<ProcessUsage>,
+}
 
-    if !Path::new(&repo_path).exists() {
-        return Err(format!("Error: repo path '{}' does not exist", repo_path));
-    }
+/// Resource use of the session's terminal and everything it runs, the agent
+/// included.
+async fn processes_handler(SessionRef(session): SessionRef) -> Json<ProcessesResponse> {
+    let processes = session.process_usage();
+    Json(ProcessesResponse {
+        totals: processes.as_deref().map(UsageTotals::sum),
+        processes: processes.unwrap_or_default(),
+    })
+}
 
-    Ok((repo_path, geometry, web_port))
+fn repo_error(message: String) -> (StatusCode, Json<ApiResponse>) {
+    (
+        StatusCode::CONFLICT,
+        Json(ApiResponse {
+            success: false,
+            message,
+        }),
+    )
 }
 
-fn validate_font_size(size: f32) -> Result<f32, String> {
-    if !size.is_finite() {
-        return Err("Font size must be a valid number".to_string());
-    }
-    if !(8.0..=72.0).contains(&size) {
-        return Err(format!(
-            "Font size must be between 8.0 and 72.0, got {}",
-            size
-        ));
-    }
-    Ok(size)
+/// Workspace snapshots taken before each prompt, newest first.
+async fn checkpoints_handler(
+    SessionRef(session): SessionRef,
+) -> Result<Json<Vec<Checkpoint>>, (StatusCode, Json<ApiResponse>)> {
+    session.checkpoints().await.map(Json).map_err(repo_error)
 }
 
-fn update_alacritty_config(size: f32) -> Result<(), String> {
-    let home = env::var("HOME").map_err(|_| "HOME environment variable not set".to_string())?;
-    let config_path = format!("{}/.config/alacritty/alacritty.toml", home);
+/// What changed in the workspace since a checkpoint.
+async fn checkpoint_diff_handler(
+    SessionRef(session): SessionRef,
+    UrlPath((_, checkpoint)): UrlPath<(String, String)>,
+) -> Result<Json<git::Diff>, (StatusCode, Json<ApiResponse>)> {
+    session
+        .checkpoint_diff(checkpoint)
+        .await
+        .map(Json)
+        .map_err(repo_error)
+}
 
-    // Read the current config
-    let mut file = fs::File::open(&config_path)
-        .map_err(|e| format!("Failed to open alacritty config: {}", e))?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)
-        .map_err(|e| format!("Failed to read alacritty config: {}", e))?;
+async fn restore_checkpoint_handler(
+    _auth: Authorized,
+    SessionRef(session): SessionRef,
+    UrlPath((_, checkpoint)): UrlPath<(String, String)>,
+) -> Result<Json<ApiResponse>, (StatusCode, Json<ApiResponse>)> {
+    lifecycle_response(session.restore_checkpoint(checkpoint).await)
+}
 
-    // Parse as TOML
-    let mut config: toml::Value = toml::from_str(&contents)
-        .map_err(|e| format!("Failed to parse alacritty config: {}", e))?;
+#[derive(Serialize)]
+struct GitStatusResponse {
+    #[serde(flatten)]
+    status: git::Status,
+    /// Whether stage, unstage, discard and commit are enabled.
+    actions: bool,
+}
 
-    // Update the font size
-    if let Some(font) = config.get_mut("font")
-        && let Some(font_table) = font.as_table_mut()
-    {
-        font_table.insert("size".to_string(), toml::Value::Float(size as f64));
-    }
+async fn git_status_handler(
+    State(state): State<Arc<AppState>>,
+    SessionRef(session): SessionRef,
+) -> Result<Json<GitStatusResponse>, (StatusCode, Json<ApiResponse>)> {
+    let status = session.with_repo(git::status).await.map_err(repo_error)?;
+    Ok(Json(GitStatusResponse {
+        status,
+        actions: state.git_actions,
+    }))
+}
 
-    // Write back to file
-    let new_contents = toml::to_string_pretty(&config)
-        .map_err(|e| format!("Failed to serialize config: {}", e))?;
-    let mut file = fs::File::create(&config_path)
-        .map_err(|e| format!("Failed to open config for writing: {}", e))?;
-    file.write_all(new_contents.as_bytes())
-        .map_err(|e| format!("Failed to write config: {}", e))?;
+#[derive(Deserialize)]
+struct GitDiffQuery {
+    /// Diff the index against HEAD instead of the work tree against the index.
+    #[serde(default)]
+    staged: bool,
+    /// Only this file.
+    path: Option<String>,
+}
 
-    Ok(())
+async fn git_diff_handler(
+    SessionRef(session): SessionRef,
+    Query(query): Query<GitDiffQuery>,
+) -> Result<Json<git::Diff>, (StatusCode, Json<ApiResponse>)> {
+    session
+        .with_repo(move |repo| git::diff(repo, query.staged, query.path.as_deref()))
+        .await
+        .map(Json)
+        .map_err(repo_error)
 }
 
-#[tokio::main]
-async fn main() {
-    let args: Vec<String> = env::args().collect();
+#[derive(Deserialize)]
+struct GitLogQuery {
+    limit: Option<usize>,
+}
 
-    let (repo_path, geometry, web_port) = match validate_args(&args) {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("{}", e);
-            eprintln!("Example: vnccc /home/user/myproject 1024x1024 8080");
-            std::process::exit(1);
-        }
-    };
+async fn git_log_handler(
+    SessionRef(session): SessionRef,
+    Query(query): Query<GitLogQuery>,
+) -> Result<Json<Vec<git::Commit>>, (StatusCode, Json<ApiResponse>)> {
+    let limit = query.limit.unwrap_or(20);
+    session
+        .with_repo(move |repo| git::log(repo, limit))
+        .await
+        .map(Json)
+        .map_err(repo_error)
+}
 
-    let display = find_available_display();
-    let vnc_port = 5900 + display;
-    let ws_port = 6080; // websockify port for noVNC
+#[derive(Deserialize)]
+struct GitActionRequest {
+    #[serde(default)]
+    paths: Vec<String>,
+    #[serde(default)]
+    message: String,
+}
 
-    println!("Starting VNC on display :{} (port {})", display, vnc_port);
-    let mut vnc_proc = start_vnc_server(display, &geometry);
+/// Stage, unstage, discard or commit, when `git.actions` is enabled.
+async fn git_action_handler(
+    _auth: Authorized,
+    State(state): State<Arc<AppState>>,
+    SessionRef(session): SessionRef,
+    UrlPath((_, action)): UrlPath<(String, String)>,
+    Json(request): Json<GitActionRequest>,
+) -> Result<Json<ApiResponse>, (StatusCode, Json<ApiResponse>)> {
+    if !state.git_actions {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(ApiResponse {
+                success: false,
+                message: "Git actions are disabled; set git.actions = true in config.toml"
+                    .to_string(),
+            }),
+        ));
+    }
+    if action == "discard" {
+        session
+            .checkpoint(&format!("Before discarding {}", request.paths.join(", ")))
+            .await;
+    }
+    let result = session
+        .with_repo(move |repo| match action.as_str() {
+            "stage" => git::stage(repo, &request.paths),
+            "unstage" => git::unstage(repo, &request.paths),
+            "discard" => git::discard(repo, &request.paths),
+            "commit" => git::commit(repo, &request.message),
+            _ => Err(format!("Unknown git action '{}'", action)),
+        })
+        .await;
+    lifecycle_response(result)
+}
 
-    thread::sleep(Duration::from_millis(500));
+#[derive(Deserialize)]
+struct FilesQuery {
+    /// Relative to the repo path, the root when empty.
+    #[serde(default)]
+    path: String,
+}
 
-    match vnc_proc.try_wait() {
-        Ok(Some(status)) => {
-            eprintln!("VNC server exited unexpectedly: {}", status);
-            std::process::exit(1);
-        }
-        Ok(None) => {}
-        Err(e) => {
-            eprintln!("Error checking VNC: {}", e);
-            std::process::exit(1);
-        }
-    }
+/// Run a read of the session's workspace off the async runtime.
+async fn read_workspace<T: Send + 'static>(
+    session: &Session,
+    f: impl FnOnce(&Path) -> Result<T, String> + Send + 'static,
+) -> Result<T, (StatusCode, Json<ApiResponse>)> {
+    let root = std::path::PathBuf::from(session.repo_path());
+    tokio::task::spawn_blocking(move || f(&root))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result)
+        .map_err(|message| {
+            (
+                StatusCode::NOT_FOUND,
+                Json(ApiResponse {
+                    success: false,
+                    message,
+                }),
+            )
+        })
+}
 
-    println!("Starting websockify on port {}", ws_port);
-    let websockify_proc = start_websockify(vnc_port, ws_port);
+/// One directory of the workspace.
+async fn files_handler(
+    SessionRef(session): SessionRef,
+    Query(query): Query<FilesQuery>,
+) -> Result<Json<files::Listing>, (StatusCode, Json<ApiResponse>)> {
+    read_workspace(&session, move |root| files::list(root, &query.path))
+        .await
+        .map(Json)
+}
 
-    thread::sleep(Duration::from_millis(300));
+/// Text of a workspace file, or what kind of file it is.
+async fn file_content_handler(
+    SessionRef(session): SessionRef,
+    Query(query): Query<FilesQuery>,
+) -> Result<Json<files::Content>, (StatusCode, Json<ApiResponse>)> {
+    read_workspace(&session, move |root| files::read(root, &query.path))
+        .await
+        .map(Json)
+}
 
-    println!("Starting ratpoison window manager");
-    let wm_proc = start_window_manager(display);
-    // Window manager runs for app lifetime, intentionally not waiting
-    mem::forget(wm_proc);
-    thread::sleep(Duration::from_millis(200));
+/// Bytes of a workspace file, for image previews. The sandbox CSP keeps an
+/// SVG opened directly from running scripts on this origin.
+async fn file_raw_handler(
+    SessionRef(session): SessionRef,
+    Query(query): Query<FilesQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ApiResponse>)> {
+    let (mime, data) = read_workspace(&session, move |root| files::raw(root, &query.path)).await?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, mime),
+            (header::CONTENT_SECURITY_POLICY, "sandbox"),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        ],
+        data,
+    ))
+}
 
-    let terminal = find_terminal();
-    println!("Starting {} with agent in {}", terminal, repo_path);
+#[derive(Deserialize)]
+struct DownloadQuery {
+    /// File or directory relative to the repo path, the root when empty.
+    #[serde(default)]
+    path: String,
+    /// Archive format for directories.
+    #[serde(default)]
+    format: files::Format,
+    /// Only files changed since this git ref, always as an archive.
+    since: Option<String>,
+}
 
-    // Spawn terminal monitor task that auto-restarts on exit
-    let repo_path_clone = repo_path.clone();
-    let geometry_clone = geometry.to_string();
-    let debug_mode = env::var("DEBUG").unwrap_or_default() == "true";
+enum Download {
+    File(std::path::PathBuf),
+    /// An archive and the download's file name without extension.
+    Archive(Box<files::Archive>, String),
+}
 
-    tokio::spawn(async move {
-        loop {
-            let mut term_proc =
-                start_terminal_with_agent(display, &repo_path_clone, terminal, &geometry_clone);
-            let pid = term_proc.id();
-            println!("Terminal started (pid: {:?})", pid);
-
-            let monitor_handle = if debug_mode {
-                // In debug mode, monitor the log file
-                let log_file = format!("/tmp/agent-debug-{}.log", std::process::id());
-                let log_file_clone = log_file.clone();
-
-                Some(tokio::spawn(async move {
-                    // Wait a moment for the log file to be created
-                    tokio::time::sleep(Duration::from_millis(200)).await;
-
-                    if let Ok(mut file) = tokio::fs::File::open(&log_file_clone).await {
-                        use tokio::io::AsyncReadExt;
-                        let mut buffer = vec![0u8; 4096];
-                        loop {
-                            match file.read(&mut buffer).await {
-                                Ok(0) => {
-                                    // EOF - wait a bit and try again (tail -f behavior)
-                                    tokio::time::sleep(Duration::from_millis(100)).await;
-                                }
-                                Ok(n) => {
-                                    let output = String::from_utf8_lossy(&buffer[..n]);
-                                    for line in output.lines() {
-                                        println!("[Agent log] {}", line);
-                                    }
-                                }
-                                Err(e) => {
-                                    eprintln!("[Agent log error] Failed to read: {}", e);
-                                    break;
-                                }
-                            }
+/// What to send for `rel`: the file itself, or an archive of the directory,
+/// or of the files changed since `since`. Nothing outside `root` is included.
+fn plan_download(
+    root: &Path,
+    rel: &str,
+    since: Option<String>,
+    format: files::Format,
+) -> Result<Download, String> {
+    let base = |path: &Path| {
+        path.file_name()
+            .map(|name| files::sanitize_name(&name.to_string_lossy()))
+            .unwrap_or_else(|| "workspace".to_string())
+    };
+    let path = files::resolve(root, if rel.is_empty() { "." } else { rel })?;
+    if let Some(since) = since {
+        let names: Vec<String> = git::changed_since(root, &since, rel)?
+            .into_iter()
+            .filter(|name| files::confined(root, name))
+            .collect();
+        if names.is_empty() {
+            return Err(format!("No files changed since {}", since));
+        }
+        let archive = files::archive(&files::resolve(root, ".")?, format, &names)?;
+        let name = format!("{}-since-{}", base(&path), files::sanitize_name(&since));
+        return Ok(Download::Archive(Box::new(archive), name));
+    }
+    if path.is_file() {
+        return Ok(Download::File(path));
+    }
+    let parent = path.parent().ok_or("Cannot archive /")?;
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let archive = files::archive(parent, format, &[name])?;
+    Ok(Download::Archive(Box::new(archive), base(&path)))
+}
+
+/// Stream `reader` as a response body. `child` is the process writing it,
+/// waited for at the end so a failing archiver gets logged.
+fn stream_body(
+    reader: impl AsyncRead + Unpin + Send + 'static,
+    child: Option<tokio::process::Child>,
+) -> axum::body::Body {
+    let stream = futures::stream::unfold(Some((reader, child)), |state| async move {
+        let (mut reader, child) = state?;
+        let mut buf = vec![0; 64 * 1024];
+        match reader.read(&mut buf).await {
+            Ok(0) => {
+                if let Some(mut child) = child {
+                    match child.wait().await {
+                        Ok(status) if !status.success() => {
+                            eprintln!("Archiver exited with {}", status)
                         }
+                        Err(e) => eprintln!("Failed to wait for archiver: {}", e),
+                        _ => {}
                     }
-                }))
-            } else {
+                }
                 None
-            };
+            }
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(axum::body::Bytes::from(buf)), Some((reader, child))))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    axum::body::Body::from_stream(stream)
+}
 
-            // Wait for terminal to exit
-            match term_proc.wait() {
-                Ok(status) => {
-                    println!("=== Terminal exited (pid={}) ===", pid);
-                    println!("Exit status: {}", status);
-                    println!("Exit code: {:?}", status.code());
-
-                    if debug_mode {
-                        // Give the log monitor a moment to catch up
-                        tokio::time::sleep(Duration::from_millis(200)).await;
-
-                        // Print final log contents
-                        let log_file = format!("/tmp/agent-debug-{}.log", std::process::id());
-                        if let Ok(contents) = fs::read_to_string(&log_file) {
-                            println!("=== Final log contents ===");
-                            for line in contents.lines() {
-                                println!("[Agent log] {}", line);
-                            }
-                            println!("==========================");
-                        } else {
-                            println!("WARNING: Could not read log file: {}", log_file);
+/// A workspace file as is, or a directory or the files changed since a git
+/// ref as a tar.gz or zip archive streamed while it is built.
+async fn download_handler(
+    SessionRef(session): SessionRef,
+    Query(query): Query<DownloadQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ApiResponse>)> {
+    let format = query.format;
+    let rel = query.path.trim_matches('/').to_string();
+    let download = read_workspace(&session, move |root| {
+        plan_download(root, &rel, query.since, format)
+    })
+    .await?;
+
+    let failed = |message: String| {
+        eprintln!("Download failed: {}", message);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResponse {
+                success: false,
+                message,
+            }),
+        )
+    };
+    let (body, name, mime) = match download {
+        Download::File(path) => {
+            let file = tokio::fs::File::open(&path)
+                .await
+                .map_err(|e| failed(format!("{}: {}", path.display(), e)))?;
+            let name = path
+                .file_name()
+                .map(|n| files::sanitize_name(&n.to_string_lossy()))
+                .unwrap_or_default();
+            (stream_body(file, None), name, "application/octet-stream")
+        }
+        Download::Archive(archive, name) => {
+            let stdout = if archive.output.is_some() {
+                std::process::Stdio::null()
+            } else {
+                std::process::Stdio::piped()
+            };
+            let mut child = tokio::process::Command::from(archive.command)
+                .stdin(std::process::Stdio::piped())
+                .stdout(stdout)
+                .kill_on_drop(true)
+                .spawn()
+                .map_err(|e| failed(format!("Failed to start archiver: {}", e)))?;
+            if let Some(mut stdin) = child.stdin.take() {
+                let input = archive.input;
+                tokio::spawn(async move {
+                    let _ = stdin.write_all(&input).await;
+                });
+            }
+            let body = match archive.output {
+                None => {
+                    let stdout = child
+                        .stdout
+                        .take()
+                        .ok_or_else(|| failed("No archiver output".to_string()))?;
+                    stream_body(stdout, Some(child))
+                }
+                // Written to a file first; the open handle outlives the unlink
+                Some(output) => {
+                    let status = child.wait().await;
+                    let file = tokio::fs::File::open(&output).await;
+                    let _ = tokio::fs::remove_file(&output).await;
+                    match status {
+                        Ok(status) if status.success() => {}
+                        Ok(status) => {
+                            return Err(failed(format!("Archiver exited with {}", status)));
                         }
+                        Err(e) => return Err(failed(format!("Archiver failed: {}", e))),
                     }
-
-                    println!("Restarting in 500ms...");
-                    println!("==============================");
-                }
-                Err(e) => {
-                    eprintln!(
-                        "Error waiting for terminal (pid={}): {} - restarting...",
-                        pid, e
-                    );
+                    let file = file.map_err(|e| failed(format!("{}: {}", output.display(), e)))?;
+                    stream_body(file, None)
                 }
+            };
+            (
+                body,
+                format!("{}.{}", name, format.extension()),
+                format.mime(),
+            )
+        }
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, mime.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", name),
+            ),
+        ],
+        body,
+    ))
+}
+
+#[derive(Serialize)]
+struct UploadResponse {
+    success: bool,
+    message: String,
+    /// Absolute paths of the saved files, in upload order.
+    paths: Vec<String>,
+}
+
+/// Save the files of a multipart upload into the session's upload directory,
+/// so their paths can be given to the agent.
+async fn upload_handler(
+    _auth: Authorized,
+    State(state): State<Arc<AppState>>,
+    SessionRef(session): SessionRef,
+    mut multipart: Multipart,
+) -> Result<Json<UploadResponse>, (StatusCode, Json<ApiResponse>)> {
+    let max_mb = state.uploads.max_mb;
+    let rejected = |e: axum::extract::multipart::MultipartError| {
+        let message = if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            format!("Upload is larger than {} MiB", max_mb)
+        } else {
+            e.body_text()
+        };
+        (
+            e.status(),
+            Json(ApiResponse {
+                success: false,
+                message,
+            }),
+        )
+    };
+    let root = std::path::PathBuf::from(session.repo_path());
+    let dir_name = state.uploads.dir.clone();
+    let dir = tokio::task::spawn_blocking(move || files::upload_dir(&root, &dir_name))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result)
+        .map_err(repo_error)?;
+
+    let mut paths = Vec::new();
+    while let Some(field) = multipart.next_field().await.map_err(rejected)? {
+        let Some(name) = field.file_name().map(str::to_string) else {
+            continue;
+        };
+        let data = field.bytes().await.map_err(rejected)?;
+        let dir = dir.clone();
+        let path = tokio::task::spawn_blocking(move || files::save_upload(&dir, &name, &data))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result)
+            .map_err(repo_error)?;
+        println!("Session {}: uploaded {}", session.id, path.display());
+        paths.push(path.to_string_lossy().into_owned());
+    }
+    if paths.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ApiResponse {
+                success: false,
+                message: "No files in upload".to_string(),
+            }),
+        ));
+    }
+    Ok(Json(UploadResponse {
+        success: true,
+        message: format!("Uploaded {} file(s)", paths.len()),
+        paths,
+    }))
+}
+
+#[derive(Deserialize)]
+struct SandboxQuery {
+    /// Probe only this path instead of the defaults.
+    path: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SandboxResponse {
+    /// Landlock ABI version of the kernel, null without Landlock.
+    abi: Option<i64>,
+    /// Null while the sandbox is disabled.
+    rules: Option<Rules>,
+    probes: Vec<Probe>,
+}
+
+/// The session's sandbox rules and what the agent is denied under them.
+/// Landlock does not log denials, so each path is tried by a probe process
+/// restricted by the same rules.
+async fn sandbox_handler(
+    SessionRef(session): SessionRef,
+    Query(query): Query<SandboxQuery>,
+) -> Result<Json<SandboxResponse>, (StatusCode, Json<ApiResponse>)> {
+    let abi = sandbox::abi_version();
+    if session.sandbox().is_none() {
+        return Ok(Json(SandboxResponse {
+            abi,
+            rules: None,
+            probes: Vec::new(),
+        }));
+    }
+    let paths: Vec<std::path::PathBuf> = query.path.into_iter().map(Into::into).collect();
+    let probe_session = session.clone();
+    let probes = tokio::task::spawn_blocking(move || probe_session.probe_sandbox(&paths))
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()))
+        .map_err(|message| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResponse {
+                    success: false,
+                    message,
+                }),
+            )
+        })?;
+    Ok(Json(SandboxResponse {
+        abi,
+        rules: session.sandbox(),
+        probes,
+    }))
+}
+
+/// Prometheus text exposition of per-session state and resource use.
+fn render_metrics(sessions: &[SessionInfo]) -> String {
+    type Gauge = fn(&SessionInfo) -> Option<f64>;
+    let gauges: [(&str, &str, Gauge); 7] = [
+        (
+            "vncaa_session_running",
+            "Whether the session's terminal is running",
+            |s| Some(f64::from(u8::from(s.running))),
+        ),
+        (
+            "vncaa_session_awake",
+            "0 while the idle timeout has the session asleep",
+            |s| Some(f64::from(u8::from(s.power == Power::Awake.name()))),
+        ),
+        ("vncaa_agent_paused", "Whether the agent is paused", |s| {
+            Some(f64::from(u8::from(s.paused)))
+        }),
+        (
+            "vncaa_session_processes",
+            "Processes under the session's terminal",
+            |s| s.usage.as_ref().map(|u| u.processes as f64),
+        ),
+        (
+            "vncaa_session_cpu_percent",
+            "CPU use of the terminal's process tree, 100 per core",
+            |s| s.usage.as_ref().map(|u| u.cpu_percent),
+        ),
+        (
+            "vncaa_session_rss_bytes",
+            "Resident memory of the terminal's process tree",
+            |s| s.usage.as_ref().map(|u| u.rss_bytes as f64),
+        ),
+        (
+            "vncaa_session_open_files",
+            "Open file descriptors of the terminal's process tree",
+            |s| s.usage.as_ref().map(|u| u.open_files as f64),
+        ),
+    ];
+
+    let mut out = String::new();
+    for (name, help, value) in gauges {
+        out.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} gauge\n",
+            name, help, name
+        ));
+        for session in sessions {
+            if let Some(value) = value(session) {
+                out.push_str(&format!(
+                    "{}{{session=\"{}\"}} {}\n",
+                    name, session.id, value
+                ));
             }
+        }
+    }
+    out
+}
+
+async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let sessions: Vec<SessionInfo> = state
+        .sessions
+        .list()
+        .iter()
+        .map(|s| SessionInfo::new(s))
+        .collect();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render_metrics(&sessions),
+    )
+}
+
+async fn create_session_handler(
+    State(state): State<Arc<AppState>>,
+    _auth: Authorized,
+    Json(request): Json<CreateSessionRequest>,
+) -> Result<(StatusCode, Json<SessionResponse>), (StatusCode, Json<SessionResponse>)> {
+    let error = |status, message| {
+        (
+            status,
+            Json(SessionResponse {
+                success: false,
+                message,
+                session: None,
+            }),
+        )
+    };
+
+    let geometry = match &request.geometry {
+        Some(geometry) => resolve_geometry(geometry, &state.geometry_presets)
+            .map_err(|e| error(StatusCode::BAD_REQUEST, e))?,
+        None => state.geometry,
+    };
+    let terminal: Arc<dyn TerminalBackend> = match &request.terminal {
+        Some(name) => terminal::backend_by_name(name)
+            .ok_or_else(|| {
+                error(
+                    StatusCode::BAD_REQUEST,
+                    format!("Unknown terminal '{}'", name),
+                )
+            })?
+            .into(),
+        None => state.terminal.clone(),
+    };
+    println!("Create session request for {}", request.repo_path);
+
+    let (repo_path, worktree) = if request.worktree {
+        let branch = request
+            .branch
+            .clone()
+            .or_else(|| request.id.as_ref().map(|id| format!("vncaa/{}", id)));
+        let (path, worktree) = add_worktree(&state, &request.repo_path, branch, request.git_ref)
+            .await
+            .map_err(|e| error(StatusCode::BAD_REQUEST, e))?;
+        (path, Some(worktree))
+    } else {
+        (request.repo_path, None)
+    };
+    let created = state
+        .sessions
+        .create(SessionOptions {
+            id: request.id,
+            repo_path,
+            geometry,
+            terminal,
+            agent: request.agent.filter(|a| !a.is_empty()),
+            limits: state.limits.clone(),
+            sandbox: state.sandbox.clone(),
+            checkpoints: state.checkpoints.clone(),
+            activity: state.activity.clone(),
+            notifier: state.notifier.clone(),
+            worktree: worktree.clone(),
+        })
+        .await;
+    let session = match created {
+        Ok(session) => session,
+        Err(e) => {
+            // Nothing has happened in the worktree yet
+            if let Some(worktree) = worktree {
+                let _ = tokio::task::spawn_blocking(move || worktree.delete()).await;
+            }
+            return Err(error(StatusCode::BAD_REQUEST, e));
+        }
+    };
+
+    Ok((
+        StatusCode::CREATED,
+        Json(SessionResponse {
+            success: true,
+            message: format!(
+                "Session {} started on display :{}",
+                session.id, session.display
+            ),
+            session: Some(SessionInfo::new(&session)),
+        }),
+    ))
+}
+
+/// Add a worktree of the repo at `repo_path` on a new branch. Returns the
+/// directory to work in, which for a subdirectory of the repo is the same
+/// subdirectory of the worktree.
+async fn add_worktree(
+    state: &AppState,
+    repo_path: &str,
+    branch: Option<String>,
+    git_ref: Option<String>,
+) -> Result<(String, workspace::Worktree), String> {
+    let defaults = state.workspace.spec().clone();
+    let repo_path = repo_path.to_string();
+    tokio::task::spawn_blocking(move || {
+        let top = git::toplevel(Path::new(&repo_path))
+            .ok_or_else(|| format!("{} is not in a git repository", repo_path))?;
+        let request = workspace::Request {
+            mode: workspace::Mode::Worktree,
+            path: Some(top.to_string_lossy().into_owned()),
+            git_ref,
+            branch,
+            ..Default::default()
+        };
+        let spec = defaults.for_request(&request, None)?;
+        let workspace = workspace::provision(&spec, &|line| println!("{}", line))?;
+        let worktree = workspace
+            .worktree()
+            .cloned()
+            .ok_or("No worktree was added")?;
+        let subdir = std::fs::canonicalize(&repo_path)
+            .ok()
+            .and_then(|repo| repo.strip_prefix(&top).ok().map(Path::to_path_buf));
+        let path = match subdir {
+            Some(subdir) => worktree.path.join(subdir),
+            None => worktree.path.clone(),
+        };
+        Ok((path.to_string_lossy().into_owned(), worktree))
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.to_string()))
+}
+
+async fn delete_session_handler(
+    State(state): State<Arc<AppState>>,
+    _auth: Authorized,
+    SessionRef(session): SessionRef,
+    Query(query): Query<DeleteSessionQuery>,
+) -> Result<Json<ApiResponse>, (StatusCode, Json<ApiResponse>)> {
+    let conflict = |message| {
+        (
+            StatusCode::CONFLICT,
+            Json(ApiResponse {
+                success: false,
+                message,
+            }),
+        )
+    };
+    // Refuse before stopping anything rather than strand a dirty worktree
+    if query.worktree == workspace::Teardown::Merge
+        && let Some(worktree) = session.worktree.clone()
+        && tokio::task::spawn_blocking(move || worktree.is_dirty())
+            .await
+            .unwrap_or(true)
+    {
+        return Err(conflict(format!(
+            "Session {}'s worktree has uncommitted changes; commit or discard them before merging",
+            session.id
+        )));
+    }
+
+    // Killing the processes waits on them, keep it off the async workers
+    let id = session.id.clone();
+    let _ = tokio::task::spawn_blocking(move || state.sessions.destroy(&id)).await;
+    let mut message = format!("Session {} stopped", session.id);
+    if let Some(worktree) = session.worktree.clone() {
+        let how = query.worktree;
+        let result = tokio::task::spawn_blocking(move || worktree.teardown(how))
+            .await
+            .unwrap_or_else(|e| Err(e.to_string()));
+        match result {
+            Ok(done) => message = format!("{}. {}", message, done),
+            Err(e) => {
+                return Err(conflict(format!(
+                    "{}, but its worktree was kept: {}",
+                    message, e
+                )));
+            }
+        }
+    }
+    Ok(Json(ApiResponse {
+        success: true,
+        message,
+    }))
+}
+
+/// Outcome of a lifecycle action as an API response.
+fn lifecycle_response(
+    result: Result<String, String>,
+) -> Result<Json<ApiResponse>, (StatusCode, Json<ApiResponse>)> {
+    match result {
+        Ok(message) => Ok(Json(ApiResponse {
+            success: true,
+            message,
+        })),
+        Err(message) => Err((
+            StatusCode::CONFLICT,
+            Json(ApiResponse {
+                success: false,
+                message,
+            }),
+        )),
+    }
+}
+
+/// Lifecycle calls act on live processes, so bring a sleeping session back first.
+async fn wake_session(session: &Session) -> Result<(), (StatusCode, Json<ApiResponse>)> {
+    session.wake().await.map_err(|message| {
+        (
+            StatusCode::CONFLICT,
+            Json(ApiResponse {
+                success: false,
+                message,
+            }),
+        )
+    })
+}
+
+async fn restart_agent_handler(
+    _auth: Authorized,
+    SessionRef(session): SessionRef,
+) -> Result<Json<ApiResponse>, (StatusCode, Json<ApiResponse>)> {
+    wake_session(&session).await?;
+    lifecycle_response(
+        session
+            .restart_agent()
+            .map(|pgid| format!("Restarting agent (process group {})", pgid)),
+    )
+}
+
+async fn pause_agent_handler(
+    _auth: Authorized,
+    SessionRef(session): SessionRef,
+) -> Result<Json<ApiResponse>, (StatusCode, Json<ApiResponse>)> {
+    wake_session(&session).await?;
+    lifecycle_response(
+        session
+            .pause_agent()
+            .map(|pgid| format!("Paused agent (process group {})", pgid)),
+    )
+}
+
+async fn resume_agent_handler(
+    _auth: Authorized,
+    SessionRef(session): SessionRef,
+) -> Result<Json<ApiResponse>, (StatusCode, Json<ApiResponse>)> {
+    wake_session(&session).await?;
+    lifecycle_response(session.resume_agent())
+}
+
+async fn stop_agent_handler(
+    _auth: Authorized,
+    State(state): State<Arc<AppState>>,
+    SessionRef(session): SessionRef,
+) -> Result<Json<ApiResponse>, (StatusCode, Json<ApiResponse>)> {
+    wake_session(&session).await?;
+    lifecycle_response(session.emergency_stop(&state.emergency_stop).await)
+}
+
+async fn restart_terminal_handler(
+    _auth: Authorized,
+    SessionRef(session): SessionRef,
+) -> Result<Json<ApiResponse>, (StatusCode, Json<ApiResponse>)> {
+    wake_session(&session).await?;
+    let result = if session.is_running() {
+        session.restart_terminal();
+        Ok(format!("Restarting {}", session.terminal.name()))
+    } else {
+        Err("Terminal is not running".to_string())
+    };
+    lifecycle_response(result)
+}
+
+async fn restart_display_handler(
+    _auth: Authorized,
+    SessionRef(session): SessionRef,
+) -> Result<Json<ApiResponse>, (StatusCode, Json<ApiResponse>)> {
+    lifecycle_response(
+        session
+            .restart_display()
+            .await
+            .map(|()| format!("Restarted display :{}", session.display)),
+    )
+}
+
+/// HTTP status for a backend operation that did not succeed.
+fn backend_error_status(err: &BackendError) -> StatusCode {
+    match err {
+        BackendError::Unsupported(_) => StatusCode::NOT_IMPLEMENTED,
+        BackendError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn setting_error(status: StatusCode, message: String) -> (StatusCode, Json<SettingResponse>) {
+    (
+        status,
+        Json(SettingResponse {
+            success: false,
+            message,
+            live: false,
+        }),
+    )
+}
+
+/// Apply an already-stored setting to the running terminal. Backends that can
+/// only pick it up at launch get restarted when `restart` is set.
+fn apply_setting(
+    session: &Session,
+    label: &str,
+    value: &str,
+    restart: bool,
+    apply: impl FnOnce(&dyn TerminalBackend, &TerminalContext) -> Result<Applied, BackendError>,
+) -> Result<Json<SettingResponse>, (StatusCode, Json<SettingResponse>)> {
+    let applied = apply(session.terminal.as_ref(), &session.terminal_context())
+        .map_err(|e| setting_error(backend_error_status(&e), e.to_string()))?;
+
+    let name = session.terminal.name();
+    let message = match applied {
+        Applied::Live => format!("{} set to {}", label, value),
+        Applied::NextLaunch if restart => {
+            session.restart_terminal();
+            format!(
+                "Restarting {} with {} {}",
+                name,
+                label.to_lowercase(),
+                value
+            )
+        }
+        Applied::NextLaunch => format!(
+            "{} cannot change {} while running; {} will apply when the terminal restarts",
+            name,
+            label.to_lowercase(),
+            value
+        ),
+    };
+
+    Ok(Json(SettingResponse {
+        success: true,
+        message,
+        live: applied == Applied::Live,
+    }))
+}
+
+async fn font_size_handler(
+    SessionRef(session): SessionRef,
+    Json(request): Json<FontSizeRequest>,
+) -> Result<Json<SettingResponse>, (StatusCode, Json<SettingResponse>)> {
+    println!("Font size change request: {}", request.size);
+
+    let size =
+        validate_font_size(request.size).map_err(|e| setting_error(StatusCode::BAD_REQUEST, e))?;
+
+    // Remember the size so relaunched terminals start with it
+    session.settings.lock().unwrap().font_size = Some(size);
+
+    apply_setting(
+        &session,
+        "Font size",
+        &size.to_string(),
+        request.restart,
+        |terminal, ctx| terminal.set_font_size(ctx, size),
+    )
+}
+
+async fn font_handler(
+    SessionRef(session): SessionRef,
+    Json(request): Json<FontRequest>,
+) -> Result<Json<SettingResponse>, (StatusCode, Json<SettingResponse>)> {
+    let font = FontSpec {
+        family: request.family,
+        style: request.style.filter(|s| !s.is_empty()),
+    };
+    println!("Font change request: {:?}", font);
+    font.validate()
+        .map_err(|e| setting_error(StatusCode::BAD_REQUEST, e))?;
+
+    session.settings.lock().unwrap().font = Some(font.clone());
+
+    let value = match &font.style {
+        Some(style) => format!("{} {}", font.family, style),
+        None => font.family.clone(),
+    };
+    apply_setting(
+        &session,
+        "Font",
+        &value,
+        request.restart,
+        |terminal, ctx| terminal.set_font(ctx, &font),
+    )
+}
+
+async fn terminal_settings_handler(
+    SessionRef(session): SessionRef,
+) -> Json<TerminalSettingsResponse> {
+    let settings = session.settings();
+    // Prefer what the emulator's own config says, it may have been edited by hand
+    let font_size = session
+        .terminal
+        .current_font_size(session.display)
+        .or(settings.font_size);
+    let font = session
+        .terminal
+        .current_font(session.display)
+        .or(settings.font);
+    Json(TerminalSettingsResponse {
+        terminal: session.terminal.name().to_string(),
+        font_size,
+        font_family: font.as_ref().map(|f| f.family.clone()),
+        font_style: font.and_then(|f| f.style),
+        theme: settings.theme,
+    })
+}
+
+async fn fonts_handler() -> Json<FontListResponse> {
+    let families = tokio::task::spawn_blocking(terminal::monospace_families)
+        .await
+        .unwrap_or_default();
+    Json(FontListResponse { families })
+}
+
+async fn themes_handler() -> Json<Vec<theme::Theme>> {
+    Json(theme::list_themes())
+}
+
+async fn theme_handler(
+    SessionRef(session): SessionRef,
+    Json(request): Json<ThemeRequest>,
+) -> Result<Json<SettingResponse>, (StatusCode, Json<SettingResponse>)> {
+    let (name, palette) = match request {
+        ThemeRequest::Named { name } => {
+            let theme = theme::find_theme(&name).ok_or_else(|| {
+                setting_error(StatusCode::NOT_FOUND, format!("Unknown theme '{}'", name))
+            })?;
+            (Some(theme.name), theme.palette)
+        }
+        ThemeRequest::Custom(palette) => (None, *palette),
+    };
+    palette
+        .validate()
+        .map_err(|e| setting_error(StatusCode::BAD_REQUEST, e))?;
+    println!(
+        "Theme change request for {}: {}",
+        session.terminal.name(),
+        name.as_deref().unwrap_or("custom")
+    );
+
+    {
+        let mut settings = session.settings.lock().unwrap();
+        settings.theme = name.clone();
+        settings.palette = Some(palette.clone());
+    }
+
+    let value = name.unwrap_or_else(|| "custom palette".to_string());
+    apply_setting(&session, "Theme", &value, false, |terminal, ctx| {
+        terminal.set_theme(ctx, &palette)
+    })
+}
+
+async fn display_settings_handler(
+    State(state): State<Arc<AppState>>,
+    SessionRef(session): SessionRef,
+) -> Json<DisplaySettingsResponse> {
+    Json(DisplaySettingsResponse {
+        geometry: session.geometry.lock().unwrap().to_string(),
+        presets: state
+            .geometry_presets
+            .iter()
+            .map(|(name, geometry)| (name.clone(), geometry.to_string()))
+            .collect(),
+    })
+}
+
+async fn geometry_handler(
+    State(state): State<Arc<AppState>>,
+    SessionRef(session): SessionRef,
+    Json(request): Json<GeometryRequest>,
+) -> Result<Json<GeometryResponse>, (StatusCode, Json<GeometryResponse>)> {
+    let error = |status, message| {
+        (
+            status,
+            Json(GeometryResponse {
+                success: false,
+                message,
+                geometry: String::new(),
+            }),
+        )
+    };
+
+    let geometry = match request {
+        GeometryRequest::Preset { preset } => {
+            *state.geometry_presets.get(&preset).ok_or_else(|| {
+                error(
+                    StatusCode::NOT_FOUND,
+                    format!("Unknown geometry preset '{}'", preset),
+                )
+            })?
+        }
+        GeometryRequest::Size { width, height } => {
+            Geometry::new(width, height).map_err(|e| error(StatusCode::BAD_REQUEST, e))?
+        }
+    };
+    println!("Display geometry change request: {}", geometry);
+
+    // xrandr is synchronous; the geometry lock keeps concurrent resizes in order
+    let resize_session = session.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let mut current = resize_session.geometry.lock().unwrap();
+        if *current == geometry {
+            return Ok(());
+        }
+        display::resize(resize_session.display, geometry)?;
+        *current = geometry;
+        Ok(())
+    })
+    .await
+    .unwrap_or_else(|e: tokio::task::JoinError| Err(e.to_string()));
+
+    match result {
+        Ok(()) => Ok(Json(GeometryResponse {
+            success: true,
+            message: format!("Display resized to {}", geometry),
+            geometry: geometry.to_string(),
+        })),
+        Err(e) => Err(error(StatusCode::INTERNAL_SERVER_ERROR, e)),
+    }
+}
+
+async fn scrollback_handler(
+    SessionRef(session): SessionRef,
+) -> Result<Json<ScrollbackResponse>, (StatusCode, Json<ScrollbackResponse>)> {
+    let terminal = session.terminal.clone();
+    let ctx = session.terminal_context();
+    // Capturing shells out to the emulator's CLI, keep it off the async workers
+    let result = tokio::task::spawn_blocking(move || terminal.capture_scrollback(&ctx))
+        .await
+        .unwrap_or_else(|e| Err(BackendError::Failed(e.to_string())));
+
+    match result {
+        Ok(text) => Ok(Json(ScrollbackResponse {
+            success: true,
+            message: format!("Captured {} bytes", text.len()),
+            text,
+        })),
+        Err(e) => Err((
+            backend_error_status(&e),
+            Json(ScrollbackResponse {
+                success: false,
+                message: e.to_string(),
+                text: String::new(),
+            }),
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Words that must all appear in the prompt.
+    #[serde(default)]
+    q: String,
+    #[serde(default)]
+    workspace: Option<String>,
+    /// Shorthand for the workspace this session works in.
+    #[serde(default)]
+    session: Option<String>,
+    #[serde(default = "default_history_limit")]
+    limit: usize,
+}
+
+fn default_history_limit() -> usize {
+    50
+}
+
+/// Prompts sent before, newest first, optionally searched and limited to one
+/// workspace.
+async fn history_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<history::Entry>>, (StatusCode, Json<ApiResponse>)> {
+    let workspace = match &query.session {
+        Some(id) => Some(
+            state
+                .sessions
+                .get(id)
+                .ok_or_else(|| {
+                    (
+                        StatusCode::NOT_FOUND,
+                        Json(ApiResponse {
+                            success: false,
+                            message: format!("No session '{}'", id),
+                        }),
+                    )
+                })?
+                .repo_path(),
+        ),
+        None => query.workspace,
+    };
+    let entries = tokio::task::spawn_blocking(move || {
+        state
+            .history
+            .search(&query.q, workspace.as_deref(), query.limit)
+    })
+    .await
+    .unwrap_or_default();
+    Ok(Json(entries))
+}
+
+#[derive(Serialize)]
+struct PushKeyResponse {
+    /// VAPID public key for `PushManager.subscribe()`, base64url.
+    key: String,
+}
+
+fn push_disabled() -> (StatusCode, Json<ApiResponse>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(ApiResponse {
+            success: false,
+            message: "Web Push is off; set notify.web_push = true in config.toml".to_string(),
+        }),
+    )
+}
+
+/// Key browsers need to subscribe to Web Push.
+async fn push_key_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<PushKeyResponse>, (StatusCode, Json<ApiResponse>)> {
+    let push = state.notifier.push().ok_or_else(push_disabled)?;
+    Ok(Json(PushKeyResponse {
+        key: push.public_key().to_string(),
+    }))
+}
+
+/// Send notifications to the browser behind `subscription`.
+async fn push_subscribe_handler(
+    _auth: Authorized,
+    State(state): State<Arc<AppState>>,
+    Json(subscription): Json<webpush::Subscription>,
+) -> Result<Json<ApiResponse>, (StatusCode, Json<ApiResponse>)> {
+    state.notifier.push().ok_or_else(push_disabled)?;
+    let result = tokio::task::spawn_blocking(move || {
+        let push = state.notifier.push().expect("checked above");
+        push.subscribe(subscription)
+            .map(|()| "Subscribed to notifications".to_string())
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.to_string()));
+    result
+        .map(|message| {
+            Json(ApiResponse {
+                success: true,
+                message,
+            })
+        })
+        .map_err(|message| {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(ApiResponse {
+                    success: false,
+                    message,
+                }),
+            )
+        })
+}
+
+#[derive(Deserialize)]
+struct UnsubscribeRequest {
+    endpoint: String,
+}
+
+async fn push_unsubscribe_handler(
+    _auth: Authorized,
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<UnsubscribeRequest>,
+) -> Result<Json<ApiResponse>, (StatusCode, Json<ApiResponse>)> {
+    state.notifier.push().ok_or_else(push_disabled)?;
+    let result = tokio::task::spawn_blocking(move || {
+        let push = state.notifier.push().expect("checked above");
+        push.unsubscribe(&request.endpoint)
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.to_string()));
+    match result {
+        Ok(true) => Ok(Json(ApiResponse {
+            success: true,
+            message: "Unsubscribed from notifications".to_string(),
+        })),
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            Json(ApiResponse {
+                success: false,
+                message: "Not subscribed".to_string(),
+            }),
+        )),
+        Err(message) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResponse {
+                success: false,
+                message,
+            }),
+        )),
+    }
+}
+
+/// Send a test notification to every webhook and subscribed browser.
+async fn notify_test_handler(
+    _auth: Authorized,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ApiResponse>, (StatusCode, Json<ApiResponse>)> {
+    let webhooks = state.notifier.config().webhooks.len();
+    let browsers = state.notifier.push().map_or(0, |push| push.subscribed());
+    if webhooks == 0 && browsers == 0 {
+        return Err((
+            StatusCode::CONFLICT,
+            Json(ApiResponse {
+                success: false,
+                message: "No webhooks configured and no browsers subscribed".to_string(),
+            }),
+        ));
+    }
+    state.notifier.notify(state.notifier.test());
+    Ok(Json(ApiResponse {
+        success: true,
+        message: format!(
+            "Sent a test notification to {} webhook(s) and {} browser(s)",
+            webhooks, browsers
+        ),
+    }))
+}
+
+#[derive(Serialize)]
+struct QueueResponse {
+    /// What the agent was last seen doing; prompts are sent when it is idle.
+    agent_state: AgentState,
+    items: Vec<queue::Queued>,
+}
+
+fn queue_error(status: StatusCode, message: String) -> (StatusCode, Json<ApiResponse>) {
+    (
+        status,
+        Json(ApiResponse {
+            success: false,
+            message,
+        }),
+    )
+}
+
+/// Prompts waiting for the agent to become idle.
+async fn queue_handler(SessionRef(session): SessionRef) -> Json<QueueResponse> {
+    Json(QueueResponse {
+        agent_state: session.agent_state(),
+        items: session.queue.list(),
+    })
+}
+
+#[derive(Deserialize)]
+struct EnqueueRequest {
+    text: String,
+    /// Inserted from the snippet picker, so built-ins are filled in.
+    #[serde(default)]
+    snippet: bool,
+}
+
+async fn enqueue_handler(
+    SessionRef(session): SessionRef,
+    Json(request): Json<EnqueueRequest>,
+) -> Result<Json<queue::Queued>, (StatusCode, Json<ApiResponse>)> {
+    session.touch();
+    let item = session
+        .queue
+        .push(request.text, request.snippet)
+        .map_err(|e| queue_error(StatusCode::BAD_REQUEST, e))?;
+    println!("Queued prompt {} for session {}", item.id, session.id);
+    Ok(Json(item))
+}
+
+#[derive(Deserialize)]
+struct UpdateQueuedRequest {
+    text: Option<String>,
+    /// New place in the queue, 0 being next.
+    position: Option<usize>,
+}
+
+async fn update_queued_handler(
+    SessionRef(session): SessionRef,
+    UrlPath((_, item)): UrlPath<(String, u64)>,
+    Json(request): Json<UpdateQueuedRequest>,
+) -> Result<Json<queue::Queued>, (StatusCode, Json<ApiResponse>)> {
+    match session.queue.update(item, request.text, request.position) {
+        Ok(Some(queued)) => Ok(Json(queued)),
+        Ok(None) => Err(queue_error(
+            StatusCode::NOT_FOUND,
+            format!("No queued prompt {}", item),
+        )),
+        Err(e) => Err(queue_error(StatusCode::BAD_REQUEST, e)),
+    }
+}
+
+async fn cancel_queued_handler(
+    SessionRef(session): SessionRef,
+    UrlPath((_, item)): UrlPath<(String, u64)>,
+) -> Result<Json<ApiResponse>, (StatusCode, Json<ApiResponse>)> {
+    session
+        .queue
+        .remove(item)
+        .map(|_| {
+            Json(ApiResponse {
+                success: true,
+                message: format!("Cancelled queued prompt {}", item),
+            })
+        })
+        .ok_or_else(|| queue_error(StatusCode::NOT_FOUND, format!("No queued prompt {}", item)))
+}
+
+#[derive(Serialize)]
+struct SnippetsResponse {
+    snippets: Vec<snippets::Snippet>,
+    /// Variables filled in when the prompt is sent.
+    builtins: [&'static str; 5],
+}
+
+/// Prompt snippets for the session's workspace.
+async fn snippets_handler(
+    SessionRef(session): SessionRef,
+) -> Result<Json<SnippetsResponse>, (StatusCode, Json<ApiResponse>)> {
+    let snippets = session.with_repo(snippets::load).await.map_err(|message| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResponse {
+                success: false,
+                message,
+            }),
+        )
+    })?;
+    Ok(Json(SnippetsResponse {
+        snippets,
+        builtins: snippets::BUILTINS,
+    }))
+}
+
+/// How provisioning the first session's workspace is going.
+async fn workspace_handler(State(state): State<Arc<AppState>>) -> Json<workspace::Status> {
+    Json(state.workspace.status())
+}
+
+#[derive(Deserialize)]
+struct OpenWorkspaceRequest {
+    /// Session to move to the workspace; a new session is started when unset.
+    #[serde(default)]
+    session: Option<String>,
+    #[serde(flatten)]
+    workspace: workspace::Request,
+}
+
+/// Open a mounted directory, or provision a clone or worktree, and point a
+/// session at it. The session's terminal and agent are restarted there.
+async fn open_workspace_handler(
+    State(state): State<Arc<AppState>>,
+    _auth: Authorized,
+    Json(request): Json<OpenWorkspaceRequest>,
+) -> Result<Json<SessionResponse>, (StatusCode, Json<SessionResponse>)> {
+    let error = |status, message| {
+        (
+            status,
+            Json(SessionResponse {
+                success: false,
+                message,
+                session: None,
+            }),
+        )
+    };
+    let session = match &request.session {
+        Some(id) => Some(
+            state
+                .sessions
+                .get(id)
+                .ok_or_else(|| error(StatusCode::NOT_FOUND, format!("No session '{}'", id)))?,
+        ),
+        None => None,
+    };
+    // Refuse before provisioning anything for it
+    if let Some(session) = &session {
+        session
+            .check_switch()
+            .map_err(|e| error(StatusCode::CONFLICT, e))?;
+    }
+    let current = session
+        .as_ref()
+        .map(|s| std::path::PathBuf::from(s.repo_path()));
+    let spec = state
+        .workspace
+        .spec()
+        .for_request(&request.workspace, current.as_deref())
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))?;
+    state
+        .workspace
+        .begin(spec.mode)
+        .map_err(|e| error(StatusCode::CONFLICT, e))?;
+
+    let provisioning = state.workspace.clone();
+    let provision_spec = spec.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        workspace::provision(&provision_spec, &|line| provisioning.progress(line))
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.to_string()));
+    let path = state
+        .workspace
+        .finish(&spec, result)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))?;
+
+    let session = match session {
+        Some(session) => {
+            session
+                .switch_repo(path.to_string_lossy().into_owned())
+                .await
+                .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+            session
+        }
+        None => start_session(&state, path.clone())
+            .await
+            .map_err(|e| error(StatusCode::BAD_REQUEST, e))?,
+    };
+    Ok(Json(SessionResponse {
+        success: true,
+        message: format!("Session {} now works in {}", session.id, path.display()),
+        session: Some(SessionInfo::new(&session)),
+    }))
+}
+
+#[derive(Serialize)]
+struct RecentWorkspace {
+    #[serde(flatten)]
+    workspace: workspace::Recent,
+    /// Whether the directory is still there; a removed clone can be cloned again.
+    exists: bool,
+}
+
+/// Workspaces opened before, most recent first.
+async fn recent_workspaces_handler(
+    State(state): State<Arc<AppState>>,
+) -> Json<Vec<RecentWorkspace>> {
+    let recent = state
+        .workspace
+        .recent()
+        .into_iter()
+        .map(|workspace| RecentWorkspace {
+            exists: Path::new(&workspace.path).is_dir(),
+            workspace,
+        })
+        .collect();
+    Json(recent)
+}
+
+/// Start a session in `repo_path` with the default terminal and geometry.
+async fn start_session(
+    state: &AppState,
+    repo_path: std::path::PathBuf,
+) -> Result<Arc<Session>, String> {
+    state
+        .sessions
+        .create(SessionOptions {
+            id: None,
+            repo_path: repo_path.to_string_lossy().into_owned(),
+            geometry: state.geometry,
+            terminal: state.terminal.clone(),
+            agent: None,
+            limits: state.limits.clone(),
+            sandbox: state.sandbox.clone(),
+            checkpoints: state.checkpoints.clone(),
+            activity: state.activity.clone(),
+            notifier: state.notifier.clone(),
+            worktree: None,
+        })
+        .await
+}
+
+/// Start the session for the provisioned workspace.
+async fn create_first_session(
+    state: &AppState,
+    repo_path: std::path::PathBuf,
+    web_port: u16,
+) -> Result<(), String> {
+    let session = start_session(state, repo_path).await?;
+    println!("Web UI: http://localhost:{}/s/{}/", web_port, session.id);
+    Ok(())
+}
+
+fn validate_args(args: &[String]) -> Result<(String, String, u16), String> {
+    if args.len() < 2 {
+        return Err("Usage: vnccc <repo-path> [geometry] [web-port]".to_string());
+    }
+
+    let repo_path = args[1].clone();
+    let geometry = args
+        .get(2)
+        .map(|s| s.as_str())
+        .unwrap_or("1024x1024")
+        .to_string();
+    let web_port: u16 = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(8080);
+
+    if !Path::new(&repo_path).exists() {
+        return Err(format!("Error: repo path '{}' does not exist", repo_path));
+    }
+
+    Ok((repo_path, geometry, web_port))
+}
+
+/// A geometry preset name or a literal `WIDTHxHEIGHT`.
+fn resolve_geometry(
+    geometry: &str,
+    presets: &BTreeMap<String, Geometry>,
+) -> Result<Geometry, String> {
+    match presets.get(geometry) {
+        Some(preset) => Ok(*preset),
+        None => Geometry::parse(geometry),
+    }
+}
+
+fn validate_font_size(size: f32) -> Result<f32, String> {
+    if !size.is_finite() {
+        return Err("Font size must be a valid number".to_string());
+    }
+    if !(8.0..=72.0).contains(&size) {
+        return Err(format!(
+            "Font size must be between 8.0 and 72.0, got {}",
+            size
+        ));
+    }
+    Ok(size)
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    match args.get(1).map(String::as_str) {
+        Some("agent-loop") => agent_loop::run(&args[2..]),
+        Some("sandbox-probe") => sandbox::run_probe(&args[2..]),
+        Some("ctl") => std::process::exit(cli::run(&args[2..])),
+        _ => {}
+    }
+
+    let (repo_path, geometry, web_port) = match validate_args(&args) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Example: vnccc /home/user/myproject 1024x1024 8080");
+            std::process::exit(1);
+        }
+    };
+
+    let config = match Config::load() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-            // Stop the log monitor if it was started
-            if let Some(handle) = monitor_handle {
-                handle.abort();
+    let terminal: Arc<dyn TerminalBackend> =
+        match terminal::select_backend(config.terminal.as_deref()) {
+            Ok(backend) => backend.into(),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
             }
+        };
 
-            // Brief delay before restart
-            tokio::time::sleep(Duration::from_millis(500)).await;
+    let geometry_presets = match config.geometry_presets() {
+        Ok(presets) => presets,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
+    };
+    // The geometry argument may also name a preset
+    let geometry = resolve_geometry(&geometry, &geometry_presets).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    let spec = workspace::Spec::from_env(&repo_path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    let state = Arc::new(AppState {
+        sessions: SessionManager::default(),
+        terminal,
+        geometry,
+        geometry_presets,
+        api_token: config.api_token.clone().filter(|t| !t.is_empty()),
+        emergency_stop: config.emergency_stop.clone(),
+        limits: config.limits.clone(),
+        sandbox: config.sandbox.clone(),
+        checkpoints: config.checkpoints.clone(),
+        activity: config.activity.clone(),
+        notifier: Arc::new(notify::Notifier::new(&config.notify, config::state_dir())),
+        git_actions: config.git.actions,
+        uploads: config.uploads.clone(),
+        workspace: Arc::new(workspace::Provisioning::new(&spec)),
+        history: history::History::new(config::state_dir().map(|dir| dir.join("history"))),
     });
 
-    let state = Arc::new(AppState { display });
+    // The workspace becomes the first session. A mounted one is used right
+    // away; a clone or worktree is made while the web UI shows its progress.
+    if spec.mode == workspace::Mode::Mount {
+        let result = workspace::provision(&spec, &|line| println!("{}", line));
+        let path = state.workspace.finish(&spec, result).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        if let Err(e) = create_first_session(&state, path, web_port).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    } else {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let provisioning = state.workspace.clone();
+            let provision_spec = spec.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                workspace::provision(&provision_spec, &|line| provisioning.progress(line))
+            })
+            .await
+            .unwrap_or_else(|e| Err(e.to_string()));
+            match state.workspace.finish(&spec, result) {
+                Ok(path) => {
+                    println!("Workspace ready at {}", path.display());
+                    if let Err(e) = create_first_session(&state, path, web_port).await {
+                        eprintln!("{}", e);
+                    }
+                }
+                Err(e) => eprintln!("Failed to provision workspace: {}", e),
+            }
+        });
+    }
 
-    // Wrap processes for cleanup
-    let processes = Arc::new(Mutex::new(Processes {
-        vnc: vnc_proc,
-        websockify: websockify_proc,
-    }));
-    let processes_clone = processes.clone();
+    if config.idle.timeout_minutes > 0 {
+        let timeout = Duration::from_secs(config.idle.timeout_minutes * 60);
+        let action = config.idle.action;
+        println!(
+            "Idle timeout: {:?} sessions after {} minutes",
+            action, config.idle.timeout_minutes
+        );
+        let state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                state.sessions.sleep_idle(timeout, action);
+            }
+        });
+    }
+
+    {
+        let state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                watch_agents(&state).await;
+            }
+        });
+    }
+
+    if !config.limits.is_empty() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                state.sessions.log_limit_hits();
+            }
+        });
+    }
 
     let app = Router::new()
-        .route("/", get(index_handler))
-        .route("/prompt", get(prompt_ws_handler))
-        .route("/api/font-size", post(font_size_handler))
+        .route("/", get(landing_handler))
+        .route("/metrics", get(metrics_handler))
+        .route(
+            "/api/workspace",
+            get(workspace_handler).post(open_workspace_handler),
+        )
+        .route("/api/workspace/recent", get(recent_workspaces_handler))
+        .route("/api/history", get(history_handler))
+        .route("/api/push/key", get(push_key_handler))
+        .route("/api/push/subscribe", post(push_subscribe_handler))
+        .route("/api/push/unsubscribe", post(push_unsubscribe_handler))
+        .route("/api/notify/test", post(notify_test_handler))
+        .route(
+            "/api/sessions",
+            get(list_sessions_handler).post(create_session_handler),
+        )
+        .route(
+            "/api/sessions/{id}",
+            get(get_session_handler).delete(delete_session_handler),
+        )
+        .route("/s/{id}", get(session_redirect_handler))
+        .route("/s/{id}/", get(session_index_handler))
+        .route("/s/{id}/prompt", get(prompt_ws_handler))
+        .route("/s/{id}/websockify", get(vnc_ws_handler))
+        .route("/s/{id}/api/font-size", post(font_size_handler))
+        .route(
+            "/s/{id}/api/terminal/settings",
+            get(terminal_settings_handler),
+        )
+        .route("/s/{id}/api/terminal/font", post(font_handler))
+        .route("/s/{id}/api/terminal/fonts", get(fonts_handler))
+        .route("/s/{id}/api/terminal/theme", post(theme_handler))
+        .route("/s/{id}/api/themes", get(themes_handler))
+        .route("/s/{id}/api/snippets", get(snippets_handler))
+        .route(
+            "/s/{id}/api/queue",
+            get(queue_handler).post(enqueue_handler),
+        )
+        .route(
+            "/s/{id}/api/queue/{item}",
+            put(update_queued_handler).delete(cancel_queued_handler),
+        )
+        .route("/s/{id}/api/terminal/scrollback", get(scrollback_handler))
+        .route("/s/{id}/api/processes", get(processes_handler))
+        .route("/s/{id}/api/sandbox", get(sandbox_handler))
+        .route(
+            "/s/{id}/api/upload",
+            post(upload_handler)
+                .layer(DefaultBodyLimit::max((state.uploads.max_mb as usize) << 20)),
+        )
+        .route("/s/{id}/api/files", get(files_handler))
+        .route("/s/{id}/api/download", get(download_handler))
+        .route("/s/{id}/api/files/content", get(file_content_handler))
+        .route("/s/{id}/api/files/raw", get(file_raw_handler))
+        .route("/s/{id}/api/git/status", get(git_status_handler))
+        .route("/s/{id}/api/git/diff", get(git_diff_handler))
+        .route("/s/{id}/api/git/log", get(git_log_handler))
+        .route("/s/{id}/api/git/{action}", post(git_action_handler))
+        .route("/s/{id}/api/checkpoints", get(checkpoints_handler))
+        .route(
+            "/s/{id}/api/checkpoints/{checkpoint}/diff",
+            get(checkpoint_diff_handler),
+        )
+        .route(
+            "/s/{id}/api/checkpoints/{checkpoint}/restore",
+            post(restore_checkpoint_handler),
+        )
+        .route("/s/{id}/api/agent/restart", post(restart_agent_handler))
+        .route("/s/{id}/api/agent/pause", post(pause_agent_handler))
+        .route("/s/{id}/api/agent/resume", post(resume_agent_handler))
+        .route("/s/{id}/api/agent/stop", post(stop_agent_handler))
+        .route(
+            "/s/{id}/api/terminal/restart",
+            post(restart_terminal_handler),
+        )
+        .route("/s/{id}/api/display/restart", post(restart_display_handler))
+        .route(
+            "/s/{id}/api/display/geometry",
+            get(display_settings_handler).post(geometry_handler),
+        )
         .route(
             "/favicon.ico",
             get(|| async {
@@ -637,26 +2618,32 @@ async fn main() {
             }),
         )
         .nest_service("/static", ServeDir::new("static"))
-        .with_state(state);
+        .nest_service("/novnc", ServeDir::new(NOVNC_DIR))
+        .with_state(state.clone());
 
     println!();
     println!("=== vncaa running ===");
-    println!("Web UI: http://localhost:{}", web_port);
-    println!("VNC websocket: ws://localhost:{}", ws_port);
+    println!("Sessions: http://localhost:{}/", web_port);
     println!();
 
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", web_port))
         .await
         .unwrap();
 
-    // Run server with graceful shutdown on Ctrl+C
+    // Run server with graceful shutdown on Ctrl+C or SIGTERM
     axum::serve(listener, app)
         .with_graceful_shutdown(async move {
-            let _ = signal::ctrl_c().await;
+            // Ctrl+C, or `docker stop`
+            let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+                .expect("Failed to listen for SIGTERM");
+            tokio::select! {
+                _ = signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
             println!("\nShutting down...");
-            let mut procs = processes_clone.lock().await;
-            let _ = procs.vnc.kill();
-            let _ = procs.websockify.kill();
+            state.sessions.destroy_all();
+            let provisioning = state.workspace.clone();
+            let _ = tokio::task::spawn_blocking(move || provisioning.cleanup()).await;
             println!("Cleanup complete");
         })
         .await
@@ -667,11 +2654,6 @@ async fn main() {
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_find_terminal() {
-        assert_eq!(find_terminal(), "alacritty");
-    }
-
     #[test]
     fn test_validate_args_missing_repo_path() {
         let args = vec!["vnccc".to_string()];
@@ -717,9 +2699,125 @@ mod tests {
     }
 
     #[test]
-    fn test_find_available_display_returns_valid_number() {
-        let display = find_available_display();
-        assert!((1..100).contains(&display));
+    fn test_render_metrics() {
+        let session = |id: &str, usage: Option<UsageTotals>| SessionInfo {
+            id: id.to_string(),
+            url: format!("/s/{}/", id),
+            repo_path: "/repo".to_string(),
+            agent: None,
+            worktree: None,
+            terminal: "xterm".to_string(),
+            display: 1,
+            geometry: "1024x768".to_string(),
+            running: usage.is_some(),
+            agent_pgid: None,
+            paused: false,
+            held: false,
+            power: Power::Awake.name(),
+            agent_state: AgentState::Offline.name(),
+            agent_watch: None,
+            usage,
+            limit_hits: None,
+        };
+        let usage = UsageTotals {
+            processes: 3,
+            cpu_percent: 12.5,
+            rss_bytes: 1024,
+            open_files: 40,
+        };
+        let metrics = render_metrics(&[session("api", Some(usage)), session("web", None)]);
+        assert!(metrics.contains("# TYPE vncaa_session_rss_bytes gauge\n"));
+        assert!(metrics.contains("vncaa_session_cpu_percent{session=\"api\"} 12.5\n"));
+        assert!(metrics.contains("vncaa_session_running{session=\"web\"} 0\n"));
+        assert!(!metrics.contains("vncaa_session_processes{session=\"web\"}"));
+    }
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match("s3cret", "s3cret"));
+        assert!(!tokens_match("s3cre", "s3cret"));
+        assert!(!tokens_match("s3creT", "s3cret"));
+        assert!(!tokens_match("", "s3cret"));
+    }
+
+    #[test]
+    fn test_resolve_geometry() {
+        let presets = BTreeMap::from([("phone".to_string(), Geometry::new(720, 1280).unwrap())]);
+        assert_eq!(
+            resolve_geometry("phone", &presets),
+            Geometry::new(720, 1280)
+        );
+        assert_eq!(
+            resolve_geometry("800x600", &presets),
+            Geometry::new(800, 600)
+        );
+        assert!(resolve_geometry("tablet", &presets).is_err());
+    }
+
+    #[test]
+    fn test_plan_download() {
+        use std::os::unix::fs::symlink;
+
+        let repo = git::tests::temp_repo("download");
+        let outside = repo.with_extension("outside");
+        let _ = std::fs::remove_dir_all(&outside);
+        std::fs::create_dir_all(repo.join("src")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(repo.join("src/lib.rs"), "old").unwrap();
+        std::fs::write(repo.join("README"), "readme").unwrap();
+        std::fs::write(outside.join("secret"), "key").unwrap();
+        let git = |args: &[&str]| {
+            assert!(
+                Command::new("git")
+                    .args(args)
+                    .current_dir(&repo)
+                    .status()
+                    .unwrap()
+                    .success()
+            );
+        };
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", "init"]);
+        symlink(&outside, repo.join("out")).unwrap();
+        std::fs::write(repo.join("src/lib.rs"), "new").unwrap();
+        std::fs::write(repo.join("src/new.rs"), "added").unwrap();
+        std::fs::write(outside.join("changed"), "x").unwrap();
+
+        // Nothing outside the workspace, directly or through a symlink
+        let plan = |rel: &str, since: Option<&str>| {
+            plan_download(&repo, rel, since.map(str::to_string), files::Format::TarGz)
+        };
+        assert!(plan("../", None).is_err());
+        assert!(plan("/etc/passwd", None).is_err());
+        assert!(plan("out/secret", None).is_err());
+        assert!(plan("out", Some("HEAD")).is_err());
+
+        match plan("README", None).unwrap() {
+            Download::File(path) => assert_eq!(path, repo.join("README")),
+            Download::Archive(..) => panic!("a file is sent as is"),
+        }
+        match plan("src", None).unwrap() {
+            Download::Archive(archive, name) => {
+                assert_eq!(name, "src");
+                assert_eq!(archive.input, b"src\0");
+            }
+            Download::File(_) => panic!("a directory is archived"),
+        }
+
+        // Changed and untracked files only; the symlink goes in as a link,
+        // not what it points to
+        match plan("", Some("HEAD")).unwrap() {
+            Download::Archive(archive, name) => {
+                assert!(name.ends_with("-since-HEAD"));
+                assert_eq!(archive.input, b"out\0src/lib.rs\0src/new.rs\0");
+            }
+            Download::File(_) => panic!("changes are archived"),
+        }
+        assert!(plan("README", Some("HEAD")).is_err());
+        assert!(plan("", Some("no-such-ref")).is_err());
+
+        std::fs::remove_dir_all(repo).unwrap();
+        std::fs::remove_dir_all(outside).unwrap();
     }
 
     #[test]
@@ -750,4 +2848,40 @@ mod tests {
         assert!(result.is_err());
         assert!(result.unwrap_err().contains("valid number"));
     }
+
+    #[tokio::test]
+    async fn test_prompt_builtins() {
+        let session = Session::new(
+            "7".to_string(),
+            0,
+            Vec::new(),
+            None,
+            session::tests::options(Path::new("/repo"), None),
+        );
+        let text = "Summarise session {{session}} in {{workspace}}";
+        assert_eq!(prompt_text(&session, text, false).await, text);
+        assert_eq!(
+            prompt_text(&session, text, true).await,
+            "Summarise session 7 in /repo"
+        );
+
+        // The page sends JSON; anything else is the prompt itself
+        assert_eq!(
+            Prompt::parse(r#"{"text":"{{branch}}","snippet":true}"#),
+            Prompt {
+                text: "{{branch}}".to_string(),
+                snippet: true
+            }
+        );
+        assert!(!Prompt::parse(r#"{"text":"Fix it"}"#).snippet);
+        for message in ["Explain {{branch}}", r#"{"snippet":true}"#, "[1]"] {
+            assert_eq!(
+                Prompt::parse(message),
+                Prompt {
+                    text: message.to_string(),
+                    snippet: false
+                }
+            );
+        }
+    }
 }
@@ -0,0 +1,352 @@
+//! Notifications about sessions: the agent finished, is asking something, or
+//! its terminal crashed or keeps crashing. They go to the webhooks in
+//! config.toml and, through Web Push, to browsers that asked for them.
+//! Requests are made with `curl` on a thread of their own, so a slow
+//! endpoint never holds up a session.
+
+use serde::{Deserialize, Serialize};
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{self, Webhook};
+use crate::snippets;
+use crate::webpush::WebPush;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    /// The agent went idle after working for a while.
+    Done,
+    /// The agent is asking something, e.g. for permission.
+    Attention,
+    /// The terminal exited without being asked to.
+    Crashed,
+    /// The terminal or agent keeps exiting.
+    CrashLoop,
+    /// Sent from the API to try the setup out; every sink gets it.
+    Test,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub event: Event,
+    pub session: String,
+    pub workspace: String,
+    pub agent: String,
+    pub title: String,
+    pub message: String,
+    /// Session page.
+    pub url: String,
+    /// Seconds since the epoch.
+    pub time: u64,
+}
+
+impl Notification {
+    /// Value of a `{{variable}}` in webhook templates.
+    fn variable(&self, name: &str) -> Option<String> {
+        Some(match name {
+            "event" => serde_json::to_value(self.event).ok()?.as_str()?.to_string(),
+            "session" => self.session.clone(),
+            "workspace" => self.workspace.clone(),
+            "agent" => self.agent.clone(),
+            "title" => self.title.clone(),
+            "message" => self.message.clone(),
+            "url" => self.url.clone(),
+            "time" => self.time.to_string(),
+            _ => return None,
+        })
+    }
+}
+
+/// POST `body` to `url` with `curl`, returning the HTTP status.
+pub fn post(url: &str, headers: &[(String, String)], body: &[u8]) -> Result<u16, String> {
+    let mut cmd = Command::new("curl");
+    cmd.args([
+        "-sS",
+        "--max-time",
+        "10",
+        "-o",
+        "/dev/null",
+        "-w",
+        "%{http_code}",
+    ]);
+    for (name, value) in headers {
+        cmd.arg("-H").arg(format!("{}: {}", name, value));
+    }
+    cmd.args(["--data-binary", "@-", "--", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| format!("Failed to run curl: {}", e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(body);
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Failed to run curl: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "POST {} failed: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .map_err(|_| format!("POST {}: no HTTP status", url))
+}
+
+/// Body and headers of `webhook`'s request for `notification`. Values put
+/// into a JSON body are escaped for use inside its strings.
+fn render(webhook: &Webhook, notification: &Notification) -> (Vec<u8>, Vec<(String, String)>) {
+    let json = webhook.content_type.contains("json");
+    let body = match &webhook.body {
+        Some(template) => snippets::expand(template, |name| {
+            let value = notification.variable(name)?;
+            if !json {
+                return Some(value);
+            }
+            let quoted = serde_json::to_string(&value).ok()?;
+            Some(quoted[1..quoted.len() - 1].to_string())
+        }),
+        None => serde_json::to_string(notification).unwrap_or_default(),
+    };
+    let mut headers = vec![("Content-Type".to_string(), webhook.content_type.clone())];
+    for (name, template) in &webhook.headers {
+        let value = snippets::expand(template, |name| notification.variable(name));
+        headers.push((name.clone(), value.replace(['\r', '\n'], " ")));
+    }
+    (body.into_bytes(), headers)
+}
+
+pub struct Notifier {
+    config: config::Notify,
+    push: Option<WebPush>,
+}
+
+impl Notifier {
+    /// Web Push keeps its keys and subscriptions in `state_dir`, and is off
+    /// without one.
+    pub fn new(config: &config::Notify, state_dir: Option<PathBuf>) -> Notifier {
+        let push = match state_dir {
+            Some(dir) if config.web_push => match WebPush::load(&dir, &config.subject) {
+                Ok(push) => Some(push),
+                Err(e) => {
+                    eprintln!("Web Push is off: {}", e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        Notifier {
+            config: config.clone(),
+            push,
+        }
+    }
+
+    pub fn config(&self) -> &config::Notify {
+        &self.config
+    }
+
+    pub fn push(&self) -> Option<&WebPush> {
+        self.push.as_ref()
+    }
+
+    /// Link to session `id`'s page.
+    pub fn session_url(&self, id: &str) -> String {
+        let base = self.config.base_url.as_deref().unwrap_or_default();
+        format!("{}/s/{}/", base.trim_end_matches('/'), id)
+    }
+
+    /// Notification for trying the setup out.
+    pub fn test(&self) -> Notification {
+        Notification {
+            event: Event::Test,
+            session: String::new(),
+            workspace: String::new(),
+            agent: String::new(),
+            title: "vncaa".to_string(),
+            message: "Notifications work".to_string(),
+            url: format!(
+                "{}/",
+                self.config
+                    .base_url
+                    .as_deref()
+                    .unwrap_or_default()
+                    .trim_end_matches('/')
+            ),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    /// Send `notification` to every sink that wants it, in the background.
+    pub fn notify(self: &std::sync::Arc<Self>, notification: Notification) {
+        if self.config.webhooks.is_empty() && self.push.is_none() {
+            return;
+        }
+        println!(
+            "Notifying {:?} for session {}: {}",
+            notification.event, notification.session, notification.message
+        );
+        let notifier = self.clone();
+        thread::spawn(move || notifier.send(&notification));
+    }
+
+    fn send(&self, notification: &Notification) {
+        for webhook in &self.config.webhooks {
+            if notification.event != Event::Test
+                && !webhook.events.is_empty()
+                && !webhook.events.contains(&notification.event)
+            {
+                continue;
+            }
+            let (body, headers) = render(webhook, notification);
+            match post(&webhook.url, &headers, &body) {
+                Ok(status) if (200..300).contains(&status) => {}
+                Ok(status) => eprintln!("Webhook {} answered {}", webhook.url, status),
+                Err(e) => eprintln!("Webhook failed: {}", e),
+            }
+        }
+        if let Some(push) = &self.push {
+            let payload = serde_json::to_vec(notification).unwrap_or_default();
+            push.send_all(&payload);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+
+    fn notification() -> Notification {
+        Notification {
+            event: Event::Done,
+            session: "1".to_string(),
+            workspace: "/repo".to_string(),
+            agent: "claude".to_string(),
+            title: "vncaa session 1".to_string(),
+            message: "Said \"done\"\nin /repo".to_string(),
+            url: "/s/1/".to_string(),
+            time: 1,
+        }
+    }
+
+    fn webhook(body: Option<&str>, content_type: &str) -> Webhook {
+        Webhook {
+            url: "http://127.0.0.1/hook".to_string(),
+            events: Vec::new(),
+            body: body.map(str::to_string),
+            content_type: content_type.to_string(),
+            headers: BTreeMap::from([("Title".to_string(), "{{title}}: {{message}}".to_string())]),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let (body, headers) = render(
+            &webhook(
+                Some(r#"{"text": "{{event}}: {{message}}"}"#),
+                "application/json",
+            ),
+            &notification(),
+        );
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["text"], "done: Said \"done\"\nin /repo");
+        assert_eq!(
+            headers[1],
+            (
+                "Title".to_string(),
+                "vncaa session 1: Said \"done\" in /repo".to_string()
+            )
+        );
+
+        let (body, headers) = render(&webhook(Some("{{message}}"), "text/plain"), &notification());
+        assert_eq!(body, b"Said \"done\"\nin /repo");
+        assert_eq!(headers[0].1, "text/plain");
+
+        let (body, _) = render(&webhook(None, "application/json"), &notification());
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["event"], "done");
+        assert_eq!(body["url"], "/s/1/");
+    }
+
+    /// One request to a stub HTTP receiver: request line, lowercased
+    /// headers and body.
+    fn receive(listener: TcpListener) -> (String, BTreeMap<String, String>, String) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut headers = BTreeMap::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let Some((name, value)) = line.trim_end().split_once(": ") else {
+                break;
+            };
+            headers.insert(name.to_lowercase(), value.to_string());
+        }
+        let length = headers["content-length"].parse().unwrap();
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        (
+            request_line.trim_end().to_string(),
+            headers,
+            String::from_utf8(body).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_send_webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let receiver = thread::spawn(move || receive(listener));
+
+        let mut hook = webhook(
+            Some(r#"{"text": "{{title}}", "event": "{{event}}", "link": "{{url}}"}"#),
+            "application/json",
+        );
+        hook.url = url.clone();
+        let skipped = Webhook {
+            url: url.replace("/hook", "/skipped"),
+            events: vec![Event::Crashed],
+            ..hook.clone()
+        };
+        let config = config::Notify {
+            webhooks: vec![skipped, hook],
+            web_push: false,
+            ..Default::default()
+        };
+        let notifier = Notifier::new(&config, None);
+        assert!(notifier.push().is_none());
+        notifier.send(&notification());
+
+        // The first webhook does not want the event, so the one request
+        // received is the second's
+        let (request_line, headers, body) = receiver.join().unwrap();
+        assert_eq!(request_line, "POST /hook HTTP/1.1");
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(headers["title"], "vncaa session 1: Said \"done\" in /repo");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"text": "vncaa session 1", "event": "done", "link": "/s/1/"})
+        );
+    }
+}
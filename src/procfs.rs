@@ -0,0 +1,320 @@
+//! Process information read from `/proc`.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::time::{Duration, Instant};
+
+/// The fields of `/proc/<pid>/stat` vnccc cares about.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProcStat {
+    pub pid: i32,
+    /// Executable name, truncated by the kernel to 15 bytes.
+    pub comm: String,
+    /// Single-letter state, `Z` for zombies waiting to be reaped.
+    pub state: char,
+    pub ppid: i32,
+    pub pgid: i32,
+    /// User plus system CPU time, in clock ticks.
+    pub cpu_ticks: u64,
+    /// Start time after boot, in clock ticks.
+    pub start_ticks: u64,
+    /// Resident set size, in pages.
+    pub rss_pages: u64,
+}
+
+/// Parse `/proc/<pid>/stat`. The command name is in parentheses and may
+/// itself contain spaces or parentheses, so fields are counted from the last `)`.
+pub fn parse_stat(stat: &str) -> Option<ProcStat> {
+    let pid = stat.split_whitespace().next()?.parse().ok()?;
+    let open = stat.find('(')?;
+    let close = stat.rfind(')')?;
+    let comm = stat.get(open + 1..close)?.to_string();
+    let fields: Vec<&str> = stat[close + 1..].split_whitespace().collect();
+    let number = |i: usize| fields.get(i)?.parse::<u64>().ok();
+    Some(ProcStat {
+        pid,
+        comm,
+        state: fields.first()?.chars().next()?,
+        ppid: fields.get(1)?.parse().ok()?,
+        pgid: fields.get(2)?.parse().ok()?,
+        cpu_ticks: number(11).unwrap_or(0) + number(12).unwrap_or(0),
+        start_ticks: number(19).unwrap_or(0),
+        rss_pages: number(21).unwrap_or(0),
+    })
+}
+
+/// Every process we can read, skipping ones that exit while we look.
+pub fn all_processes() -> Vec<ProcStat> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter(|e| {
+            e.file_name()
+                .to_str()
+                .is_some_and(|n| n.parse::<i32>().is_ok())
+        })
+        .filter_map(|e| fs::read_to_string(e.path().join("stat")).ok())
+        .filter_map(|stat| parse_stat(&stat))
+        .collect()
+}
+
+/// Pids of all descendants of `root` in `processes`, children first.
+pub fn descendants_in(processes: &[ProcStat], root: i32) -> Vec<i32> {
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    for p in processes {
+        children.entry(p.ppid).or_default().push(p.pid);
+    }
+    let mut found = Vec::new();
+    let mut queue = vec![root];
+    while let Some(pid) = queue.pop() {
+        for &child in children.get(&pid).into_iter().flatten() {
+            found.push(child);
+            queue.push(child);
+        }
+    }
+    found
+}
+
+/// Live processes that belong to the agent: members of process group `pgid`
+/// and anything they started, even if it moved to a group or session of its own.
+pub fn process_tree(pgid: i32) -> Vec<i32> {
+    let mut processes = all_processes();
+    processes.retain(|p| p.state != 'Z');
+    let mut pids: Vec<i32> = processes
+        .iter()
+        .filter(|p| p.pgid == pgid)
+        .map(|p| p.pid)
+        .collect();
+    for member in pids.clone() {
+        pids.extend(descendants_in(&processes, member));
+    }
+    pids.sort_unstable();
+    pids.dedup();
+    pids
+}
+
+/// Resource use of one process.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessUsage {
+    pub pid: i32,
+    pub ppid: i32,
+    pub state: String,
+    /// Full command line, or the executable name for kernel-style processes.
+    pub command: String,
+    pub cpu_percent: f64,
+    pub rss_bytes: u64,
+    /// Unknown when `/proc/<pid>/fd` is not readable.
+    pub open_files: Option<usize>,
+}
+
+/// Sums over a process tree.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UsageTotals {
+    pub processes: usize,
+    pub cpu_percent: f64,
+    pub rss_bytes: u64,
+    pub open_files: usize,
+}
+
+impl UsageTotals {
+    pub fn sum(processes: &[ProcessUsage]) -> UsageTotals {
+        UsageTotals {
+            processes: processes.len(),
+            cpu_percent: processes.iter().map(|p| p.cpu_percent).sum(),
+            rss_bytes: processes.iter().map(|p| p.rss_bytes).sum(),
+            open_files: processes.iter().filter_map(|p| p.open_files).sum(),
+        }
+    }
+}
+
+/// CPU times from an earlier walk, so CPU % covers the time since then
+/// rather than each process's whole life.
+#[derive(Debug, Default)]
+pub struct CpuSample {
+    taken: Option<Instant>,
+    ticks: HashMap<i32, u64>,
+}
+
+impl CpuSample {
+    /// Samples closer together than this are too noisy to replace the last one.
+    const MIN_INTERVAL: Duration = Duration::from_secs(1);
+}
+
+fn clock_ticks_per_sec() -> f64 {
+    // SAFETY: sysconf has no preconditions
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks > 0 { ticks as f64 } else { 100.0 }
+}
+
+fn page_size() -> u64 {
+    // SAFETY: sysconf has no preconditions
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size > 0 { size as u64 } else { 4096 }
+}
+
+/// Seconds since boot, from `/proc/uptime`.
+fn uptime_secs() -> Option<f64> {
+    fs::read_to_string("/proc/uptime")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Command line from `/proc/<pid>/cmdline`, whose arguments are NUL-separated.
+fn parse_cmdline(raw: &[u8]) -> String {
+    raw.split(|&b| b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(String::from_utf8_lossy)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// CPU % of `ticks` spent over `elapsed_secs`, where 100 is one full core.
+fn cpu_percent(ticks: u64, elapsed_secs: f64, ticks_per_sec: f64) -> f64 {
+    if elapsed_secs <= 0.0 {
+        return 0.0;
+    }
+    ticks as f64 / ticks_per_sec / elapsed_secs * 100.0
+}
+
+/// Resource use of `root` and all of its live descendants. CPU % is measured
+/// since `sample` was taken; processes it has not seen yet are averaged over
+/// their lifetime instead.
+pub fn tree_usage(root: i32, sample: &mut CpuSample) -> Vec<ProcessUsage> {
+    let now = Instant::now();
+    let mut processes = all_processes();
+    processes.retain(|p| p.state != 'Z');
+    let mut pids = vec![root];
+    pids.extend(descendants_in(&processes, root));
+
+    let ticks_per_sec = clock_ticks_per_sec();
+    let page_size = page_size();
+    let uptime = uptime_secs().unwrap_or(0.0);
+    let since_sample = sample.taken.map(|t| now.duration_since(t).as_secs_f64());
+
+    let usage: Vec<ProcessUsage> = processes
+        .iter()
+        .filter(|p| pids.contains(&p.pid))
+        .map(|p| {
+            let cpu = match (since_sample, sample.ticks.get(&p.pid)) {
+                (Some(elapsed), Some(&before)) => {
+                    cpu_percent(p.cpu_ticks.saturating_sub(before), elapsed, ticks_per_sec)
+                }
+                _ => {
+                    let age = uptime - p.start_ticks as f64 / ticks_per_sec;
+                    cpu_percent(p.cpu_ticks, age, ticks_per_sec)
+                }
+            };
+            let command = fs::read(format!("/proc/{}/cmdline", p.pid))
+                .map(|raw| parse_cmdline(&raw))
+                .ok()
+                .filter(|c| !c.is_empty())
+                .unwrap_or_else(|| format!("[{}]", p.comm));
+            let open_files = fs::read_dir(format!("/proc/{}/fd", p.pid))
+                .ok()
+                .map(|fds| fds.count());
+            ProcessUsage {
+                pid: p.pid,
+                ppid: p.ppid,
+                state: p.state.to_string(),
+                command,
+                cpu_percent: (cpu * 10.0).round() / 10.0,
+                rss_bytes: p.rss_pages * page_size,
+                open_files,
+            }
+        })
+        .collect();
+
+    if since_sample.is_none_or(|elapsed| elapsed >= CpuSample::MIN_INTERVAL.as_secs_f64()) {
+        sample.taken = Some(now);
+        sample.ticks = processes
+            .iter()
+            .filter(|p| pids.contains(&p.pid))
+            .map(|p| (p.pid, p.cpu_ticks))
+            .collect();
+    }
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "4242 (my (odd) prog) S 4200 4240 4240 34816 4240 4194560 0 0 0 0 \
+                    150 50 0 0 20 0 1 0 98765 1000000 321 18446744073709551615";
+        assert_eq!(
+            parse_stat(stat),
+            Some(ProcStat {
+                pid: 4242,
+                comm: "my (odd) prog".to_string(),
+                state: 'S',
+                ppid: 4200,
+                pgid: 4240,
+                cpu_ticks: 200,
+                start_ticks: 98765,
+                rss_pages: 321,
+            })
+        );
+        assert_eq!(parse_stat("garbage"), None);
+    }
+
+    #[test]
+    fn test_descendants_in() {
+        let p = |pid, ppid| ProcStat {
+            pid,
+            ppid,
+            ..Default::default()
+        };
+        let processes = [p(10, 1), p(11, 10), p(12, 11), p(13, 10), p(20, 1)];
+        let mut found = descendants_in(&processes, 10);
+        found.sort_unstable();
+        assert_eq!(found, vec![11, 12, 13]);
+        assert!(descendants_in(&processes, 20).is_empty());
+    }
+
+    #[test]
+    fn test_process_tree_finds_child() {
+        let mut child = Command::new("sleep").arg("5").spawn().unwrap();
+        // SAFETY: getpgrp has no preconditions
+        let pgid = unsafe { libc::getpgrp() };
+        let tree = process_tree(pgid);
+        assert!(tree.contains(&(std::process::id() as i32)));
+        assert!(tree.contains(&(child.id() as i32)));
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_parse_cmdline_and_cpu_percent() {
+        assert_eq!(
+            parse_cmdline(b"cargo\0build\0--release\0"),
+            "cargo build --release"
+        );
+        assert_eq!(parse_cmdline(b""), "");
+        assert_eq!(cpu_percent(50, 1.0, 100.0), 50.0);
+        assert_eq!(cpu_percent(400, 2.0, 100.0), 200.0);
+        assert_eq!(cpu_percent(10, 0.0, 100.0), 0.0);
+    }
+
+    #[test]
+    fn test_tree_usage_covers_children() {
+        let mut child = Command::new("sleep").arg("5").spawn().unwrap();
+        let mut sample = CpuSample::default();
+        let usage = tree_usage(std::process::id() as i32, &mut sample);
+        let sleep = usage.iter().find(|p| p.pid == child.id() as i32).unwrap();
+        assert_eq!(sleep.command, "sleep 5");
+        assert!(sleep.rss_bytes > 0);
+        assert!(sleep.open_files.is_some());
+        assert!(sample.taken.is_some());
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+//! Prompts waiting for a session's agent to finish what it is doing. They
+//! live on the server, so they are sent whether or not a page is open.
+
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Most prompts a session's queue holds.
+const MAX_QUEUED: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Queued {
+    /// Stays the same while the prompt is edited or moved.
+    pub id: u64,
+    pub text: String,
+    /// Whether it came from the snippet picker, so built-ins get filled in.
+    pub snippet: bool,
+    /// Seconds since the epoch.
+    pub queued: u64,
+}
+
+#[derive(Default)]
+struct Items {
+    next_id: u64,
+    items: Vec<Queued>,
+}
+
+/// A session's queued prompts, in the order they will be sent.
+#[derive(Default)]
+pub struct Queue {
+    inner: Mutex<Items>,
+}
+
+impl Queue {
+    pub fn list(&self) -> Vec<Queued> {
+        self.inner.lock().unwrap().items.clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.lock().unwrap().items.is_empty()
+    }
+
+    /// Add `text` to the end of the queue.
+    pub fn push(&self, text: String, snippet: bool) -> Result<Queued, String> {
+        if text.trim().is_empty() {
+            return Err("Prompt is empty".to_string());
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.items.len() >= MAX_QUEUED {
+            return Err(format!("The queue already holds {} prompts", MAX_QUEUED));
+        }
+        inner.next_id += 1;
+        let item = Queued {
+            id: inner.next_id,
+            text,
+            snippet,
+            queued: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        };
+        inner.items.push(item.clone());
+        Ok(item)
+    }
+
+    /// Change the text of prompt `id` and/or move it to `position`, counted
+    /// from the front and clamped to the end. `None` if there is no such
+    /// prompt (any more).
+    pub fn update(
+        &self,
+        id: u64,
+        text: Option<String>,
+        position: Option<usize>,
+    ) -> Result<Option<Queued>, String> {
+        if text.is_none() && position.is_none() {
+            return Err("Give a new text or position".to_string());
+        }
+        if text.as_ref().is_some_and(|t| t.trim().is_empty()) {
+            return Err("Prompt is empty".to_string());
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let Some(index) = inner.items.iter().position(|item| item.id == id) else {
+            return Ok(None);
+        };
+        let mut item = inner.items.remove(index);
+        if let Some(text) = text {
+            item.text = text;
+        }
+        let position = position.unwrap_or(index).min(inner.items.len());
+        inner.items.insert(position, item.clone());
+        Ok(Some(item))
+    }
+
+    /// Cancel prompt `id`.
+    pub fn remove(&self, id: u64) -> Option<Queued> {
+        let mut inner = self.inner.lock().unwrap();
+        let index = inner.items.iter().position(|item| item.id == id)?;
+        Some(inner.items.remove(index))
+    }
+
+    /// Take the next prompt to send.
+    pub fn pop(&self) -> Option<Queued> {
+        let mut inner = self.inner.lock().unwrap();
+        (!inner.items.is_empty()).then(|| inner.items.remove(0))
+    }
+
+    /// Put `item` back at the front, as it was before [`Queue::pop`].
+    pub fn requeue(&self, item: Queued) {
+        self.inner.lock().unwrap().items.insert(0, item);
+    }
+
+    /// Send the next prompt with `send`. It goes back to the front of the
+    /// queue if that fails, so a prompt is only lost once it was typed.
+    pub async fn send_next<F, Fut>(&self, send: F) -> Option<(Queued, Result<(), String>)>
+    where
+        F: FnOnce(Queued) -> Fut,
+        Fut: Future<Output = Result<(), String>>,
+    {
+        let item = self.pop()?;
+        let result = send(item.clone()).await;
+        if result.is_err() {
+            self.requeue(item.clone());
+        }
+        Some((item, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(queue: &Queue) -> Vec<String> {
+        queue.list().into_iter().map(|item| item.text).collect()
+    }
+
+    #[test]
+    fn test_queue() {
+        let queue = Queue::default();
+        let first = queue.push("one".to_string(), false).unwrap();
+        queue.push("two".to_string(), false).unwrap();
+        let third = queue.push("three".to_string(), false).unwrap();
+        assert!(queue.push("  ".to_string(), false).is_err());
+
+        queue.update(third.id, None, Some(0)).unwrap();
+        assert_eq!(texts(&queue), ["three", "one", "two"]);
+        queue
+            .update(first.id, Some("uno".to_string()), Some(99))
+            .unwrap();
+        assert_eq!(texts(&queue), ["three", "two", "uno"]);
+        assert_eq!(queue.update(42, None, Some(0)), Ok(None));
+
+        assert_eq!(
+            queue.remove(third.id).map(|item| item.text).as_deref(),
+            Some("three")
+        );
+        assert_eq!(queue.remove(third.id), None);
+        assert_eq!(queue.pop().map(|item| item.text).as_deref(), Some("two"));
+        assert_eq!(queue.pop().map(|item| item.id), Some(first.id));
+        assert!(queue.pop().is_none());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_update_and_remove() {
+        let queue = Queue::default();
+        let ids: Vec<u64> = ["a", "b", "c"]
+            .iter()
+            .map(|text| queue.push(text.to_string(), false).unwrap().id)
+            .collect();
+
+        // Moving to where it is, to the end and past it
+        queue.update(ids[1], None, Some(1)).unwrap();
+        assert_eq!(texts(&queue), ["a", "b", "c"]);
+        queue.update(ids[0], None, Some(2)).unwrap();
+        assert_eq!(texts(&queue), ["b", "c", "a"]);
+        queue.update(ids[1], None, Some(usize::MAX)).unwrap();
+        assert_eq!(texts(&queue), ["c", "a", "b"]);
+
+        // Editing keeps the place and the id
+        let edited = queue
+            .update(ids[0], Some("A".to_string()), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(edited.id, ids[0]);
+        assert_eq!(texts(&queue), ["c", "A", "b"]);
+
+        // Bad changes are refused before the id is looked up
+        assert!(
+            queue
+                .update(ids[0], Some(" \n".to_string()), Some(0))
+                .is_err()
+        );
+        assert!(queue.update(ids[0], None, None).is_err());
+        assert!(queue.update(42, None, None).is_err());
+        assert_eq!(texts(&queue), ["c", "A", "b"]);
+
+        assert!(queue.remove(ids[0]).is_some());
+        assert_eq!(queue.update(ids[0], None, Some(0)), Ok(None));
+        assert_eq!(texts(&queue), ["c", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_send_next() {
+        let queue = Queue::default();
+        assert!(queue.send_next(|_| async { Ok(()) }).await.is_none());
+        let first = queue.push("one".to_string(), false).unwrap();
+        queue.push("two".to_string(), true).unwrap();
+
+        // A prompt that could not be typed stays next, unchanged
+        let (sent, result) = queue
+            .send_next(|item| async move { Err(format!("no window for {}", item.text)) })
+            .await
+            .unwrap();
+        assert_eq!(sent, first);
+        assert_eq!(result, Err("no window for one".to_string()));
+        assert_eq!(queue.list()[0], first);
+        assert_eq!(texts(&queue), ["one", "two"]);
+
+        let (sent, result) = queue.send_next(|_| async { Ok(()) }).await.unwrap();
+        assert_eq!((sent.id, result), (first.id, Ok(())));
+        assert_eq!(texts(&queue), ["two"]);
+        assert!(queue.list()[0].snippet);
+    }
+
+    #[test]
+    fn test_queue_limit() {
+        let queue = Queue::default();
+        for i in 0..MAX_QUEUED {
+            queue.push(format!("prompt {}", i), false).unwrap();
+        }
+        assert!(queue.push("one too many".to_string(), false).is_err());
+        assert_eq!(queue.list().len(), MAX_QUEUED);
+        // Room again once one is sent
+        queue.pop().unwrap();
+        let last = queue.push("fits".to_string(), false).unwrap();
+        assert_eq!(queue.list().last(), Some(&last));
+    }
+}
@@ -0,0 +1,135 @@
+//! Just enough of the RFB (VNC) protocol to tell what a client sends: the
+//! user's keys, pointer and clipboard, or requests for screen updates that
+//! noVNC keeps making with nobody in front of it.
+
+/// Handshake bytes a client sends to Xvnc with `-SecurityTypes None`:
+/// ProtocolVersion, the chosen security type and ClientInit.
+const HANDSHAKE: usize = 12 + 1 + 1;
+
+const SET_PIXEL_FORMAT: u8 = 0;
+const SET_ENCODINGS: u8 = 2;
+const FRAMEBUFFER_UPDATE_REQUEST: u8 = 3;
+const KEY_EVENT: u8 = 4;
+const POINTER_EVENT: u8 = 5;
+const CLIENT_CUT_TEXT: u8 = 6;
+const ENABLE_CONTINUOUS_UPDATES: u8 = 150;
+const FENCE: u8 = 248;
+const SET_DESKTOP_SIZE: u8 = 251;
+/// Extended key events are its only client message.
+const QEMU: u8 = 255;
+
+/// Follows the client-to-server half of a connection.
+pub struct ClientStream {
+    /// Handshake bytes still to come.
+    handshake: usize,
+    /// Start of a message not complete yet.
+    pending: Vec<u8>,
+    /// Set on a message type we do not know the length of, after which
+    /// messages can no longer be told apart.
+    lost: bool,
+}
+
+impl Default for ClientStream {
+    fn default() -> Self {
+        ClientStream {
+            handshake: HANDSHAKE,
+            pending: Vec::new(),
+            lost: false,
+        }
+    }
+}
+
+/// Length of the message at the start of `data`, `Some(None)` when more
+/// bytes are needed to tell, `None` for an unknown type.
+fn message_len(data: &[u8]) -> Option<Option<usize>> {
+    let u16_at =
+        |i: usize| (data.len() >= i + 2).then(|| u16::from_be_bytes([data[i], data[i + 1]]));
+    let u32_at = |i: usize| {
+        (data.len() >= i + 4)
+            .then(|| u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]))
+    };
+    Some(match data[0] {
+        SET_PIXEL_FORMAT => Some(20),
+        SET_ENCODINGS => u16_at(2).map(|n| 4 + 4 * n as usize),
+        FRAMEBUFFER_UPDATE_REQUEST | ENABLE_CONTINUOUS_UPDATES => Some(10),
+        KEY_EVENT => Some(8),
+        POINTER_EVENT => Some(6),
+        CLIENT_CUT_TEXT => u32_at(4).map(|len| 8 + len as usize),
+        FENCE => data.get(8).map(|&len| 9 + len as usize),
+        SET_DESKTOP_SIZE => data.get(6).map(|&screens| 8 + 16 * screens as usize),
+        QEMU => Some(12),
+        _ => return None,
+    })
+}
+
+impl ClientStream {
+    /// Take `data` the client sent; true when it holds user input.
+    pub fn feed(&mut self, mut data: &[u8]) -> bool {
+        let skip = self.handshake.min(data.len());
+        self.handshake -= skip;
+        data = &data[skip..];
+        if self.lost {
+            // Screen update requests are the only thing sent without a user
+            return !data.is_empty()
+                && !data
+                    .chunks(10)
+                    .all(|chunk| chunk.len() == 10 && chunk[0] == FRAMEBUFFER_UPDATE_REQUEST);
+        }
+        self.pending.extend_from_slice(data);
+        let mut input = false;
+        while !self.pending.is_empty() {
+            let Some(len) = message_len(&self.pending) else {
+                self.lost = true;
+                self.pending.clear();
+                return true;
+            };
+            let Some(len) = len.filter(|&len| len <= self.pending.len()) else {
+                break;
+            };
+            input |= matches!(
+                self.pending[0],
+                KEY_EVENT | POINTER_EVENT | CLIENT_CUT_TEXT | QEMU
+            );
+            self.pending.drain(..len);
+        }
+        input
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPDATE_REQUEST: [u8; 10] = [3, 1, 0, 0, 0, 0, 4, 0, 3, 0];
+
+    #[test]
+    fn test_client_stream() {
+        let mut stream = ClientStream::default();
+        let mut handshake = b"RFB 003.008\n".to_vec();
+        handshake.extend([1, 1]);
+        assert!(!stream.feed(&handshake));
+
+        let mut setup = vec![0; 20];
+        setup.extend([2, 0, 0, 2, 0, 0, 0, 7, 0xff, 0xff, 0xfe, 0xe9]);
+        setup.extend(UPDATE_REQUEST);
+        assert!(!stream.feed(&setup));
+        assert!(!stream.feed(&UPDATE_REQUEST.repeat(3)));
+
+        // Messages split across and sharing frames
+        assert!(!stream.feed(&[5, 0, 1]));
+        assert!(stream.feed(&[0, 2, 0]));
+        let mut key = vec![4, 1, 0, 0, 0, 0, 0, 0x61];
+        key.extend(UPDATE_REQUEST);
+        assert!(stream.feed(&key));
+        assert!(stream.feed(&[6, 0, 0, 0, 0, 0, 0, 2, b'h', b'i']));
+        assert!(!stream.feed(&UPDATE_REQUEST));
+        assert!(!stream.feed(&[248, 0, 0, 0, 0, 0, 0, 3, 2, 9, 9]));
+        assert!(stream.feed(&[255, 0, 0, 1, 0, 0, 0, 0x61, 0, 0, 0, 0x1e]));
+
+        // An unknown message counts as input, and from then on everything
+        // but update requests does
+        assert!(stream.feed(&[200, 1, 2, 3]));
+        assert!(!stream.feed(&UPDATE_REQUEST.repeat(2)));
+        assert!(stream.feed(&[5, 0, 0, 1, 0, 1]));
+    }
+}
@@ -0,0 +1,476 @@
+//! Opt-in Landlock sandbox for the agent.
+//!
+//! `vnccc agent-loop` builds the ruleset once and each agent restricts itself
+//! to it between fork and exec, so the loop and the terminal keep full access.
+//! Landlock does not log denials, so `vnccc sandbox-probe` applies the same
+//! ruleset to itself and reports which reads and writes it is refused.
+
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::config;
+
+const EXECUTE: u64 = 1 << 0;
+const WRITE_FILE: u64 = 1 << 1;
+const READ_FILE: u64 = 1 << 2;
+const READ_DIR: u64 = 1 << 3;
+/// Everything up to MAKE_SYM, the rights of Landlock ABI 1.
+const ABI_1_ALL: u64 = (1 << 13) - 1;
+const REFER: u64 = 1 << 13;
+const TRUNCATE: u64 = 1 << 14;
+const IOCTL_DEV: u64 = 1 << 15;
+/// The only rights a rule on a single file may grant.
+const FILE_RIGHTS: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE | IOCTL_DEV;
+const READ_ONLY: u64 = EXECUTE | READ_FILE | READ_DIR;
+
+const CREATE_RULESET_VERSION: u32 = 1;
+const RULE_PATH_BENEATH: libc::c_int = 1;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Directories the agent may use besides the workspace and its config.
+const DEFAULT_READ_WRITE: &[&str] = &["/dev"];
+/// System and toolchain directories the agent may read and execute from.
+const DEFAULT_READ_ONLY: &[&str] = &[
+    "/usr",
+    "/bin",
+    "/sbin",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/etc",
+    "/opt",
+    "/run",
+    "/proc",
+    "/sys",
+    "~/.cargo",
+    "~/.rustup",
+    "~/.local",
+    "~/.nvm",
+    "~/.npm-global",
+    "~/go",
+];
+
+/// Config files and directories each agent keeps its login and state in,
+/// matching what entrypoint.sh links from the host.
+fn agent_config_paths(agent: &str) -> &'static [&'static str] {
+    match agent {
+        "claude" => &["~/.claude", "~/.claude.json", "~/.config/claude"],
+        "gemini" => &["~/.gemini"],
+        "kilocode" => &["~/.kilocode"],
+        "opencode" => &["~/.config/opencode", "~/.opencode"],
+        "crush" => &["~/.config/crush"],
+        "codex" => &["~/.codex"],
+        "qwen-code" => &["~/.qwen"],
+        _ => &[],
+    }
+}
+
+/// Places worth checking in the diagnostic even when no rule mentions them.
+const SENSITIVE: &[&str] = &["~", "~/.ssh", "~/.config/gh", "/tmp", "/root"];
+
+/// Expand a leading `~` to `$HOME`.
+fn expand_home(path: &str, home: Option<&Path>) -> PathBuf {
+    match (path.strip_prefix('~'), home) {
+        (Some(""), Some(home)) => home.to_path_buf(),
+        (Some(rest), Some(home)) if rest.starts_with('/') => home.join(&rest[1..]),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Paths the sandboxed agent may reach.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rules {
+    pub read_write: Vec<PathBuf>,
+    pub read_only: Vec<PathBuf>,
+}
+
+impl Rules {
+    /// Rules for an agent working in `repo_path`: the defaults, unless the
+    /// config turns them off, plus the configured paths.
+    pub fn for_session(sandbox: &config::Sandbox, repo_path: &str, agent: &str) -> Rules {
+        let home = env::var_os("HOME").map(PathBuf::from);
+        let expand = |paths: &[&str]| -> Vec<PathBuf> {
+            paths
+                .iter()
+                .map(|p| expand_home(p, home.as_deref()))
+                .collect()
+        };
+        let mut rules = Rules::default();
+        if sandbox.defaults {
+            rules.read_write.push(PathBuf::from(repo_path));
+            rules.read_write.extend(expand(agent_config_paths(agent)));
+            if agent == "claude"
+                && let Some(dir) = env::var_os("CLAUDE_CONFIG_DIR")
+            {
+                rules.read_write.push(PathBuf::from(dir));
+            }
+            rules.read_write.extend(expand(DEFAULT_READ_WRITE));
+            rules.read_only.extend(expand(DEFAULT_READ_ONLY));
+        }
+        let configured = |paths: &[String]| -> Vec<PathBuf> {
+            paths
+                .iter()
+                .map(|p| expand_home(p, home.as_deref()))
+                .collect()
+        };
+        rules.read_write.extend(configured(&sandbox.read_write));
+        rules.read_only.extend(configured(&sandbox.read_only));
+        rules
+    }
+
+    /// `--sandbox-rw`/`--sandbox-ro` arguments for `agent-loop` and `sandbox-probe`.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for (flag, paths) in [
+            ("--sandbox-rw", &self.read_write),
+            ("--sandbox-ro", &self.read_only),
+        ] {
+            for path in paths {
+                args.push(flag.to_string());
+                args.push(path.to_string_lossy().into_owned());
+            }
+        }
+        args
+    }
+
+    /// Take one `--sandbox-rw`/`--sandbox-ro` argument, returning false for
+    /// any other flag.
+    pub fn parse_flag(&mut self, flag: &str, value: &str) -> bool {
+        match flag {
+            "--sandbox-rw" => self.read_write.push(PathBuf::from(value)),
+            "--sandbox-ro" => self.read_only.push(PathBuf::from(value)),
+            _ => return false,
+        }
+        true
+    }
+
+    /// The rule paths and the sensitive defaults, for the diagnostic.
+    pub fn probe_paths(&self) -> Vec<PathBuf> {
+        let home = env::var_os("HOME").map(PathBuf::from);
+        let sensitive = SENSITIVE.iter().map(|p| expand_home(p, home.as_deref()));
+        let mut paths: Vec<PathBuf> = Vec::new();
+        for path in self
+            .read_write
+            .iter()
+            .chain(&self.read_only)
+            .cloned()
+            .chain(sensitive)
+        {
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        paths
+    }
+}
+
+/// Landlock ABI version the kernel supports, or `None` without Landlock.
+pub fn abi_version() -> Option<i64> {
+    // SAFETY: the version query takes no attribute
+    let version = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0usize,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    (version > 0).then_some(version)
+}
+
+/// Rights handled by a ruleset on `abi`; anything handled and not granted
+/// by a rule is denied.
+fn handled_access(abi: i64) -> u64 {
+    let mut access = ABI_1_ALL;
+    if abi >= 2 {
+        access |= REFER;
+    }
+    if abi >= 3 {
+        access |= TRUNCATE;
+    }
+    if abi >= 5 {
+        access |= IOCTL_DEV;
+    }
+    access
+}
+
+/// A built Landlock ruleset, ready to be applied.
+pub struct Ruleset {
+    fd: OwnedFd,
+    /// Rule paths that do not exist and were left out.
+    pub skipped: Vec<PathBuf>,
+}
+
+impl Ruleset {
+    pub fn build(rules: &Rules) -> Result<Ruleset, String> {
+        let abi = abi_version().ok_or("This kernel does not support Landlock")?;
+        let handled = handled_access(abi);
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        // SAFETY: attr is a valid ruleset attribute of the given size
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0u32,
+            )
+        };
+        if fd < 0 {
+            return Err(format!(
+                "Failed to create Landlock ruleset: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        // SAFETY: the syscall returned a new file descriptor we now own
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+        let mut ruleset = Ruleset {
+            fd,
+            skipped: Vec::new(),
+        };
+        for (paths, access) in [(&rules.read_only, READ_ONLY), (&rules.read_write, handled)] {
+            for path in paths {
+                ruleset.add_rule(path, access & handled)?;
+            }
+        }
+        Ok(ruleset)
+    }
+
+    fn add_rule(&mut self, path: &Path, access: u64) -> Result<(), String> {
+        let file = match fs::File::options()
+            .read(true)
+            .custom_flags(libc::O_PATH)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.skipped.push(path.to_path_buf());
+                return Ok(());
+            }
+            Err(e) => return Err(format!("Failed to open {}: {}", path.display(), e)),
+        };
+        let is_dir = fs::metadata(path).map(|m| m.is_dir()).unwrap_or(false);
+        let attr = PathBeneathAttr {
+            allowed_access: if is_dir { access } else { access & FILE_RIGHTS },
+            parent_fd: file.as_raw_fd(),
+        };
+        // SAFETY: attr is a valid path-beneath rule for an open fd
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                self.fd.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0u32,
+            )
+        };
+        if result != 0 {
+            return Err(format!(
+                "Failed to add Landlock rule for {}: {}",
+                path.display(),
+                io::Error::last_os_error()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for Ruleset {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Restrict the calling thread, and everything it execs, to the ruleset in
+/// `fd`. Only makes syscalls, so it is safe to call between fork and exec.
+pub fn restrict_self(fd: RawFd) -> io::Result<()> {
+    // SAFETY: plain syscalls; a bad fd is reported as an error
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::syscall(libc::SYS_landlock_restrict_self, fd, 0u32) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Whether one kind of access to a path works under the sandbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Allowed,
+    Denied,
+    Missing,
+    /// Failed for a reason other than the sandbox, e.g. file permissions.
+    Error,
+}
+
+impl Access {
+    fn of(result: io::Result<()>) -> Access {
+        match result {
+            Ok(()) => Access::Allowed,
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Access::Denied,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Access::Missing,
+            Err(_) => Access::Error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Probe {
+    pub path: PathBuf,
+    pub read: Access,
+    pub write: Access,
+}
+
+/// Try to read and write `path`. Writing a directory creates and removes a
+/// scratch file; writing a file opens it for appending without changing it.
+fn probe(path: &Path) -> Probe {
+    let is_dir = fs::metadata(path).map(|m| m.is_dir());
+    let (read, write) = match is_dir {
+        Err(e) => (Access::of(Err(e)), Access::Missing),
+        Ok(true) => {
+            let scratch = path.join(format!(".vncaa-probe-{}", std::process::id()));
+            (
+                Access::of(fs::read_dir(path).map(|_| ())),
+                Access::of(fs::write(&scratch, b"").and_then(|()| fs::remove_file(&scratch))),
+            )
+        }
+        Ok(false) => (
+            Access::of(fs::File::open(path).map(|_| ())),
+            Access::of(fs::File::options().append(true).open(path).map(|_| ())),
+        ),
+    };
+    Probe {
+        path: path.to_path_buf(),
+        read,
+        write,
+    }
+}
+
+/// Entry point for `vnccc sandbox-probe [--sandbox-rw P]... [--sandbox-ro P]...
+/// -- <path>...`: restrict this process to the rules and print what each path
+/// allows as JSON. Never returns.
+pub fn run_probe(args: &[String]) -> ! {
+    let mut rules = Rules::default();
+    let mut rest = args;
+    while let [flag, value, tail @ ..] = rest
+        && rules.parse_flag(flag, value)
+    {
+        rest = tail;
+    }
+    let paths = match rest {
+        [dashes, paths @ ..] if dashes == "--" => paths,
+        _ => {
+            eprintln!(
+                "Usage: vnccc sandbox-probe [--sandbox-rw PATH]... [--sandbox-ro PATH]... -- <path>..."
+            );
+            std::process::exit(2);
+        }
+    };
+    let ruleset = Ruleset::build(&rules).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    if let Err(e) = restrict_self(ruleset.as_raw_fd()) {
+        eprintln!("Failed to apply Landlock ruleset: {}", e);
+        std::process::exit(1);
+    }
+    let probes: Vec<Probe> = paths.iter().map(|p| probe(Path::new(p))).collect();
+    println!("{}", serde_json::to_string(&probes).unwrap());
+    std::process::exit(0);
+}
+
+/// Run `vnccc sandbox-probe` for `rules` over `paths`.
+pub fn probe_with(rules: &Rules, paths: &[PathBuf]) -> Result<Vec<Probe>, String> {
+    let exe = env::current_exe().map_err(|e| format!("Cannot locate vnccc: {}", e))?;
+    let output = std::process::Command::new(exe)
+        .arg("sandbox-probe")
+        .args(rules.args())
+        .arg("--")
+        .args(paths)
+        .output()
+        .map_err(|e| format!("Failed to run sandbox probe: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    serde_json::from_slice(&output.stdout).map_err(|e| format!("Invalid probe output: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_home() {
+        let home = Some(Path::new("/home/dev"));
+        assert_eq!(expand_home("~", home), PathBuf::from("/home/dev"));
+        assert_eq!(expand_home("~/.ssh", home), PathBuf::from("/home/dev/.ssh"));
+        assert_eq!(expand_home("/usr", home), PathBuf::from("/usr"));
+        assert_eq!(expand_home("~other", home), PathBuf::from("~other"));
+        assert_eq!(expand_home("~/.ssh", None), PathBuf::from("~/.ssh"));
+    }
+
+    #[test]
+    fn test_rules_for_session() {
+        let sandbox = config::Sandbox {
+            enabled: true,
+            read_only: vec!["/srv/data".to_string()],
+            ..config::Sandbox::default()
+        };
+        let rules = Rules::for_session(&sandbox, "/repo", "gemini");
+        assert_eq!(rules.read_write[0], PathBuf::from("/repo"));
+        assert!(rules.read_write.iter().any(|p| p.ends_with(".gemini")));
+        assert!(rules.read_only.contains(&PathBuf::from("/usr")));
+        assert!(rules.read_only.contains(&PathBuf::from("/srv/data")));
+
+        let bare = config::Sandbox {
+            defaults: false,
+            ..sandbox
+        };
+        let rules = Rules::for_session(&bare, "/repo", "gemini");
+        assert!(rules.read_write.is_empty());
+        assert_eq!(rules.read_only, vec![PathBuf::from("/srv/data")]);
+    }
+
+    #[test]
+    fn test_rules_args_round_trip() {
+        let rules = Rules {
+            read_write: vec![PathBuf::from("/repo")],
+            read_only: vec![PathBuf::from("/usr"), PathBuf::from("/etc")],
+        };
+        let args = rules.args();
+        let mut parsed = Rules::default();
+        for pair in args.chunks(2) {
+            assert!(parsed.parse_flag(&pair[0], &pair[1]));
+        }
+        assert_eq!(parsed, rules);
+        assert!(!parsed.parse_flag("--cgroup", "/sys/fs/cgroup"));
+    }
+
+    #[test]
+    fn test_handled_access() {
+        assert_eq!(handled_access(1), ABI_1_ALL);
+        assert_eq!(handled_access(3), ABI_1_ALL | REFER | TRUNCATE);
+        assert_eq!(handled_access(5) & IOCTL_DEV, IOCTL_DEV);
+    }
+}
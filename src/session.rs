@@ -0,0 +1,1475 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::activity::{AgentState, Observation, Recognizer, Tracker, Watch};
+use crate::agent_loop::{self, shell_quote};
+use crate::checkpoint::{self, Checkpoint};
+use crate::config::{self, EmergencyStop, IdleAction, Limits};
+use crate::display::{self, Geometry};
+use crate::git;
+use crate::limits::{self, LimitHits, Rlimit};
+use crate::notify::{Event, Notification, Notifier};
+use crate::procfs::{self, CpuSample, ProcessUsage};
+use crate::queue::Queue;
+use crate::sandbox::{self, Probe, Rules};
+use crate::terminal::{LaunchOptions, TerminalBackend, TerminalContext, TerminalSettings};
+use crate::workspace::Worktree;
+
+/// What a new session runs and where.
+pub struct SessionOptions {
+    /// Session id used in URLs; the next free number when unset.
+    pub id: Option<String>,
+    pub repo_path: String,
+    pub geometry: Geometry,
+    pub terminal: Arc<dyn TerminalBackend>,
+    /// Agent CLI to launch, overriding the AGENT env var.
+    pub agent: Option<String>,
+    pub limits: Limits,
+    pub sandbox: config::Sandbox,
+    pub checkpoints: config::Checkpoints,
+    pub activity: config::Activity,
+    pub notifier: Arc<Notifier>,
+    /// Worktree made for this session, torn down with it.
+    pub worktree: Option<Worktree>,
+}
+
+/// Whether a session's processes are running or were put to sleep by the
+/// idle timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Power {
+    Awake,
+    /// Agent, terminal and X server are stopped with SIGSTOP.
+    Suspended,
+    /// Terminal and X server were shut down.
+    Stopped,
+    /// Coming back from either of the above.
+    Waking,
+}
+
+impl Power {
+    pub fn name(self) -> &'static str {
+        match self {
+            Power::Awake => "awake",
+            Power::Suspended => "suspended",
+            Power::Stopped => "stopped",
+            Power::Waking => "waking",
+        }
+    }
+}
+
+/// One display, terminal and agent working in one repo.
+pub struct Session {
+    pub id: String,
+    /// Workspace the agent runs in; changed by [`Session::switch_repo`].
+    repo_path: RwLock<String>,
+    pub agent: Option<String>,
+    /// Worktree made for this session, torn down with it.
+    pub worktree: Option<Worktree>,
+    pub display: u32,
+    pub terminal: Arc<dyn TerminalBackend>,
+    /// Pid of the running terminal emulator, 0 while none is running.
+    pub terminal_pid: AtomicU32,
+    /// Appearance last requested through the API, reused on relaunch.
+    pub settings: Mutex<TerminalSettings>,
+    /// Current screen size. Held for the duration of a resize.
+    pub geometry: Mutex<Geometry>,
+    /// Whether the agent's process group was stopped with SIGSTOP.
+    pub paused: AtomicBool,
+    /// Set once the session is being destroyed so the terminal is not relaunched.
+    stopping: AtomicBool,
+    /// Xvnc and the window manager, killed with the session.
+    processes: Mutex<Vec<Child>>,
+    /// Last prompt, VNC input or page activity, for the idle timeout.
+    last_activity: Mutex<Instant>,
+    power: Mutex<Power>,
+    /// Held while waking so concurrent requests wait for the same wake-up.
+    wake_lock: tokio::sync::Mutex<()>,
+    /// CPU times from the last process walk, for CPU %.
+    cpu_sample: Mutex<CpuSample>,
+    /// cgroup the agent loop joins, when cgroup v2 is delegated to us.
+    cgroup: Option<PathBuf>,
+    /// rlimits the agent loop sets before starting the agent.
+    rlimits: Vec<Rlimit>,
+    /// Limit hits as last logged.
+    limit_hits: Mutex<LimitHits>,
+    /// Landlock rules for the agent, when the sandbox is enabled. They follow
+    /// the workspace.
+    sandbox: RwLock<Option<Rules>>,
+    sandbox_config: config::Sandbox,
+    checkpoints: config::Checkpoints,
+    /// Keeps git operations on the workspace from interleaving.
+    checkpoint_lock: tokio::sync::Mutex<()>,
+    /// Prompts to send once the agent is idle.
+    pub queue: Queue,
+    /// Infers the agent's state from the terminal, and the CPU times it
+    /// needs, apart from the ones the API shows.
+    activity: Mutex<(Tracker, CpuSample)>,
+    /// What the agent was last seen doing.
+    agent_state: tokio::sync::watch::Sender<AgentState>,
+    /// What the agent state was last worked out from.
+    watch: Mutex<Option<Watch>>,
+    notifier: Arc<Notifier>,
+    /// When the agent last got busy after being idle.
+    busy_since: Mutex<Option<Instant>>,
+    /// Set while the terminal or agent is being restarted on purpose, so the
+    /// exit is not taken for a crash.
+    restarting_terminal: AtomicBool,
+    restarting_agent: AtomicBool,
+    /// Agent process group last seen by [`Session::check_activity`].
+    seen_pgid: Mutex<Option<i32>>,
+    /// Recent crashes of the terminal or agent, for crash loop detection.
+    crashes: Mutex<Vec<Instant>>,
+}
+
+impl Session {
+    /// A session on `display` with its display `processes` already running,
+    /// but no terminal yet.
+    pub(crate) fn new(
+        id: String,
+        display: u32,
+        processes: Vec<Child>,
+        cgroup: Option<PathBuf>,
+        opts: SessionOptions,
+    ) -> Session {
+        let rlimits = limits::rlimits(&opts.limits, cgroup.is_some());
+        let sandbox = sandbox_rules(&opts.sandbox, &opts.repo_path, opts.agent.as_deref());
+        let recognizer = Recognizer::new(&agent_name(opts.agent.as_deref()), &opts.activity);
+        let tracker = Tracker::new(recognizer, &opts.activity);
+
+        Session {
+            id,
+            repo_path: RwLock::new(opts.repo_path),
+            agent: opts.agent,
+            worktree: opts.worktree,
+            display,
+            terminal: opts.terminal,
+            terminal_pid: AtomicU32::new(0),
+            settings: Mutex::new(TerminalSettings::default()),
+            geometry: Mutex::new(opts.geometry),
+            paused: AtomicBool::new(false),
+            stopping: AtomicBool::new(false),
+            processes: Mutex::new(processes),
+            last_activity: Mutex::new(Instant::now()),
+            power: Mutex::new(Power::Awake),
+            wake_lock: tokio::sync::Mutex::new(()),
+            cpu_sample: Mutex::new(CpuSample::default()),
+            cgroup,
+            rlimits,
+            limit_hits: Mutex::new(LimitHits::default()),
+            sandbox: RwLock::new(sandbox),
+            sandbox_config: opts.sandbox,
+            checkpoints: opts.checkpoints,
+            checkpoint_lock: tokio::sync::Mutex::new(()),
+            queue: Queue::default(),
+            activity: Mutex::new((tracker, CpuSample::default())),
+            agent_state: tokio::sync::watch::Sender::new(AgentState::Offline),
+            watch: Mutex::new(None),
+            notifier: opts.notifier,
+            busy_since: Mutex::new(None),
+            restarting_terminal: AtomicBool::new(false),
+            restarting_agent: AtomicBool::new(false),
+            seen_pgid: Mutex::new(None),
+            crashes: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn repo_path(&self) -> String {
+        self.repo_path.read().unwrap().clone()
+    }
+
+    pub fn sandbox(&self) -> Option<Rules> {
+        self.sandbox.read().unwrap().clone()
+    }
+
+    /// Point the session at another workspace and relaunch the terminal,
+    /// and with it the agent, there. Not for sessions in a worktree of their
+    /// own, which is merged or deleted with them.
+    pub async fn switch_repo(&self, repo_path: String) -> Result<(), String> {
+        self.check_switch()?;
+        if !Path::new(&repo_path).is_dir() {
+            return Err(format!("Repo path '{}' is not a directory", repo_path));
+        }
+        self.wake().await?;
+        // Let running git operations on the old workspace finish first
+        let _guard = self.checkpoint_lock.lock().await;
+        println!("Switching session {} to {}", self.id, repo_path);
+        *self.sandbox.write().unwrap() =
+            sandbox_rules(&self.sandbox_config, &repo_path, self.agent.as_deref());
+        *self.repo_path.write().unwrap() = repo_path;
+        let _ = self.signal_agent(libc::SIGCONT);
+        self.paused.store(false, Ordering::SeqCst);
+        self.restart_terminal();
+        Ok(())
+    }
+
+    /// Whether [`Session::switch_repo`] may move this session elsewhere.
+    pub fn check_switch(&self) -> Result<(), String> {
+        match &self.worktree {
+            Some(worktree) => Err(format!(
+                "Session {} works in its own worktree on branch {}; stop it first",
+                self.id, worktree.branch
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn terminal_context(&self) -> TerminalContext {
+        let pid = self.terminal_pid.load(Ordering::SeqCst);
+        TerminalContext {
+            display: self.display,
+            pid: (pid != 0).then_some(pid),
+            settings: self.settings(),
+        }
+    }
+
+    pub fn settings(&self) -> TerminalSettings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    pub fn vnc_port(&self) -> u16 {
+        5900 + self.display as u16
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.terminal_pid.load(Ordering::SeqCst) != 0
+    }
+
+    /// Ask the running terminal to exit; the monitor task relaunches it.
+    pub fn restart_terminal(&self) {
+        let pid = self.terminal_pid.load(Ordering::SeqCst);
+        if pid == 0 {
+            return;
+        }
+        println!("Restarting terminal (pid={})", pid);
+        self.restarting_terminal.store(true, Ordering::SeqCst);
+        // SAFETY: kill has no memory-safety preconditions
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+    }
+
+    /// Where `vnccc agent-loop` records the agent's process group.
+    pub fn control_dir(&self) -> PathBuf {
+        control_dir(self.display)
+    }
+
+    /// Process group of the running agent, if any.
+    pub fn agent_pgid(&self) -> Option<i32> {
+        agent_loop::read_pgid(&self.control_dir())
+    }
+
+    /// Send `signal` to the agent's whole process group.
+    fn signal_agent(&self, signal: libc::c_int) -> Result<i32, String> {
+        let pgid = self
+            .agent_pgid()
+            .ok_or_else(|| format!("No agent running in session {}", self.id))?;
+        // SAFETY: kill has no memory-safety preconditions
+        if unsafe { libc::kill(-pgid, signal) } != 0 {
+            return Err(format!(
+                "Failed to signal agent group {}: {}",
+                pgid,
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(pgid)
+    }
+
+    /// Terminate the agent; the agent loop inside the terminal starts it again.
+    pub fn restart_agent(&self) -> Result<i32, String> {
+        let pgid = self.signal_agent(libc::SIGTERM)?;
+        self.restarting_agent.store(true, Ordering::SeqCst);
+        // A stopped group only acts on SIGTERM once continued
+        let _ = self.signal_agent(libc::SIGCONT);
+        self.paused.store(false, Ordering::SeqCst);
+        println!("Restarting agent (pgid={})", pgid);
+        Ok(pgid)
+    }
+
+    pub fn pause_agent(&self) -> Result<i32, String> {
+        let pgid = self.signal_agent(libc::SIGSTOP)?;
+        self.paused.store(true, Ordering::SeqCst);
+        println!("Paused agent (pgid={})", pgid);
+        Ok(pgid)
+    }
+
+    /// Continue a paused agent, or let the agent loop start one again after
+    /// an emergency stop.
+    pub fn resume_agent(&self) -> Result<String, String> {
+        if self.is_held() {
+            fs::remove_file(self.control_dir().join(agent_loop::HOLD_FILE))
+                .map_err(|e| format!("Failed to release agent hold: {}", e))?;
+            self.restarting_agent.store(true, Ordering::SeqCst);
+            println!("Released agent hold for session {}", self.id);
+            return Ok(format!("Starting agent in session {}", self.id));
+        }
+        let pgid = self.signal_agent(libc::SIGCONT)?;
+        self.paused.store(false, Ordering::SeqCst);
+        println!("Resumed agent (pgid={})", pgid);
+        Ok(format!("Resumed agent (process group {})", pgid))
+    }
+
+    /// Whether an emergency stop is keeping the agent from being restarted.
+    pub fn is_held(&self) -> bool {
+        self.control_dir().join(agent_loop::HOLD_FILE).exists()
+    }
+
+    /// Kill the agent and everything it started, and keep the agent loop from
+    /// starting it again until [`Session::resume_agent`]. Escalates from
+    /// SIGINT to SIGTERM to SIGKILL, waiting the configured time between steps.
+    pub async fn emergency_stop(&self, timeouts: &EmergencyStop) -> Result<String, String> {
+        let dir = self.control_dir();
+        fs::create_dir_all(&dir)
+            .and_then(|()| fs::write(dir.join(agent_loop::HOLD_FILE), ""))
+            .map_err(|e| format!("Failed to hold agent: {}", e))?;
+        let Some(pgid) = self.agent_pgid() else {
+            return Ok(format!(
+                "No agent running in session {}, it will not be restarted",
+                self.id
+            ));
+        };
+        println!("Emergency stop of agent (pgid={})", pgid);
+
+        let steps = [
+            (libc::SIGINT, "SIGINT", timeouts.interrupt_timeout),
+            (libc::SIGTERM, "SIGTERM", timeouts.term_timeout),
+        ];
+        for (signal, name, timeout) in steps {
+            let tree = procfs::process_tree(pgid);
+            if tree.is_empty() {
+                break;
+            }
+            signal_tree(pgid, &tree, signal);
+            if wait_for_exit(pgid, Duration::from_secs_f64(timeout)).await {
+                self.paused.store(false, Ordering::SeqCst);
+                println!("Agent (pgid={}) exited after {}", pgid, name);
+                return Ok(format!("Agent stopped with {}", name));
+            }
+        }
+
+        let tree = procfs::process_tree(pgid);
+        if !tree.is_empty() {
+            signal_tree(pgid, &tree, libc::SIGKILL);
+        }
+        self.paused.store(false, Ordering::SeqCst);
+        if wait_for_exit(pgid, Duration::from_secs(2)).await {
+            println!("Agent (pgid={}) killed", pgid);
+            Ok("Agent killed with SIGKILL".to_string())
+        } else {
+            Err(format!(
+                "Processes of agent group {} survived SIGKILL",
+                pgid
+            ))
+        }
+    }
+
+    /// Resource use of the terminal and everything running under it, or
+    /// `None` while no terminal is running.
+    pub fn process_usage(&self) -> Option<Vec<ProcessUsage>> {
+        let pid = self.terminal_pid.load(Ordering::SeqCst);
+        if pid == 0 {
+            return None;
+        }
+        let mut sample = self.cpu_sample.lock().unwrap();
+        Some(procfs::tree_usage(pid as i32, &mut sample))
+    }
+
+    /// Arguments for `vnccc agent-loop` up to the agent command.
+    fn agent_loop_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(cgroup) = &self.cgroup {
+            args.push("--cgroup".to_string());
+            args.push(cgroup.to_string_lossy().into_owned());
+        }
+        for rlimit in &self.rlimits {
+            args.push("--rlimit".to_string());
+            args.push(format!("{}={}", rlimit.name, rlimit.value));
+        }
+        if let Some(rules) = self.sandbox() {
+            args.extend(rules.args());
+            args.push("--sandbox-tmp".to_string());
+            args.push(self.sandbox_tmp().to_string_lossy().into_owned());
+        }
+        args.push(self.control_dir().to_string_lossy().into_owned());
+        args
+    }
+
+    /// Private TMPDIR of the sandboxed agent, removed with the control directory.
+    fn sandbox_tmp(&self) -> PathBuf {
+        self.control_dir().join("tmp")
+    }
+
+    /// What the sandboxed agent can read and write at each of `paths`, or at
+    /// the rule paths and a few sensitive ones when `paths` is empty.
+    pub fn probe_sandbox(&self, paths: &[PathBuf]) -> Result<Vec<Probe>, String> {
+        let mut rules = self.sandbox().ok_or("The sandbox is not enabled")?;
+        rules.read_write.push(self.sandbox_tmp());
+        if paths.is_empty() {
+            sandbox::probe_with(&rules, &rules.probe_paths())
+        } else {
+            sandbox::probe_with(&rules, paths)
+        }
+    }
+
+    /// Run `f` on the workspace off the async runtime, one at a time.
+    pub async fn with_repo<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Path) -> Result<T, String> + Send + 'static,
+    ) -> Result<T, String> {
+        let _guard = self.checkpoint_lock.lock().await;
+        let repo = PathBuf::from(self.repo_path());
+        tokio::task::spawn_blocking(move || f(&repo))
+            .await
+            .map_err(|e| e.to_string())?
+    }
+
+    /// Snapshot the workspace before `prompt` is sent to the agent.
+    pub async fn checkpoint(&self, prompt: &str) {
+        if !self.checkpoints.enabled {
+            return;
+        }
+        let prompt = prompt.to_string();
+        let keep = self.checkpoints.keep;
+        match self
+            .with_repo(move |repo| checkpoint::create(repo, &prompt, keep))
+            .await
+        {
+            Ok(Some(checkpoint)) => {
+                println!("Session {} checkpoint {}", self.id, checkpoint.id)
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("Failed to checkpoint session {}: {}", self.id, e),
+        }
+    }
+
+    pub async fn checkpoints(&self) -> Result<Vec<Checkpoint>, String> {
+        self.with_repo(checkpoint::list).await
+    }
+
+    pub async fn checkpoint_diff(&self, id: String) -> Result<git::Diff, String> {
+        self.with_repo(move |repo| checkpoint::diff(repo, &id))
+            .await
+    }
+
+    pub async fn restore_checkpoint(&self, id: String) -> Result<String, String> {
+        let keep = self.checkpoints.keep;
+        self.with_repo(move |repo| checkpoint::restore(repo, &id, keep))
+            .await
+    }
+
+    /// How often the agent ran into its limits, or `None` when no limits
+    /// are configured.
+    pub fn limit_hits(&self) -> Option<LimitHits> {
+        if self.cgroup.is_none() && self.rlimits.is_empty() {
+            return None;
+        }
+        let mut hits = self
+            .cgroup
+            .as_deref()
+            .map(LimitHits::read)
+            .unwrap_or_default();
+        if let Some(nofile) = self.rlimits.iter().find(|r| r.name == "nofile") {
+            hits.open_files_at_limit = self
+                .process_usage()
+                .unwrap_or_default()
+                .iter()
+                .filter(|p| p.open_files.is_some_and(|n| n as u64 >= nofile.value))
+                .count();
+        }
+        Some(hits)
+    }
+
+    /// Log limit hits since the last check.
+    fn log_limit_hits(&self) {
+        let Some(hits) = self.limit_hits() else {
+            return;
+        };
+        let mut last = self.limit_hits.lock().unwrap();
+        let increases = hits.increases(&last);
+        if !increases.is_empty() {
+            println!(
+                "Session {} agent hit its limits: {}",
+                self.id,
+                increases.join(", ")
+            );
+        }
+        *last = hits;
+    }
+
+    /// Remove the session's cgroup once the agent loop and agent have left it.
+    async fn remove_cgroup(&self) {
+        let Some(cgroup) = &self.cgroup else {
+            return;
+        };
+        for _ in 0..20 {
+            if limits::remove_cgroup(cgroup).is_ok() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        eprintln!(
+            "Failed to remove {}: processes still in it",
+            cgroup.display()
+        );
+    }
+
+    /// Record activity, postponing the idle timeout.
+    pub fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity.lock().unwrap().elapsed()
+    }
+
+    /// Note that a prompt was just sent, so the agent is about to get busy.
+    pub fn mark_busy(&self) {
+        self.activity.lock().unwrap().0.prompted(Instant::now());
+        self.set_agent_state(AgentState::Busy);
+    }
+
+    /// Look at the terminal and work out what the agent is doing. Called
+    /// every few seconds; CPU use is measured since the call before. This
+    /// runs the terminal's text capture or `xwd`, so keep it off the runtime.
+    pub fn check_activity(&self) -> AgentState {
+        let pid = self.terminal_pid.load(Ordering::SeqCst);
+        let state = if pid == 0 || self.power() != Power::Awake {
+            AgentState::Offline
+        } else if self.paused.load(Ordering::SeqCst) || self.is_held() {
+            // An emergency-stopped agent loop waits with a still screen, which
+            // is not the agent being done
+            AgentState::Paused
+        } else {
+            let screen = self.terminal.capture_screen(&self.terminal_context()).ok();
+            // Only look at the pixels when the text is not available
+            let pixels = match screen {
+                Some(_) => None,
+                None => display::screen_hash(self.display),
+            };
+            let mut activity = self.activity.lock().unwrap();
+            let (tracker, sample) = &mut *activity;
+            let cpu_percent = procfs::tree_usage(pid as i32, sample)
+                .iter()
+                .map(|p| p.cpu_percent)
+                .sum();
+            let observation = Observation {
+                cpu_percent,
+                screen: screen.as_deref(),
+                pixels,
+            };
+            self.set_watch(observation.watch());
+            tracker.observe(&observation, Instant::now())
+        };
+        self.check_agent_restart();
+        self.set_agent_state(state);
+        state
+    }
+
+    /// Count the agent loop starting a new agent without being asked to as a
+    /// crash.
+    fn check_agent_restart(&self) {
+        // The loop waits a moment between agents, so hold on to the last one
+        let Some(pgid) = self.agent_pgid() else {
+            return;
+        };
+        let previous = self.seen_pgid.lock().unwrap().replace(pgid);
+        if let Some(previous) = previous
+            && previous != pgid
+            && !self.restarting_agent.swap(false, Ordering::SeqCst)
+        {
+            println!("Agent of session {} exited and was restarted", self.id);
+            self.crashed(false);
+        }
+    }
+
+    fn set_agent_state(&self, state: AgentState) {
+        let mut previous = state;
+        let changed = self.agent_state.send_if_modified(|current| {
+            previous = *current;
+            *current = state;
+            previous != state
+        });
+        if !changed {
+            return;
+        }
+        println!("Agent of session {} is {}", self.id, state.name());
+        let agent = agent_name(self.agent.as_deref());
+        let mut busy_since = self.busy_since.lock().unwrap();
+        match state {
+            // Not when the agent is just starting up
+            AgentState::Busy if previous != AgentState::Offline => {
+                busy_since.get_or_insert_with(Instant::now);
+            }
+            AgentState::Busy => {}
+            AgentState::Attention => {
+                self.notify(
+                    Event::Attention,
+                    format!("{} needs you", agent),
+                    format!("{} is asking something", agent),
+                );
+            }
+            AgentState::Idle => {
+                let worked = busy_since.take().map(|since| since.elapsed());
+                let done_after = Duration::from_secs(self.notifier.config().done_after_seconds);
+                if let Some(worked) = worked.filter(|worked| *worked >= done_after) {
+                    self.notify(
+                        Event::Done,
+                        format!("{} is done", agent),
+                        format!("{} finished after {}s of work", agent, worked.as_secs()),
+                    );
+                }
+            }
+            AgentState::Paused | AgentState::Offline => *busy_since = None,
+        }
+    }
+
+    /// Send a notification about this session.
+    pub fn notify(&self, event: Event, title: String, message: String) {
+        let workspace = self.repo_path();
+        let name = Path::new(&workspace)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| workspace.clone());
+        self.notifier.notify(Notification {
+            event,
+            session: self.id.clone(),
+            agent: agent_name(self.agent.as_deref()),
+            title: format!("[{}] {}", self.id, title),
+            message: format!("{} in {}", message, name),
+            url: self.notifier.session_url(&self.id),
+            workspace,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        });
+    }
+
+    /// Record a crash of the terminal, or of the agent when `terminal` is
+    /// false, and report it. Too many close together make a crash loop.
+    fn crashed(&self, terminal: bool) {
+        let config = self.notifier.config();
+        let window = Duration::from_secs(config.crash_loop_seconds);
+        let mut crashes = self.crashes.lock().unwrap();
+        crashes.retain(|at| at.elapsed() < window);
+        crashes.push(Instant::now());
+        if config.crash_loop_count > 0 && crashes.len() >= config.crash_loop_count {
+            let count = crashes.len();
+            crashes.clear();
+            self.notify(
+                Event::CrashLoop,
+                "Crash loop".to_string(),
+                format!(
+                    "The {} crashed {} times in {}s",
+                    if terminal { "terminal" } else { "agent" },
+                    count,
+                    config.crash_loop_seconds
+                ),
+            );
+        } else if terminal {
+            self.notify(
+                Event::Crashed,
+                format!("{} crashed", self.terminal.name()),
+                format!("{} exited and is being restarted", self.terminal.name()),
+            );
+        }
+    }
+
+    fn set_watch(&self, watch: Watch) {
+        let previous = self.watch.lock().unwrap().replace(watch);
+        if previous == Some(watch) || watch == Watch::Text {
+            return;
+        }
+        eprintln!(
+            "{} cannot give vnccc its screen text, so session {} watches the agent through {} \
+             and cannot tell when it asks something",
+            self.terminal.name(),
+            self.id,
+            if watch == Watch::Pixels {
+                "screenshots"
+            } else {
+                "CPU use alone"
+            }
+        );
+    }
+
+    /// What [`Session::agent_state`] was last worked out from, `None` before
+    /// the first look.
+    pub fn agent_watch(&self) -> Option<Watch> {
+        *self.watch.lock().unwrap()
+    }
+
+    /// What the agent was last seen doing.
+    pub fn agent_state(&self) -> AgentState {
+        *self.agent_state.borrow()
+    }
+
+    /// Changes of [`Session::agent_state`].
+    pub fn watch_agent_state(&self) -> tokio::sync::watch::Receiver<AgentState> {
+        self.agent_state.subscribe()
+    }
+
+    pub fn power(&self) -> Power {
+        *self.power.lock().unwrap()
+    }
+
+    /// Send `signal` to the agent's process tree, the terminal and the display
+    /// processes, in that order. The agent is skipped while the user has it
+    /// paused, so waking up does not resume it behind their back.
+    fn signal_stack(&self, signal: libc::c_int) {
+        let mut pids = Vec::new();
+        if !self.paused.load(Ordering::SeqCst)
+            && let Some(pgid) = self.agent_pgid()
+        {
+            pids.extend(procfs::process_tree(pgid));
+        }
+        let terminal = self.terminal_pid.load(Ordering::SeqCst);
+        if terminal != 0 {
+            pids.push(terminal as i32);
+        }
+        let processes = self.processes.lock().unwrap();
+        pids.extend(processes.iter().rev().map(|child| child.id() as i32));
+        for pid in pids {
+            // SAFETY: kill has no memory-safety preconditions
+            unsafe {
+                libc::kill(pid, signal);
+            }
+        }
+    }
+
+    /// Kill Xvnc and the window manager and clean up after them.
+    fn kill_display(&self) {
+        let old = std::mem::take(&mut *self.processes.lock().unwrap());
+        for mut child in old {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        // Xvnc removes its lock file on exit, but not when killed
+        let _ = fs::remove_file(format!("/tmp/.X{}-lock", self.display));
+        let _ = fs::remove_file(format!("/tmp/.X11-unix/X{}", self.display));
+    }
+
+    /// Put an awake session to sleep after the idle timeout.
+    pub fn sleep(&self, action: IdleAction) {
+        let mut power = self.power.lock().unwrap();
+        if *power != Power::Awake {
+            return;
+        }
+        match action {
+            IdleAction::Suspend => {
+                println!("Suspending idle session {}", self.id);
+                self.signal_stack(libc::SIGSTOP);
+                *power = Power::Suspended;
+            }
+            IdleAction::Stop => {
+                println!("Stopping idle session {}", self.id);
+                // Set first so the monitor waits instead of relaunching
+                *power = Power::Stopped;
+                let _ = self.signal_agent(libc::SIGCONT);
+                self.paused.store(false, Ordering::SeqCst);
+                self.restart_terminal();
+                self.kill_display();
+            }
+        }
+    }
+
+    /// Bring a sleeping session back. Returns once the display is up; the
+    /// monitor then relaunches the terminal if it was stopped.
+    pub async fn wake(&self) -> Result<(), String> {
+        self.touch();
+        let _guard = self.wake_lock.lock().await;
+        let previous = {
+            let mut power = self.power.lock().unwrap();
+            let previous = *power;
+            if previous != Power::Awake {
+                *power = Power::Waking;
+            }
+            previous
+        };
+        match previous {
+            Power::Awake => return Ok(()),
+            Power::Suspended => {
+                println!("Resuming session {}", self.id);
+                self.signal_stack(libc::SIGCONT);
+            }
+            // A wake that failed part way leaves Waking behind; start over
+            Power::Stopped | Power::Waking => {
+                println!(
+                    "Restarting display :{} for session {}",
+                    self.display, self.id
+                );
+                self.kill_display();
+                let geometry = *self.geometry.lock().unwrap();
+                let processes = start_display(self.display, geometry).await?;
+                *self.processes.lock().unwrap() = processes;
+            }
+        }
+        *self.power.lock().unwrap() = Power::Awake;
+        self.touch();
+        Ok(())
+    }
+
+    /// Replace Xvnc and the window manager with fresh ones on the same
+    /// display. The terminal dies with the old server and the monitor
+    /// relaunches it once the new one is up.
+    pub async fn restart_display(&self) -> Result<(), String> {
+        self.wake().await?;
+        println!(
+            "Restarting display :{} for session {}",
+            self.display, self.id
+        );
+        self.restarting_terminal
+            .store(self.is_running(), Ordering::SeqCst);
+        self.kill_display();
+
+        let geometry = *self.geometry.lock().unwrap();
+        let processes = start_display(self.display, geometry).await?;
+        *self.processes.lock().unwrap() = processes;
+        self.paused.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Stop the terminal for good and shut down the display.
+    fn stop(&self) {
+        println!("Stopping session {} (display :{})", self.id, self.display);
+        self.stopping.store(true, Ordering::SeqCst);
+        if self.power() == Power::Suspended {
+            self.signal_stack(libc::SIGCONT);
+        }
+        let _ = self.signal_agent(libc::SIGCONT);
+        self.restart_terminal();
+        for mut child in self.processes.lock().unwrap().drain(..) {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        let _ = fs::remove_dir_all(self.control_dir());
+    }
+}
+
+/// Send `signal` to process group `pgid` and to every pid in `tree`, which
+/// covers children that left the group. Stopped processes are continued so
+/// they act on it.
+fn signal_tree(pgid: i32, tree: &[i32], signal: libc::c_int) {
+    // SAFETY: kill has no memory-safety preconditions
+    unsafe {
+        libc::kill(-pgid, signal);
+        for &pid in tree {
+            libc::kill(pid, signal);
+        }
+        if signal != libc::SIGKILL {
+            libc::kill(-pgid, libc::SIGCONT);
+            for &pid in tree {
+                libc::kill(pid, libc::SIGCONT);
+            }
+        }
+    }
+}
+
+/// Poll until nothing is left of the agent's process tree, up to `timeout`.
+async fn wait_for_exit(pgid: i32, timeout: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        if procfs::process_tree(pgid).is_empty() {
+            return true;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Per-display control directory, namespaced by our pid so a stale file
+/// from an earlier run never names someone else's process group.
+fn control_dir(display: u32) -> PathBuf {
+    env::temp_dir().join(format!("vncaa-{}-{}", std::process::id(), display))
+}
+
+/// Start Xvnc and ratpoison on `display`, returning both processes.
+async fn start_display(display: u32, geometry: Geometry) -> Result<Vec<Child>, String> {
+    let mut vnc_proc = start_vnc_server(display, &geometry.to_string())?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    match vnc_proc.try_wait() {
+        Ok(Some(status)) => return Err(format!("VNC server exited unexpectedly: {}", status)),
+        Ok(None) => {}
+        Err(e) => {
+            let _ = vnc_proc.kill();
+            return Err(format!("Error checking VNC: {}", e));
+        }
+    }
+
+    println!("Starting ratpoison window manager");
+    let wm_proc = match start_window_manager(display) {
+        Ok(child) => child,
+        Err(e) => {
+            let _ = vnc_proc.kill();
+            return Err(e);
+        }
+    };
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    Ok(vec![vnc_proc, wm_proc])
+}
+
+/// All sessions served by this process, keyed by id.
+#[derive(Default)]
+pub struct SessionManager {
+    sessions: RwLock<BTreeMap<String, Arc<Session>>>,
+    /// Held while a session starts so two sessions never pick the same display.
+    create_lock: tokio::sync::Mutex<()>,
+}
+
+impl SessionManager {
+    pub fn list(&self) -> Vec<Arc<Session>> {
+        self.sessions.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Session>> {
+        self.sessions.read().unwrap().get(id).cloned()
+    }
+
+    /// Start a display, window manager and terminal for a new session.
+    pub async fn create(&self, opts: SessionOptions) -> Result<Arc<Session>, String> {
+        if !Path::new(&opts.repo_path).is_dir() {
+            return Err(format!("Repo path '{}' is not a directory", opts.repo_path));
+        }
+
+        let _guard = self.create_lock.lock().await;
+        let (id, in_use) = {
+            let sessions = self.sessions.read().unwrap();
+            let id = match opts.id.clone() {
+                Some(id) => {
+                    validate_session_id(&id)?;
+                    if sessions.contains_key(&id) {
+                        return Err(format!("Session '{}' already exists", id));
+                    }
+                    id
+                }
+                None => next_session_id(&sessions),
+            };
+            let in_use: Vec<u32> = sessions.values().map(|s| s.display).collect();
+            (id, in_use)
+        };
+
+        let display = find_available_display(&in_use)?;
+        println!(
+            "Starting session {} on display :{} (port {})",
+            id,
+            display,
+            5900 + display
+        );
+        let processes = start_display(display, opts.geometry).await?;
+        let _ = fs::remove_dir_all(control_dir(display));
+
+        let cgroup = if opts.limits.is_empty() {
+            None
+        } else {
+            limits::create_cgroup(&id, &opts.limits)
+        };
+        if cgroup.is_none() && opts.limits.cpus.is_some() {
+            println!("Ignoring limits.cpus for session {}: it needs a cgroup", id);
+        }
+
+        println!(
+            "Starting {} with agent in {}",
+            opts.terminal.name(),
+            opts.repo_path
+        );
+        let session = Arc::new(Session::new(id.clone(), display, processes, cgroup, opts));
+        spawn_terminal_monitor(session.clone());
+
+        self.sessions.write().unwrap().insert(id, session.clone());
+        Ok(session)
+    }
+
+    /// Remove a session and stop its processes.
+    pub fn destroy(&self, id: &str) -> Option<Arc<Session>> {
+        let session = self.sessions.write().unwrap().remove(id)?;
+        session.stop();
+        Some(session)
+    }
+
+    /// Put sessions idle for longer than `timeout` to sleep.
+    pub fn sleep_idle(&self, timeout: Duration, action: IdleAction) {
+        for session in self.list() {
+            if session.power() == Power::Awake && session.idle_for() >= timeout {
+                session.sleep(action);
+            }
+        }
+    }
+
+    /// Log limit hits of every session since the last check.
+    pub fn log_limit_hits(&self) {
+        for session in self.list() {
+            session.log_limit_hits();
+        }
+    }
+
+    pub fn destroy_all(&self) {
+        let sessions = std::mem::take(&mut *self.sessions.write().unwrap());
+        for session in sessions.values() {
+            session.stop();
+        }
+    }
+}
+
+/// Landlock rules for `agent` working in `repo_path`, when the sandbox is on.
+fn sandbox_rules(sandbox: &config::Sandbox, repo_path: &str, agent: Option<&str>) -> Option<Rules> {
+    sandbox
+        .enabled
+        .then(|| Rules::for_session(sandbox, repo_path, &agent_name(agent)))
+}
+
+/// The agent a session runs: its own, else `$AGENT`, else claude.
+fn agent_name(agent: Option<&str>) -> String {
+    agent
+        .map(str::to_string)
+        .or_else(|| env::var("AGENT").ok())
+        .unwrap_or_else(|| "claude".to_string())
+}
+
+/// Ids appear in URLs, so keep them short and path-safe.
+fn validate_session_id(id: &str) -> Result<(), String> {
+    let ok = !id.is_empty()
+        && id.len() <= 32
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if ok {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid session id '{}': use up to 32 letters, digits, '-' or '_'",
+            id
+        ))
+    }
+}
+
+/// Lowest positive number not already used as an id.
+fn next_session_id(sessions: &BTreeMap<String, Arc<Session>>) -> String {
+    (1..)
+        .map(|n: u32| n.to_string())
+        .find(|id| !sessions.contains_key(id))
+        .unwrap()
+}
+
+/// First display number with neither an X lock file nor a session of ours;
+/// a display we just started may not have written its lock file yet.
+fn find_available_display(in_use: &[u32]) -> Result<u32, String> {
+    (1..100)
+        .find(|display| {
+            !in_use.contains(display) && !Path::new(&format!("/tmp/.X{}-lock", display)).exists()
+        })
+        .ok_or_else(|| "No available display found".to_string())
+}
+
+fn debug_log_path(display: u32) -> String {
+    format!("/tmp/agent-debug-{}-{}.log", std::process::id(), display)
+}
+
+fn start_vnc_server(display: u32, geometry: &str) -> Result<Child, String> {
+    let vnc = Command::new("Xvnc")
+        .args([
+            &format!(":{}", display),
+            "-geometry",
+            geometry,
+            "-depth",
+            "24",
+            "-SecurityTypes",
+            "None",
+        ])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+
+    match vnc {
+        Ok(child) => Ok(child),
+        Err(_) => {
+            eprintln!("Xvnc not found, trying vncserver...");
+            Command::new("vncserver")
+                .args([
+                    &format!(":{}", display),
+                    "-geometry",
+                    geometry,
+                    "-depth",
+                    "24",
+                ])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .map_err(|e| format!("Failed to start VNC server, install tigervnc: {}", e))
+        }
+    }
+}
+
+fn start_window_manager(display: u32) -> Result<Child, String> {
+    let display_env = format!(":{}", display);
+    Command::new("ratpoison")
+        .env("DISPLAY", &display_env)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Failed to start ratpoison window manager: {}", e))
+}
+
+fn start_terminal_with_agent(
+    display: u32,
+    repo_path: &str,
+    terminal: &dyn TerminalBackend,
+    geometry: &str,
+    settings: &TerminalSettings,
+    agent: Option<&str>,
+    loop_args: &[String],
+) -> std::io::Result<Child> {
+    let display_env = format!(":{}", display);
+
+    // Check if DEBUG mode is enabled
+    let debug_mode = env::var("DEBUG").unwrap_or_default() == "true";
+
+    let (agent_cmd, _log_file_opt) = if debug_mode {
+        // Use debug launcher script for verbose logging
+        // Redirect stdout/stderr to a log file that we can monitor
+        let log_file = debug_log_path(display);
+        let debug_launcher = "/usr/local/bin/debug-agent-launcher.sh";
+        let cmd = format!("{} {} > {} 2>&1", debug_launcher, repo_path, log_file);
+        println!("=== DEBUG MODE ENABLED ===");
+        println!("Agent output will be logged to: {}", log_file);
+        println!("=== Terminal Launch Debug ===");
+        println!("Display: {}", display_env);
+        println!("Repo path: {}", repo_path);
+        println!("Terminal: {}", terminal.name());
+        (cmd, Some(log_file))
+    } else {
+        // Standard mode: use agent wrapper directly
+        let cmd = format!("cd -- {} && /usr/local/bin/agent", shell_quote(repo_path));
+        (cmd, None)
+    };
+
+    // Supervise the agent from inside the terminal so it can be restarted,
+    // paused and signalled as a process group
+    let agent_cmd = match env::current_exe() {
+        Ok(exe) => {
+            let mut words = vec![exe.to_string_lossy().into_owned(), "agent-loop".to_string()];
+            words.extend(loop_args.iter().cloned());
+            words.push(agent_cmd);
+            let quoted: Vec<String> = words.iter().map(|w| shell_quote(w)).collect();
+            format!("exec {}", quoted.join(" "))
+        }
+        Err(e) => {
+            eprintln!("Cannot locate vnccc for the agent loop: {}", e);
+            agent_cmd
+        }
+    };
+
+    if debug_mode {
+        println!("Agent command: {}", agent_cmd);
+    }
+
+    let mut cmd = Command::new(terminal.name());
+    cmd.env("DISPLAY", &display_env);
+
+    // Capture stdout and stderr for debugging - NOTE: terminal emulators typically
+    // don't output to stdout/stderr, but we capture them anyway to catch any errors
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+
+    if debug_mode {
+        println!("Command to execute: {:?}", cmd);
+    }
+
+    // Pass through important env vars
+    if let Ok(claude_dir) = env::var("CLAUDE_CONFIG_DIR") {
+        println!("Passing CLAUDE_CONFIG_DIR={} to terminal", claude_dir);
+        cmd.env("CLAUDE_CONFIG_DIR", &claude_dir);
+    }
+    if let Ok(home) = env::var("HOME") {
+        if debug_mode {
+            println!("Passing HOME={} to terminal", home);
+        }
+        cmd.env("HOME", &home);
+    }
+
+    // A per-session agent overrides the AGENT env var
+    if let Some(agent) = agent.map(str::to_string).or_else(|| env::var("AGENT").ok()) {
+        println!("Detected AGENT={}", agent);
+        cmd.env("AGENT", &agent);
+    }
+
+    let opts = LaunchOptions {
+        display,
+        geometry,
+        agent_cmd: &agent_cmd,
+        settings,
+    };
+    if let Err(e) = terminal.prepare_launch(&opts) {
+        eprintln!("Failed to prepare {} launch: {}", terminal.name(), e);
+    }
+    cmd.args(terminal.launch_args(&opts));
+
+    cmd.spawn()
+}
+
+/// Keep the session's terminal running, relaunching it whenever it exits
+/// until the session is stopped.
+fn spawn_terminal_monitor(session: Arc<Session>) {
+    let debug_mode = env::var("DEBUG").unwrap_or_default() == "true";
+
+    tokio::spawn(async move {
+        loop {
+            // Nothing to launch into while the idle timeout has the display down
+            while !matches!(session.power(), Power::Awake | Power::Suspended) {
+                if session.stopping.load(Ordering::SeqCst) {
+                    session.remove_cgroup().await;
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+
+            let geometry = session.geometry.lock().unwrap().to_string();
+            let mut term_proc = match start_terminal_with_agent(
+                session.display,
+                &session.repo_path(),
+                session.terminal.as_ref(),
+                &geometry,
+                &session.settings(),
+                session.agent.as_deref(),
+                &session.agent_loop_args(),
+            ) {
+                Ok(child) => child,
+                Err(e) => {
+                    eprintln!("Failed to start {}: {}", session.terminal.name(), e);
+                    if session.stopping.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+            let pid = term_proc.id();
+            session.terminal_pid.store(pid, Ordering::SeqCst);
+            println!("Terminal started (pid: {:?})", pid);
+
+            let monitor_handle = if debug_mode {
+                // In debug mode, monitor the log file
+                let log_file_clone = debug_log_path(session.display);
+
+                Some(tokio::spawn(async move {
+                    // Wait a moment for the log file to be created
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+
+                    if let Ok(mut file) = tokio::fs::File::open(&log_file_clone).await {
+                        use tokio::io::AsyncReadExt;
+                        let mut buffer = vec![0u8; 4096];
+                        loop {
+                            match file.read(&mut buffer).await {
+                                Ok(0) => {
+                                    // EOF - wait a bit and try again (tail -f behavior)
+                                    tokio::time::sleep(Duration::from_millis(100)).await;
+                                }
+                                Ok(n) => {
+                                    let output = String::from_utf8_lossy(&buffer[..n]);
+                                    for line in output.lines() {
+                                        println!("[Agent log] {}", line);
+                                    }
+                                }
+                                Err(e) => {
+                                    eprintln!("[Agent log error] Failed to read: {}", e);
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                }))
+            } else {
+                None
+            };
+
+            // Wait for terminal to exit without tying up an async worker
+            let exit = tokio::task::spawn_blocking(move || term_proc.wait())
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+            session.terminal_pid.store(0, Ordering::SeqCst);
+            *session.seen_pgid.lock().unwrap() = None;
+            let stopping = session.stopping.load(Ordering::SeqCst);
+            let restarting = session.restarting_terminal.swap(false, Ordering::SeqCst);
+            // Exits while stopping, restarting or going to sleep are expected
+            if !stopping && !restarting && session.power() == Power::Awake {
+                session.crashed(true);
+            }
+            match exit {
+                Ok(status) => {
+                    println!("=== Terminal exited (pid={}) ===", pid);
+                    println!("Exit status: {}", status);
+                    println!("Exit code: {:?}", status.code());
+
+                    if debug_mode {
+                        // Give the log monitor a moment to catch up
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+
+                        // Print final log contents
+                        let log_file = debug_log_path(session.display);
+                        if let Ok(contents) = fs::read_to_string(&log_file) {
+                            println!("=== Final log contents ===");
+                            for line in contents.lines() {
+                                println!("[Agent log] {}", line);
+                            }
+                            println!("==========================");
+                        } else {
+                            println!("WARNING: Could not read log file: {}", log_file);
+                        }
+                    }
+
+                    if !stopping {
+                        println!("Restarting in 500ms...");
+                    }
+                    println!("==============================");
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Error waiting for terminal (pid={}): {} - restarting...",
+                        pid, e
+                    );
+                }
+            }
+
+            // Stop the log monitor if it was started
+            if let Some(handle) = monitor_handle {
+                handle.abort();
+            }
+
+            if stopping {
+                println!("Session {} stopped", session.id);
+                session.remove_cgroup().await;
+                break;
+            }
+
+            // Brief delay before restart
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub fn options(repo_path: &Path, worktree: Option<Worktree>) -> SessionOptions {
+        SessionOptions {
+            id: Some("wt".to_string()),
+            repo_path: repo_path.to_string_lossy().into_owned(),
+            geometry: Geometry {
+                width: 800,
+                height: 600,
+            },
+            terminal: crate::terminal::backend_by_name("xterm").unwrap().into(),
+            agent: None,
+            limits: Limits::default(),
+            sandbox: config::Sandbox::default(),
+            checkpoints: config::Checkpoints::default(),
+            activity: config::Activity::default(),
+            notifier: Arc::new(Notifier::new(&config::Notify::default(), None)),
+            worktree,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_switch_keeps_worktree() {
+        let repo = git::tests::temp_repo("switch");
+        let elsewhere = repo.with_extension("elsewhere");
+        fs::create_dir_all(&elsewhere).unwrap();
+        let worktree = Worktree {
+            repo: repo.clone(),
+            path: repo.join("wt"),
+            branch: "vncaa/wt".to_string(),
+        };
+        fs::create_dir_all(&worktree.path).unwrap();
+
+        // The worktree torn down when the session stops stays the one its
+        // agent works in
+        let session = Session::new(
+            "wt".to_string(),
+            0,
+            Vec::new(),
+            None,
+            options(&worktree.path, Some(worktree.clone())),
+        );
+        let switched = session
+            .switch_repo(elsewhere.to_string_lossy().into_owned())
+            .await;
+        assert!(switched.unwrap_err().contains("own worktree"));
+        assert_eq!(session.repo_path(), worktree.path.to_string_lossy());
+        assert_eq!(session.worktree, Some(worktree));
+
+        let plain = Session::new(
+            "plain".to_string(),
+            0,
+            Vec::new(),
+            None,
+            options(&repo, None),
+        );
+        assert!(plain.check_switch().is_ok());
+
+        fs::remove_dir_all(repo).unwrap();
+        fs::remove_dir_all(elsewhere).unwrap();
+    }
+
+    #[test]
+    fn test_held_agent_is_paused() {
+        let repo = git::tests::temp_repo("held");
+        let session = Session::new(
+            "held".to_string(),
+            97,
+            Vec::new(),
+            None,
+            options(&repo, None),
+        );
+        session
+            .terminal_pid
+            .store(std::process::id(), Ordering::SeqCst);
+        session.mark_busy();
+
+        let dir = session.control_dir();
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(agent_loop::HOLD_FILE), "").unwrap();
+        assert!(session.is_held());
+        assert_eq!(session.check_activity(), AgentState::Paused);
+        assert_eq!(session.agent_state(), AgentState::Paused);
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[test]
+    fn test_find_available_display_returns_valid_number() {
+        let display = find_available_display(&[]).unwrap();
+        assert!((1..100).contains(&display));
+    }
+
+    #[test]
+    fn test_find_available_display_skips_in_use() {
+        let first = find_available_display(&[]).unwrap();
+        let second = find_available_display(&[first]).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_validate_session_id() {
+        assert!(validate_session_id("1").is_ok());
+        assert!(validate_session_id("api-server_2").is_ok());
+        assert!(validate_session_id("").is_err());
+        assert!(validate_session_id("../etc").is_err());
+        assert!(validate_session_id("a b").is_err());
+        assert!(validate_session_id(&"x".repeat(33)).is_err());
+    }
+
+    #[test]
+    fn test_next_session_id() {
+        let sessions = BTreeMap::new();
+        assert_eq!(next_session_id(&sessions), "1");
+    }
+}
@@ -0,0 +1,177 @@
+//! Named prompt templates from `snippets.toml` in the config directory and
+//! `.vncaa/snippets.toml` in the repo. A snippet is either just its prompt or
+//! a table with a description:
+//!
+//! ```toml
+//! tests = "Run the tests and fix any failures"
+//!
+//! [pr]
+//! description = "Open a PR"
+//! prompt = "Open a PR from {{branch}} titled '{{type}}: {{title}}'"
+//! ```
+//!
+//! `{{name}}` placeholders are filled in from [`BUILTINS`] when the prompt is
+//! sent; the web UI asks for any others when the snippet is inserted.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::config;
+
+/// Variables vnccc fills in itself.
+pub const BUILTINS: [&str; 5] = ["branch", "workspace", "session", "agent", "clipboard"];
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Definition {
+    Prompt(String),
+    Full {
+        prompt: String,
+        #[serde(default)]
+        description: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Snippet {
+    pub name: String,
+    pub prompt: String,
+    pub description: Option<String>,
+    /// `user` or `repo`; a repo snippet replaces a user one of the same name.
+    pub source: &'static str,
+    /// Placeholders that are not built in, in order of appearance.
+    pub variables: Vec<String>,
+}
+
+/// Names of the `{{name}}` placeholders in `template`, in order, once each.
+pub fn variables(template: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    expand(template, |name| {
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+        None
+    });
+    names
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Replace each `{{name}}` in `template` that `lookup` has a value for.
+/// Anything else, unknown names included, is left as written.
+pub fn expand(template: &str, mut lookup: impl FnMut(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+        let name = after[..end].trim();
+        match is_name(name).then(|| lookup(name)).flatten() {
+            Some(value) => out.push_str(&value),
+            None => out.push_str(&rest[start..start + 2 + end + 2]),
+        }
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn parse(contents: &str, source: &'static str) -> Result<Vec<Snippet>, String> {
+    let definitions: BTreeMap<String, Definition> =
+        toml::from_str(contents).map_err(|e| e.to_string())?;
+    Ok(definitions
+        .into_iter()
+        .map(|(name, definition)| {
+            let (prompt, description) = match definition {
+                Definition::Prompt(prompt) => (prompt, None),
+                Definition::Full {
+                    prompt,
+                    description,
+                } => (prompt, description),
+            };
+            let variables = variables(&prompt)
+                .into_iter()
+                .filter(|v| !BUILTINS.contains(&v.as_str()))
+                .collect();
+            Snippet {
+                name,
+                prompt,
+                description,
+                source,
+                variables,
+            }
+        })
+        .collect())
+}
+
+fn load_file(path: &Path, source: &'static str) -> Result<Vec<Snippet>, String> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    parse(&contents, source).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+}
+
+/// The user's snippets merged with those of the repo at `repo`, sorted by
+/// name. Read fresh each time, so edits show up without a restart.
+pub fn load(repo: &Path) -> Result<Vec<Snippet>, String> {
+    let mut snippets = match config::config_dir() {
+        Some(dir) => load_file(&dir.join("snippets.toml"), "user")?,
+        None => Vec::new(),
+    };
+    for snippet in load_file(&repo.join(".vncaa").join("snippets.toml"), "repo")? {
+        snippets.retain(|s| s.name != snippet.name);
+        snippets.push(snippet);
+    }
+    snippets.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(snippets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let lookup = |name: &str| (name == "branch").then(|| "main".to_string());
+        assert_eq!(expand("on {{branch}}!", lookup), "on main!");
+        assert_eq!(expand("{{ branch }}{{branch}}", lookup), "mainmain");
+        assert_eq!(expand("{{other}} {{branch", lookup), "{{other}} {{branch");
+        assert_eq!(expand("{{not a name}}", lookup), "{{not a name}}");
+        assert_eq!(expand("no braces", lookup), "no braces");
+        assert_eq!(
+            variables("{{title}} {{branch}} {{title}} {{ type }}"),
+            ["title", "branch", "type"]
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let snippets = parse(
+            "tests = \"Run the tests\"\n\
+             [pr]\n\
+             description = \"Open a PR\"\n\
+             prompt = \"PR from {{branch}}: {{title}}\"\n",
+            "repo",
+        )
+        .unwrap();
+        assert_eq!(snippets.len(), 2);
+        assert_eq!(snippets[0].name, "pr");
+        assert_eq!(snippets[0].description.as_deref(), Some("Open a PR"));
+        assert_eq!(snippets[0].variables, ["title"]);
+        assert_eq!(snippets[1].prompt, "Run the tests");
+        assert!(parse("tests = 3", "user").is_err());
+    }
+}
@@ -0,0 +1,1268 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use toml_edit::{DocumentMut, InlineTable, Item, Table, Value};
+
+use crate::config;
+
+/// Backends in auto-detection order. Alacritty comes first because it is the
+/// emulator the container image ships and configures.
+const BACKEND_NAMES: [&str; 6] = ["alacritty", "kitty", "wezterm", "xterm", "urxvt", "st"];
+
+/// Scrollback lines requested from emulators that need an explicit range.
+const SCROLLBACK_LINES: u32 = 10000;
+
+/// Font size used in Xft patterns when none has been requested.
+const DEFAULT_FONT_SIZE: f32 = 14.0;
+
+#[derive(Debug, PartialEq)]
+pub enum BackendError {
+    /// The emulator has no way to perform the operation.
+    Unsupported(String),
+    /// The operation is supported but failed.
+    Failed(String),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Unsupported(msg) | BackendError::Failed(msg) => f.write_str(msg),
+        }
+    }
+}
+
+/// How a setting change took effect.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Applied {
+    /// The running terminal picked it up immediately.
+    Live,
+    /// The emulator cannot change it at runtime; it is used on the next launch.
+    NextLaunch,
+}
+
+/// Terminal colour scheme: primary colours plus the 16 ANSI colours.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Palette {
+    pub foreground: String,
+    pub background: String,
+    pub normal: [String; 8],
+    pub bright: [String; 8],
+}
+
+/// ANSI colour names in palette order, as used by Alacritty's `[colors.*]` tables.
+pub const COLOR_NAMES: [&str; 8] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+
+impl Palette {
+    /// Colours end up in escape sequences, Lua and command lines, so only
+    /// accept plain `#rrggbb`.
+    pub fn validate(&self) -> Result<(), String> {
+        let colors = [&self.foreground, &self.background]
+            .into_iter()
+            .chain(&self.normal)
+            .chain(&self.bright);
+        for color in colors {
+            let hex = color.strip_prefix('#').unwrap_or_default();
+            if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("Invalid colour '{}', expected #rrggbb", color));
+            }
+        }
+        Ok(())
+    }
+
+    /// Foreground, background and the 16 ANSI colours as OSC 10/11/4 sequences.
+    fn osc_sequences(&self) -> String {
+        let mut seq = format!(
+            "\x1b]10;{}\x07\x1b]11;{}\x07",
+            self.foreground, self.background
+        );
+        for (i, color) in self.normal.iter().chain(&self.bright).enumerate() {
+            seq.push_str(&format!("\x1b]4;{};{}\x07", i, color));
+        }
+        seq
+    }
+}
+
+/// Font family and optional style (e.g. "Bold", "Book").
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FontSpec {
+    pub family: String,
+    #[serde(default)]
+    pub style: Option<String>,
+}
+
+impl FontSpec {
+    /// Names end up in Xft patterns and Lua strings, where `:`, `-` prefixes
+    /// and quotes are significant, so keep to a conservative character set.
+    pub fn validate(&self) -> Result<(), String> {
+        fn check(what: &str, value: &str) -> Result<(), String> {
+            let ok = !value.trim().is_empty()
+                && value.len() <= 64
+                && value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, ' ' | '_' | '.' | '-'))
+                && !value.starts_with('-');
+            if ok {
+                Ok(())
+            } else {
+                Err(format!("Invalid font {} '{}'", what, value))
+            }
+        }
+        check("family", &self.family)?;
+        if let Some(style) = &self.style {
+            check("style", style)?;
+        }
+        Ok(())
+    }
+
+    /// Fontconfig/Xft pattern like `DejaVu Sans Mono:style=Bold:size=14`.
+    fn xft_pattern(&self, size: f32) -> String {
+        match &self.style {
+            Some(style) => format!("{}:style={}:size={}", self.family, style, size),
+            None => format!("{}:size={}", self.family, size),
+        }
+    }
+}
+
+/// Appearance settings vnccc manages on top of the emulator's own config.
+/// `None` leaves the emulator's default alone.
+#[derive(Debug, Clone, Default)]
+pub struct TerminalSettings {
+    pub font_size: Option<f32>,
+    pub font: Option<FontSpec>,
+    /// Name of the selected theme, for reporting back to the UI.
+    pub theme: Option<String>,
+    pub palette: Option<Palette>,
+}
+
+impl TerminalSettings {
+    fn xft_pattern(&self, default_family: &str) -> String {
+        let size = self.font_size.unwrap_or(DEFAULT_FONT_SIZE);
+        match &self.font {
+            Some(font) => font.xft_pattern(size),
+            None => format!("{}:size={}", default_family, size),
+        }
+    }
+}
+
+/// The running terminal a backend operation targets.
+pub struct TerminalContext {
+    pub display: u32,
+    /// Pid of the emulator process, if one is running.
+    pub pid: Option<u32>,
+    /// All current settings, including the change being applied.
+    pub settings: TerminalSettings,
+}
+
+/// Settings for starting a new terminal.
+pub struct LaunchOptions<'a> {
+    pub display: u32,
+    pub geometry: &'a str,
+    pub agent_cmd: &'a str,
+    pub settings: &'a TerminalSettings,
+}
+
+/// Everything vnccc needs to drive a particular terminal emulator.
+pub trait TerminalBackend: Send + Sync {
+    /// Name used in config and logs; also the executable name.
+    fn name(&self) -> &'static str;
+
+    /// Write any files the emulator needs before `launch_args` is used.
+    fn prepare_launch(&self, _opts: &LaunchOptions) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Arguments that start the emulator running `agent_cmd` under bash.
+    fn launch_args(&self, opts: &LaunchOptions) -> Vec<String>;
+
+    /// X11 window class, used by xdotool to find the terminal window.
+    fn window_class(&self) -> &'static str;
+
+    /// Font size the emulator on `display` is currently configured with, if
+    /// it can be read.
+    fn current_font_size(&self, _display: u32) -> Option<f32> {
+        None
+    }
+
+    /// Font the emulator on `display` is currently configured with, if it can
+    /// be read.
+    fn current_font(&self, _display: u32) -> Option<FontSpec> {
+        None
+    }
+
+    /// Change the font size. Backends that cannot do it live rely on
+    /// `launch_args` picking the size up on the next launch.
+    fn set_font_size(&self, _ctx: &TerminalContext, _size: f32) -> Result<Applied, BackendError> {
+        Ok(Applied::NextLaunch)
+    }
+
+    /// Change the font family and style, with the same fallback as
+    /// `set_font_size`.
+    fn set_font(&self, _ctx: &TerminalContext, _font: &FontSpec) -> Result<Applied, BackendError> {
+        Ok(Applied::NextLaunch)
+    }
+
+    fn set_theme(
+        &self,
+        _ctx: &TerminalContext,
+        _palette: &Palette,
+    ) -> Result<Applied, BackendError> {
+        Err(unsupported(self.name(), "theme changes"))
+    }
+
+    /// Full scrollback text of the terminal.
+    fn capture_scrollback(&self, _ctx: &TerminalContext) -> Result<String, BackendError> {
+        Err(unsupported(self.name(), "scrollback capture"))
+    }
+
+    /// Text currently on screen.
+    fn capture_screen(&self, _ctx: &TerminalContext) -> Result<String, BackendError> {
+        Err(unsupported(self.name(), "screen capture"))
+    }
+}
+
+fn unsupported(backend: &str, what: &str) -> BackendError {
+    BackendError::Unsupported(format!("{} does not support {}", backend, what))
+}
+
+fn bash_args(agent_cmd: &str) -> [String; 3] {
+    ["bash".to_string(), "-c".to_string(), agent_cmd.to_string()]
+}
+
+fn run_tool(mut cmd: Command, what: &str) -> Result<String, BackendError> {
+    let output = cmd
+        .output()
+        .map_err(|e| BackendError::Failed(format!("Failed to run {}: {}", what, e)))?;
+    if !output.status.success() {
+        return Err(BackendError::Failed(format!(
+            "{} failed: {}",
+            what,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Parent pid from the contents of `/proc/<pid>/stat`. The command name in
+/// field 2 may contain spaces, so parse from the last ')'.
+fn parse_ppid(stat: &str) -> Option<u32> {
+    let rest = &stat[stat.rfind(')')? + 1..];
+    rest.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Pty slave the emulator's shell is attached to.
+fn shell_pty(terminal_pid: u32) -> Result<PathBuf, BackendError> {
+    let entries = fs::read_dir("/proc")
+        .map_err(|e| BackendError::Failed(format!("Failed to read /proc: {}", e)))?;
+    for entry in entries.flatten() {
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|s| s.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let Ok(stat) = fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+            continue;
+        };
+        if parse_ppid(&stat) != Some(terminal_pid) {
+            continue;
+        }
+        if let Ok(tty) = fs::read_link(format!("/proc/{}/fd/0", pid))
+            && tty.starts_with("/dev/pts")
+        {
+            return Ok(tty);
+        }
+    }
+    Err(BackendError::Failed(format!(
+        "No shell pty found for terminal pid {}",
+        terminal_pid
+    )))
+}
+
+/// Send an escape sequence to the emulator by writing it to its shell's pty.
+/// Output written to the slave side is parsed by the emulator like anything
+/// the agent prints.
+fn write_escape(ctx: &TerminalContext, sequence: &str) -> Result<(), BackendError> {
+    let pid = ctx
+        .pid
+        .ok_or_else(|| BackendError::Failed("Terminal is not running".to_string()))?;
+    let tty = shell_pty(pid)?;
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&tty)
+        .and_then(|mut f| f.write_all(sequence.as_bytes()))
+        .map_err(|e| BackendError::Failed(format!("Failed to write to {}: {}", tty.display(), e)))
+}
+
+/// OSC 50 "set font" with an Xft pattern, understood by xterm and urxvt.
+fn osc_set_font(pattern: &str) -> String {
+    format!("\x1b]50;xft:{}\x07", pattern)
+}
+
+/// Alacritty is started with a config file per display that imports the
+/// user's `alacritty.toml`, so sessions don't share fonts and colours.
+/// Alacritty reloads its config file on change (live_config_reload), so
+/// editing that file is enough for fonts and colours, and the settings are
+/// already in place for the next launch.
+pub struct Alacritty;
+
+impl Alacritty {
+    fn config_path(display: u32) -> Result<PathBuf, String> {
+        let dir = config::config_dir().ok_or("HOME environment variable not set")?;
+        Ok(dir.join(format!("alacritty-{}.toml", display)))
+    }
+
+    /// Config importing `user_config` with `settings` on top.
+    fn render_config(user_config: &Path, settings: &TerminalSettings) -> Result<String, String> {
+        let base = format!(
+            "# Generated by vnccc, changes will be overwritten.\n[general]\nimport = [{}]\n",
+            Value::from(user_config.display().to_string())
+        );
+        edit_toml(&base, |config| {
+            if let Some(size) = settings.font_size {
+                set_alacritty_font_size(config, size);
+            }
+            if let Some(font) = &settings.font {
+                set_alacritty_font(config, font);
+            }
+            if let Some(palette) = &settings.palette {
+                set_alacritty_palette(config, palette);
+            }
+        })
+    }
+
+    /// `get` from the display's config, or else from the user's, where
+    /// settings not made through vnccc come from.
+    fn read_setting<T>(display: u32, get: fn(&str) -> Option<T>) -> Option<T> {
+        [Alacritty::config_path(display), alacritty_config_path()]
+            .into_iter()
+            .flatten()
+            .find_map(|path| get(&fs::read_to_string(path).ok()?))
+    }
+}
+
+impl TerminalBackend for Alacritty {
+    fn name(&self) -> &'static str {
+        "alacritty"
+    }
+
+    fn prepare_launch(&self, opts: &LaunchOptions) -> Result<(), String> {
+        let contents = Alacritty::render_config(&alacritty_config_path()?, opts.settings)?;
+        config::write_atomic(&Alacritty::config_path(opts.display)?, &contents)
+    }
+
+    fn launch_args(&self, opts: &LaunchOptions) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Ok(path) = Alacritty::config_path(opts.display) {
+            args.extend(["--config-file".to_string(), path.display().to_string()]);
+        }
+        args.push("-e".to_string());
+        args.extend(bash_args(opts.agent_cmd));
+        args
+    }
+
+    fn window_class(&self) -> &'static str {
+        "Alacritty"
+    }
+
+    fn current_font_size(&self, display: u32) -> Option<f32> {
+        Alacritty::read_setting(display, alacritty_font_size)
+    }
+
+    fn current_font(&self, display: u32) -> Option<FontSpec> {
+        Alacritty::read_setting(display, alacritty_font)
+    }
+
+    fn set_font_size(&self, ctx: &TerminalContext, size: f32) -> Result<Applied, BackendError> {
+        edit_alacritty_config(ctx.display, |config| set_alacritty_font_size(config, size))
+            .map_err(BackendError::Failed)?;
+        Ok(Applied::Live)
+    }
+
+    fn set_font(&self, ctx: &TerminalContext, font: &FontSpec) -> Result<Applied, BackendError> {
+        edit_alacritty_config(ctx.display, |config| set_alacritty_font(config, font))
+            .map_err(BackendError::Failed)?;
+        Ok(Applied::Live)
+    }
+
+    fn set_theme(&self, ctx: &TerminalContext, palette: &Palette) -> Result<Applied, BackendError> {
+        edit_alacritty_config(ctx.display, |config| set_alacritty_palette(config, palette))
+            .map_err(BackendError::Failed)?;
+        Ok(Applied::Live)
+    }
+}
+
+pub struct Kitty;
+
+impl Kitty {
+    /// Remote-control socket, one per display so concurrent sessions don't clash.
+    fn socket(display: u32) -> String {
+        format!("unix:/tmp/vncaa-kitty-{}", display)
+    }
+
+    fn remote(display: u32) -> Command {
+        let mut cmd = Command::new("kitty");
+        cmd.env("DISPLAY", format!(":{}", display))
+            .args(["@", "--to", &Kitty::socket(display)]);
+        cmd
+    }
+}
+
+impl TerminalBackend for Kitty {
+    fn name(&self) -> &'static str {
+        "kitty"
+    }
+
+    fn launch_args(&self, opts: &LaunchOptions) -> Vec<String> {
+        let mut overrides = vec!["allow_remote_control=yes".to_string()];
+        if let Some(size) = opts.settings.font_size {
+            overrides.push(format!("font_size={}", size));
+        }
+        if let Some(font) = &opts.settings.font {
+            overrides.push(format!("font_family={}", font.family));
+        }
+        if let Some(palette) = &opts.settings.palette {
+            overrides.push(format!("foreground={}", palette.foreground));
+            overrides.push(format!("background={}", palette.background));
+            for (i, color) in palette.normal.iter().chain(&palette.bright).enumerate() {
+                overrides.push(format!("color{}={}", i, color));
+            }
+        }
+
+        let mut args = Vec::new();
+        for value in overrides {
+            args.extend(["-o".to_string(), value]);
+        }
+        args.extend([
+            "--listen-on".to_string(),
+            Kitty::socket(opts.display),
+            "--start-as".to_string(),
+            "fullscreen".to_string(),
+        ]);
+        args.extend(bash_args(opts.agent_cmd));
+        args
+    }
+
+    fn window_class(&self) -> &'static str {
+        "kitty"
+    }
+
+    fn set_font_size(&self, ctx: &TerminalContext, size: f32) -> Result<Applied, BackendError> {
+        let mut cmd = Kitty::remote(ctx.display);
+        cmd.args(["set-font-size", "--all", &size.to_string()]);
+        run_tool(cmd, "kitty @ set-font-size")?;
+        Ok(Applied::Live)
+    }
+
+    fn set_theme(&self, ctx: &TerminalContext, palette: &Palette) -> Result<Applied, BackendError> {
+        let mut cmd = Kitty::remote(ctx.display);
+        cmd.args(["set-colors", "--all", "--configured"]);
+        cmd.arg(format!("foreground={}", palette.foreground));
+        cmd.arg(format!("background={}", palette.background));
+        for (i, color) in palette.normal.iter().chain(&palette.bright).enumerate() {
+            cmd.arg(format!("color{}={}", i, color));
+        }
+        run_tool(cmd, "kitty @ set-colors")?;
+        Ok(Applied::Live)
+    }
+
+    fn capture_scrollback(&self, ctx: &TerminalContext) -> Result<String, BackendError> {
+        let mut cmd = Kitty::remote(ctx.display);
+        cmd.args(["get-text", "--extent", "all"]);
+        run_tool(cmd, "kitty @ get-text")
+    }
+
+    fn capture_screen(&self, ctx: &TerminalContext) -> Result<String, BackendError> {
+        let mut cmd = Kitty::remote(ctx.display);
+        cmd.args(["get-text", "--extent", "screen"]);
+        run_tool(cmd, "kitty @ get-text")
+    }
+}
+
+/// WezTerm is started with a config file generated by vnccc that loads the
+/// user's own `wezterm.lua` and then applies our overrides. WezTerm watches its
+/// config file, so rewriting it changes the running terminal.
+pub struct Wezterm;
+
+impl Wezterm {
+    fn config_path(display: u32) -> Result<PathBuf, String> {
+        let dir = config::config_dir().ok_or("HOME environment variable not set")?;
+        Ok(dir.join(format!("wezterm-{}.lua", display)))
+    }
+
+    fn render_config(settings: &TerminalSettings) -> String {
+        let mut lua = String::from(
+            "-- Generated by vnccc, changes will be overwritten.\n\
+             local wezterm = require 'wezterm'\n\
+             local config = {}\n\
+             local ok, user = pcall(dofile, wezterm.home_dir .. '/.config/wezterm/wezterm.lua')\n\
+             if ok and type(user) == 'table' then\n  config = user\nend\n",
+        );
+        if let Some(size) = settings.font_size {
+            lua.push_str(&format!("config.font_size = {:?}\n", size));
+        }
+        if let Some(font) = &settings.font {
+            match &font.style {
+                Some(style) => lua.push_str(&format!(
+                    "config.font = wezterm.font('{}', {{ style = '{}' }})\n",
+                    font.family, style
+                )),
+                None => lua.push_str(&format!("config.font = wezterm.font('{}')\n", font.family)),
+            }
+        }
+        if let Some(palette) = &settings.palette {
+            let list = |colors: &[String; 8]| {
+                colors
+                    .iter()
+                    .map(|c| format!("'{}'", c))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            lua.push_str(&format!(
+                "config.colors = {{ foreground = '{}', background = '{}', ansi = {{ {} }}, brights = {{ {} }} }}\n",
+                palette.foreground,
+                palette.background,
+                list(&palette.normal),
+                list(&palette.bright)
+            ));
+        }
+        lua.push_str("return config\n");
+        lua
+    }
+
+    fn write_config(display: u32, settings: &TerminalSettings) -> Result<Applied, BackendError> {
+        let path = Wezterm::config_path(display).map_err(BackendError::Failed)?;
+        config::write_atomic(&path, &Wezterm::render_config(settings))
+            .map_err(BackendError::Failed)?;
+        Ok(Applied::Live)
+    }
+}
+
+impl TerminalBackend for Wezterm {
+    fn name(&self) -> &'static str {
+        "wezterm"
+    }
+
+    fn prepare_launch(&self, opts: &LaunchOptions) -> Result<(), String> {
+        Wezterm::write_config(opts.display, opts.settings)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn launch_args(&self, opts: &LaunchOptions) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Ok(path) = Wezterm::config_path(opts.display) {
+            args.extend(["--config-file".to_string(), path.display().to_string()]);
+        }
+        args.extend([
+            "start".to_string(),
+            "--always-new-process".to_string(),
+            "--".to_string(),
+        ]);
+        args.extend(bash_args(opts.agent_cmd));
+        args
+    }
+
+    fn window_class(&self) -> &'static str {
+        "org.wezfurlong.wezterm"
+    }
+
+    // The context's settings already contain the new value, so every change
+    // is a rewrite of the generated config.
+    fn set_font_size(&self, ctx: &TerminalContext, _size: f32) -> Result<Applied, BackendError> {
+        Wezterm::write_config(ctx.display, &ctx.settings)
+    }
+
+    fn set_font(&self, ctx: &TerminalContext, _font: &FontSpec) -> Result<Applied, BackendError> {
+        Wezterm::write_config(ctx.display, &ctx.settings)
+    }
+
+    fn set_theme(
+        &self,
+        ctx: &TerminalContext,
+        _palette: &Palette,
+    ) -> Result<Applied, BackendError> {
+        Wezterm::write_config(ctx.display, &ctx.settings)
+    }
+
+    fn capture_scrollback(&self, ctx: &TerminalContext) -> Result<String, BackendError> {
+        let mut cmd = Command::new("wezterm");
+        cmd.env("DISPLAY", format!(":{}", ctx.display)).args([
+            "cli",
+            "get-text",
+            "--start-line",
+            &format!("-{}", SCROLLBACK_LINES),
+        ]);
+        run_tool(cmd, "wezterm cli get-text")
+    }
+
+    fn capture_screen(&self, ctx: &TerminalContext) -> Result<String, BackendError> {
+        let mut cmd = Command::new("wezterm");
+        cmd.env("DISPLAY", format!(":{}", ctx.display))
+            .args(["cli", "get-text"]);
+        run_tool(cmd, "wezterm cli get-text")
+    }
+}
+
+pub struct Xterm;
+
+impl TerminalBackend for Xterm {
+    fn name(&self) -> &'static str {
+        "xterm"
+    }
+
+    fn launch_args(&self, opts: &LaunchOptions) -> Vec<String> {
+        let settings = opts.settings;
+        let family = settings
+            .font
+            .as_ref()
+            .map_or("Monospace".to_string(), |f| f.family.clone());
+        let size = settings.font_size.unwrap_or(DEFAULT_FONT_SIZE);
+        let (fg, bg) = settings
+            .palette
+            .as_ref()
+            .map_or(("white".to_string(), "black".to_string()), |p| {
+                (p.foreground.clone(), p.background.clone())
+            });
+
+        let mut args = vec![
+            "-maximized".to_string(),
+            "-fa".to_string(),
+            family,
+            "-fs".to_string(),
+            size.to_string(),
+            "-bg".to_string(),
+            bg,
+            "-fg".to_string(),
+            fg,
+        ];
+        if let Some(palette) = &settings.palette {
+            for (i, color) in palette.normal.iter().chain(&palette.bright).enumerate() {
+                args.extend(["-xrm".to_string(), format!("*color{}: {}", i, color)]);
+            }
+        }
+        args.push("-e".to_string());
+        args.extend(bash_args(opts.agent_cmd));
+        args
+    }
+
+    fn window_class(&self) -> &'static str {
+        "XTerm"
+    }
+
+    fn set_font_size(&self, ctx: &TerminalContext, _size: f32) -> Result<Applied, BackendError> {
+        write_escape(ctx, &osc_set_font(&ctx.settings.xft_pattern("Monospace")))?;
+        Ok(Applied::Live)
+    }
+
+    fn set_font(&self, ctx: &TerminalContext, _font: &FontSpec) -> Result<Applied, BackendError> {
+        write_escape(ctx, &osc_set_font(&ctx.settings.xft_pattern("Monospace")))?;
+        Ok(Applied::Live)
+    }
+
+    fn set_theme(&self, ctx: &TerminalContext, palette: &Palette) -> Result<Applied, BackendError> {
+        write_escape(ctx, &palette.osc_sequences())?;
+        Ok(Applied::Live)
+    }
+}
+
+pub struct Urxvt;
+
+impl TerminalBackend for Urxvt {
+    fn name(&self) -> &'static str {
+        "urxvt"
+    }
+
+    fn launch_args(&self, opts: &LaunchOptions) -> Vec<String> {
+        let settings = opts.settings;
+        let mut args = vec!["-geometry".to_string(), opts.geometry.to_string()];
+        if settings.font_size.is_some() || settings.font.is_some() {
+            args.extend([
+                "-fn".to_string(),
+                format!("xft:{}", settings.xft_pattern("Monospace")),
+            ]);
+        }
+        if let Some(palette) = &settings.palette {
+            args.extend([
+                "-fg".to_string(),
+                palette.foreground.clone(),
+                "-bg".to_string(),
+                palette.background.clone(),
+            ]);
+            for (i, color) in palette.normal.iter().chain(&palette.bright).enumerate() {
+                args.extend([format!("-color{}", i), color.clone()]);
+            }
+        }
+        args.push("-e".to_string());
+        args.extend(bash_args(opts.agent_cmd));
+        args
+    }
+
+    fn window_class(&self) -> &'static str {
+        "URxvt"
+    }
+
+    fn set_font_size(&self, ctx: &TerminalContext, _size: f32) -> Result<Applied, BackendError> {
+        write_escape(ctx, &osc_set_font(&ctx.settings.xft_pattern("Monospace")))?;
+        Ok(Applied::Live)
+    }
+
+    fn set_font(&self, ctx: &TerminalContext, _font: &FontSpec) -> Result<Applied, BackendError> {
+        write_escape(ctx, &osc_set_font(&ctx.settings.xft_pattern("Monospace")))?;
+        Ok(Applied::Live)
+    }
+
+    fn set_theme(&self, ctx: &TerminalContext, palette: &Palette) -> Result<Applied, BackendError> {
+        write_escape(ctx, &palette.osc_sequences())?;
+        Ok(Applied::Live)
+    }
+}
+
+/// st has no runtime font control; the font is passed with `-f` on launch.
+/// Colours can be changed live with OSC sequences but are not kept across a
+/// relaunch, since st has no command-line option for them.
+pub struct St;
+
+impl TerminalBackend for St {
+    fn name(&self) -> &'static str {
+        "st"
+    }
+
+    fn launch_args(&self, opts: &LaunchOptions) -> Vec<String> {
+        let settings = opts.settings;
+        let mut args = Vec::new();
+        if settings.font_size.is_some() || settings.font.is_some() {
+            args.extend(["-f".to_string(), settings.xft_pattern("monospace")]);
+        }
+        args.push("-e".to_string());
+        args.extend(bash_args(opts.agent_cmd));
+        args
+    }
+
+    fn window_class(&self) -> &'static str {
+        "st-256color"
+    }
+
+    fn set_theme(&self, ctx: &TerminalContext, palette: &Palette) -> Result<Applied, BackendError> {
+        write_escape(ctx, &palette.osc_sequences())?;
+        Ok(Applied::Live)
+    }
+}
+
+pub fn backend_by_name(name: &str) -> Option<Box<dyn TerminalBackend>> {
+    match name {
+        "alacritty" => Some(Box::new(Alacritty)),
+        "kitty" => Some(Box::new(Kitty)),
+        "wezterm" => Some(Box::new(Wezterm)),
+        "xterm" => Some(Box::new(Xterm)),
+        "urxvt" => Some(Box::new(Urxvt)),
+        "st" => Some(Box::new(St)),
+        _ => None,
+    }
+}
+
+/// True if `name` is an executable file in one of the `path_var` directories.
+pub(crate) fn is_on_path(name: &str, path_var: &str) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    env::split_paths(path_var).any(|dir| {
+        fs::metadata(dir.join(name))
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    })
+}
+
+fn detect_backend(path_var: &str) -> Option<Box<dyn TerminalBackend>> {
+    BACKEND_NAMES
+        .iter()
+        .find(|name| is_on_path(name, path_var))
+        .and_then(|name| backend_by_name(name))
+}
+
+/// Pick the backend named in config, or the first supported emulator on PATH.
+/// Falls back to Alacritty when nothing is found so the error surfaces at
+/// launch with the emulator's name in it.
+pub fn select_backend(configured: Option<&str>) -> Result<Box<dyn TerminalBackend>, String> {
+    if let Some(name) = configured {
+        return backend_by_name(name).ok_or_else(|| {
+            format!(
+                "Unknown terminal '{}', expected one of: {}",
+                name,
+                BACKEND_NAMES.join(", ")
+            )
+        });
+    }
+
+    let path_var = env::var("PATH").unwrap_or_default();
+    Ok(detect_backend(&path_var).unwrap_or_else(|| {
+        eprintln!("No supported terminal found on PATH, defaulting to alacritty");
+        Box::new(Alacritty)
+    }))
+}
+
+/// Monospace font families known to fontconfig, sorted and deduplicated.
+/// Empty when `fc-list` is unavailable.
+pub fn monospace_families() -> Vec<String> {
+    Command::new("fc-list")
+        .args([":spacing=mono", "family"])
+        .output()
+        .map(|output| parse_font_families(&String::from_utf8_lossy(&output.stdout)))
+        .unwrap_or_default()
+}
+
+/// `fc-list` prints one line per font with localized names comma separated;
+/// keep the first (default) name of each.
+fn parse_font_families(output: &str) -> Vec<String> {
+    let mut families: Vec<String> = output
+        .lines()
+        .filter_map(|line| line.split(',').next())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+    families.sort();
+    families.dedup();
+    families
+}
+
+fn alacritty_config_path() -> Result<PathBuf, String> {
+    let home = env::var("HOME").map_err(|_| "HOME environment variable not set".to_string())?;
+    Ok(PathBuf::from(home).join(".config/alacritty/alacritty.toml"))
+}
+
+/// Sub-table `key` of `parent`, created (or replacing a non-table value) as
+/// needed. New tables match the parent's style: `[header]` tables are added
+/// as implicit so `[colors]` isn't emitted just to hold `[colors.primary]`.
+fn table_item<'a>(parent: &'a mut Item, key: &str) -> &'a mut Item {
+    let inline = parent.is_inline_table();
+    let child = &mut parent[key];
+    if !child.is_table_like() {
+        *child = if inline {
+            Item::Value(Value::InlineTable(InlineTable::new()))
+        } else {
+            let mut table = Table::new();
+            table.set_implicit(true);
+            Item::Table(table)
+        };
+    }
+    child
+}
+
+/// Set `table[key]`, keeping any comment or spacing attached to the old value.
+fn set_value(table: &mut Item, key: &str, value: impl Into<Value>) {
+    let mut value = value.into();
+    if let Some(old) = table.get(key).and_then(Item::as_value) {
+        *value.decor_mut() = old.decor().clone();
+    }
+    table[key] = Item::Value(value);
+}
+
+/// Apply `edit` to a TOML document without disturbing comments, key order or
+/// inline tables elsewhere in it.
+fn edit_toml(contents: &str, edit: impl FnOnce(&mut Item)) -> Result<String, String> {
+    let mut doc: DocumentMut = contents
+        .parse()
+        .map_err(|e| format!("Failed to parse alacritty config: {}", e))?;
+    edit(doc.as_item_mut());
+    Ok(doc.to_string())
+}
+
+/// Read the Alacritty config of `display`, apply `edit` and atomically write
+/// it back. A missing file is created.
+fn edit_alacritty_config(display: u32, edit: impl FnOnce(&mut Item)) -> Result<(), String> {
+    let config_path = Alacritty::config_path(display)?;
+
+    let contents = match fs::read_to_string(&config_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Failed to read alacritty config: {}", e)),
+    };
+
+    let new_contents = edit_toml(&contents, edit)?;
+    config::write_atomic(&config_path, &new_contents)
+}
+
+fn set_alacritty_font_size(config: &mut Item, size: f32) {
+    set_value(table_item(config, "font"), "size", size as f64);
+}
+
+fn set_alacritty_font(config: &mut Item, font: &FontSpec) {
+    let normal = table_item(table_item(config, "font"), "normal");
+    set_value(normal, "family", &font.family);
+    match &font.style {
+        Some(style) => set_value(normal, "style", style),
+        None => {
+            if let Some(table) = normal.as_table_like_mut() {
+                table.remove("style");
+            }
+        }
+    }
+}
+
+fn set_alacritty_palette(config: &mut Item, palette: &Palette) {
+    let colors = table_item(config, "colors");
+    let primary = table_item(colors, "primary");
+    set_value(primary, "foreground", &palette.foreground);
+    set_value(primary, "background", &palette.background);
+    for (section, values) in [("normal", &palette.normal), ("bright", &palette.bright)] {
+        let table = table_item(colors, section);
+        for (name, value) in COLOR_NAMES.iter().zip(values) {
+            set_value(table, name, value);
+        }
+    }
+}
+
+/// `font.normal` family and style from an Alacritty config.
+fn alacritty_font(contents: &str) -> Option<FontSpec> {
+    let doc: DocumentMut = contents.parse().ok()?;
+    let normal = doc.get("font")?.get("normal")?;
+    Some(FontSpec {
+        family: normal.get("family")?.as_str()?.to_string(),
+        style: normal
+            .get("style")
+            .and_then(Item::as_str)
+            .map(str::to_string),
+    })
+}
+
+/// `font.size` from an Alacritty config, which may be written as an integer.
+fn alacritty_font_size(contents: &str) -> Option<f32> {
+    let doc: DocumentMut = contents.parse().ok()?;
+    let size = doc.get("font")?.get("size")?.as_value()?;
+    size.as_float()
+        .or_else(|| size.as_integer().map(|i| i as f64))
+        .map(|s| s as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn fake_bin_dir(name: &str, bins: &[&str]) -> std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("vnccc-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for bin in bins {
+            let path = dir.join(bin);
+            fs::write(&path, "#!/bin/sh\n").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_backend_by_name() {
+        for name in BACKEND_NAMES {
+            assert_eq!(backend_by_name(name).unwrap().name(), name);
+        }
+        assert!(backend_by_name("gnome-terminal").is_none());
+    }
+
+    #[test]
+    fn test_select_backend_from_config() {
+        assert_eq!(select_backend(Some("kitty")).unwrap().name(), "kitty");
+        let err = select_backend(Some("konsole")).err().unwrap();
+        assert!(err.contains("Unknown terminal 'konsole'"));
+    }
+
+    #[test]
+    fn test_detect_backend_prefers_order() {
+        let dir = fake_bin_dir("order", &["xterm", "kitty"]);
+        let backend = detect_backend(dir.to_str().unwrap()).unwrap();
+        assert_eq!(backend.name(), "kitty");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_detect_backend_ignores_non_executables() {
+        let dir = fake_bin_dir("noexec", &["st"]);
+        fs::write(dir.join("alacritty"), "").unwrap();
+        let backend = detect_backend(dir.to_str().unwrap()).unwrap();
+        assert_eq!(backend.name(), "st");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_detect_backend_none() {
+        let dir = fake_bin_dir("empty", &[]);
+        assert!(detect_backend(dir.to_str().unwrap()).is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn launch(settings: &TerminalSettings) -> LaunchOptions<'_> {
+        LaunchOptions {
+            display: 3,
+            geometry: "800x600",
+            agent_cmd: "agent",
+            settings,
+        }
+    }
+
+    fn sized(font_size: f32) -> TerminalSettings {
+        TerminalSettings {
+            font_size: Some(font_size),
+            ..Default::default()
+        }
+    }
+
+    fn test_palette() -> Palette {
+        Palette {
+            foreground: "#ffffff".to_string(),
+            background: "#000000".to_string(),
+            normal: std::array::from_fn(|i| format!("#00000{}", i)),
+            bright: std::array::from_fn(|i| format!("#11111{}", i)),
+        }
+    }
+
+    #[test]
+    fn test_launch_args() {
+        let defaults = TerminalSettings::default();
+        assert!(Alacritty.launch_args(&launch(&defaults)).ends_with(&[
+            "-e".into(),
+            "bash".into(),
+            "-c".into(),
+            "agent".into()
+        ]));
+        assert_eq!(
+            Urxvt.launch_args(&launch(&defaults)),
+            vec!["-geometry", "800x600", "-e", "bash", "-c", "agent"]
+        );
+        let kitty = Kitty.launch_args(&launch(&defaults));
+        assert!(kitty.contains(&"unix:/tmp/vncaa-kitty-3".to_string()));
+        assert!(kitty.ends_with(&["bash".into(), "-c".into(), "agent".into()]));
+    }
+
+    #[test]
+    fn test_launch_args_font_size() {
+        let kitty = Kitty.launch_args(&launch(&sized(18.0)));
+        assert!(kitty.contains(&"font_size=18".to_string()));
+        let xterm = Xterm.launch_args(&launch(&sized(18.5)));
+        let fs = xterm.iter().position(|a| a == "-fs").unwrap();
+        assert_eq!(xterm[fs + 1], "18.5");
+        assert_eq!(
+            St.launch_args(&launch(&sized(12.0)))[..2],
+            ["-f", "monospace:size=12"]
+        );
+        assert_eq!(
+            St.launch_args(&launch(&TerminalSettings::default()))[0],
+            "-e"
+        );
+    }
+
+    #[test]
+    fn test_launch_args_font_and_palette() {
+        let settings = TerminalSettings {
+            font: Some(FontSpec {
+                family: "DejaVu Sans Mono".to_string(),
+                style: Some("Bold".to_string()),
+            }),
+            palette: Some(test_palette()),
+            ..Default::default()
+        };
+        assert_eq!(
+            St.launch_args(&launch(&settings))[1],
+            "DejaVu Sans Mono:style=Bold:size=14"
+        );
+        let kitty = Kitty.launch_args(&launch(&settings));
+        assert!(kitty.contains(&"font_family=DejaVu Sans Mono".to_string()));
+        assert!(kitty.contains(&"color15=#111117".to_string()));
+        let urxvt = Urxvt.launch_args(&launch(&settings));
+        assert!(urxvt.contains(&"xft:DejaVu Sans Mono:style=Bold:size=14".to_string()));
+        assert!(urxvt.contains(&"-color0".to_string()));
+        let xterm = Xterm.launch_args(&launch(&settings));
+        assert!(xterm.contains(&"*color8: #111110".to_string()));
+    }
+
+    #[test]
+    fn test_alacritty_config_overrides() {
+        let user = Path::new("/home/me/.config/alacritty/alacritty.toml");
+        let defaults = Alacritty::render_config(user, &TerminalSettings::default()).unwrap();
+        assert!(defaults.contains("import = [\"/home/me/.config/alacritty/alacritty.toml\"]"));
+        assert_eq!(alacritty_font_size(&defaults), None);
+
+        let settings = TerminalSettings {
+            font: Some(FontSpec {
+                family: "Hack".to_string(),
+                style: None,
+            }),
+            palette: Some(test_palette()),
+            ..sized(16.0)
+        };
+        let toml = Alacritty::render_config(user, &settings).unwrap();
+        assert_eq!(alacritty_font_size(&toml), Some(16.0));
+        assert_eq!(alacritty_font(&toml).unwrap().family, "Hack");
+        assert!(toml.contains("white = \"#111117\""));
+
+        let args = Alacritty.launch_args(&launch(&settings));
+        let config = args.iter().position(|a| a == "--config-file").unwrap();
+        assert!(args[config + 1].ends_with("alacritty-3.toml"));
+    }
+
+    #[test]
+    fn test_wezterm_config_overrides() {
+        let lua = Wezterm::render_config(&sized(16.0));
+        assert!(lua.contains("pcall(dofile"));
+        assert!(lua.contains("config.font_size = 16.0\n"));
+        assert!(!Wezterm::render_config(&TerminalSettings::default()).contains("font_size"));
+
+        let settings = TerminalSettings {
+            font: Some(FontSpec {
+                family: "Hack".to_string(),
+                style: None,
+            }),
+            palette: Some(test_palette()),
+            ..Default::default()
+        };
+        let lua = Wezterm::render_config(&settings);
+        assert!(lua.contains("config.font = wezterm.font('Hack')\n"));
+        assert!(lua.contains("foreground = '#ffffff'"));
+        assert!(lua.contains("brights = { '#111110', "));
+    }
+
+    #[test]
+    fn test_palette_validate() {
+        assert!(test_palette().validate().is_ok());
+        let mut palette = test_palette();
+        palette.normal[3] = "red".to_string();
+        assert!(palette.validate().unwrap_err().contains("'red'"));
+        palette.normal[3] = "#12345g".to_string();
+        assert!(palette.validate().is_err());
+    }
+
+    #[test]
+    fn test_palette_osc_sequences() {
+        let seq = test_palette().osc_sequences();
+        assert!(seq.starts_with("\x1b]10;#ffffff\x07\x1b]11;#000000\x07"));
+        assert!(seq.ends_with("\x1b]4;15;#111117\x07"));
+    }
+
+    #[test]
+    fn test_font_spec_validate() {
+        let font = |family: &str, style: Option<&str>| FontSpec {
+            family: family.to_string(),
+            style: style.map(str::to_string),
+        };
+        assert!(font("DejaVu Sans Mono", Some("Book")).validate().is_ok());
+        assert!(font("", None).validate().is_err());
+        assert!(font("Mono:size=99", None).validate().is_err());
+        assert!(font("Hack'); os.exit()", None).validate().is_err());
+        assert!(font("Hack", Some("-bold")).validate().is_err());
+    }
+
+    const ALACRITTY_SAMPLE: &str = "# Window settings\n\
+        [window]\n\
+        padding = { x = 4, y = 4 } # keep tight\n\
+        \n\
+        [font]\n\
+        size = 20.0 # default size\n\
+        \n\
+        [font.normal]\n\
+        family = \"monospace\"\n";
+
+    #[test]
+    fn test_parse_font_families() {
+        let output = "DejaVu Sans Mono\nNoto Sans Mono,Noto Sans Mono CJK\n\nDejaVu Sans Mono\n";
+        assert_eq!(
+            parse_font_families(output),
+            vec!["DejaVu Sans Mono".to_string(), "Noto Sans Mono".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_edit_toml_preserves_layout() {
+        let edited = edit_toml(ALACRITTY_SAMPLE, |config| {
+            set_value(table_item(config, "font"), "size", 14.5);
+        })
+        .unwrap();
+        assert_eq!(
+            edited,
+            ALACRITTY_SAMPLE.replace("size = 20.0 # default", "size = 14.5 # default")
+        );
+    }
+
+    #[test]
+    fn test_edit_toml_creates_missing_tables() {
+        let original = "# Window\n[window]\ndecorations = \"None\"\n";
+        let edited = edit_toml(original, |config| {
+            set_value(table_item(config, "font"), "size", 12.0);
+            let colors = table_item(config, "colors");
+            set_value(table_item(colors, "primary"), "background", "#000000");
+        })
+        .unwrap();
+        assert!(edited.starts_with(original));
+        assert_eq!(alacritty_font_size(&edited), Some(12.0));
+        assert!(edited.contains("[colors.primary]\nbackground = \"#000000\""));
+        assert!(!edited.contains("[colors]\n"));
+    }
+
+    #[test]
+    fn test_edit_toml_inline_table() {
+        let edited = edit_toml("font = { size = 10 }\n", |config| {
+            set_value(table_item(config, "font"), "size", 11.0);
+        })
+        .unwrap();
+        assert_eq!(edited, "font = { size = 11.0 }\n");
+    }
+
+    #[test]
+    fn test_edit_toml_rejects_invalid() {
+        assert!(edit_toml("[font\n", |_| {}).is_err());
+    }
+
+    #[test]
+    fn test_alacritty_font_size() {
+        assert_eq!(alacritty_font_size(ALACRITTY_SAMPLE), Some(20.0));
+        assert_eq!(alacritty_font_size("[font]\nsize = 16\n"), Some(16.0));
+        assert_eq!(alacritty_font_size("[window]\n"), None);
+    }
+
+    #[test]
+    fn test_alacritty_font() {
+        assert_eq!(
+            alacritty_font(ALACRITTY_SAMPLE),
+            Some(FontSpec {
+                family: "monospace".to_string(),
+                style: None,
+            })
+        );
+        let edited = edit_toml(ALACRITTY_SAMPLE, |config| {
+            let normal = table_item(table_item(config, "font"), "normal");
+            set_value(normal, "style", "Bold");
+        })
+        .unwrap();
+        assert_eq!(
+            alacritty_font(&edited).unwrap().style.as_deref(),
+            Some("Bold")
+        );
+    }
+
+    #[test]
+    fn test_parse_ppid() {
+        assert_eq!(parse_ppid("42 (bash) S 7 42 42 0"), Some(7));
+        assert_eq!(parse_ppid("42 (my (odd) cmd) S 9 42"), Some(9));
+        assert_eq!(parse_ppid("garbage"), None);
+    }
+
+    #[test]
+    fn test_unsupported_operations() {
+        let ctx = TerminalContext {
+            display: 1,
+            pid: None,
+            settings: TerminalSettings::default(),
+        };
+        assert!(matches!(
+            St.capture_scrollback(&ctx),
+            Err(BackendError::Unsupported(_))
+        ));
+        assert_eq!(St.set_font_size(&ctx, 12.0), Ok(Applied::NextLaunch));
+        assert!(matches!(
+            Xterm.set_font_size(&ctx, 12.0),
+            Err(BackendError::Failed(_))
+        ));
+    }
+}
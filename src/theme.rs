@@ -0,0 +1,272 @@
+//! Terminal colour themes: the built-in palettes and the user's own, loaded
+//! from Alacritty-format files in `~/.config/vncaa/themes`.
+
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+use crate::config;
+use crate::terminal::{COLOR_NAMES, Palette};
+
+/// A named palette, either built in or loaded from `~/.config/vncaa/themes`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Theme {
+    pub name: String,
+    pub builtin: bool,
+    pub palette: Palette,
+}
+
+fn palette(foreground: &str, background: &str, normal: [&str; 8], bright: [&str; 8]) -> Palette {
+    Palette {
+        foreground: foreground.to_string(),
+        background: background.to_string(),
+        normal: normal.map(str::to_string),
+        bright: bright.map(str::to_string),
+    }
+}
+
+/// Built-in themes. `dark` is the Tokyo Night palette shipped in
+/// `alacritty.toml`; the others are meant for bright rooms and sunlight.
+pub fn builtin_themes() -> Vec<Theme> {
+    let themes = [
+        (
+            "dark",
+            palette(
+                "#c0caf5",
+                "#1a1b26",
+                [
+                    "#15161e", "#f7768e", "#9ece6a", "#e0af68", "#7aa2f7", "#bb9af7", "#7dcfff",
+                    "#a9b1d6",
+                ],
+                [
+                    "#414868", "#f7768e", "#9ece6a", "#e0af68", "#7aa2f7", "#bb9af7", "#7dcfff",
+                    "#c0caf5",
+                ],
+            ),
+        ),
+        (
+            "light",
+            palette(
+                "#3760bf",
+                "#e1e2e7",
+                [
+                    "#e9e9ed", "#f52a65", "#587539", "#8c6c3e", "#2e7de9", "#9854f1", "#007197",
+                    "#6172b0",
+                ],
+                [
+                    "#a1a6c5", "#f52a65", "#587539", "#8c6c3e", "#2e7de9", "#9854f1", "#007197",
+                    "#3760bf",
+                ],
+            ),
+        ),
+        (
+            "high-contrast",
+            palette(
+                "#ffffff",
+                "#000000",
+                [
+                    "#000000", "#ff5555", "#55ff55", "#ffff55", "#5599ff", "#ff55ff", "#55ffff",
+                    "#ffffff",
+                ],
+                [
+                    "#808080", "#ff8080", "#80ff80", "#ffff80", "#80b3ff", "#ff80ff", "#80ffff",
+                    "#ffffff",
+                ],
+            ),
+        ),
+        (
+            "high-contrast-light",
+            palette(
+                "#000000",
+                "#ffffff",
+                [
+                    "#000000", "#b00000", "#006400", "#7a5c00", "#0000c0", "#8b008b", "#005f7f",
+                    "#404040",
+                ],
+                [
+                    "#555555", "#d00000", "#008000", "#8b6b00", "#1c1cff", "#a000a0", "#007a99",
+                    "#000000",
+                ],
+            ),
+        ),
+    ];
+    themes
+        .into_iter()
+        .map(|(name, palette)| Theme {
+            name: name.to_string(),
+            builtin: true,
+            palette,
+        })
+        .collect()
+}
+
+/// Parse a theme file in Alacritty's format: `[colors.primary]` with
+/// foreground/background, and `[colors.normal]`/`[colors.bright]` with the
+/// eight ANSI colour names. Files from the alacritty-theme project work as is.
+fn parse_theme(contents: &str) -> Result<Palette, String> {
+    let doc: toml::Table = toml::from_str(contents).map_err(|e| e.to_string())?;
+    let colors = doc.get("colors").ok_or("missing [colors] table")?;
+    let color = |section: &str, name: &str| -> Result<String, String> {
+        colors
+            .get(section)
+            .and_then(|s| s.get(name))
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+            .ok_or_else(|| format!("missing colors.{}.{}", section, name))
+    };
+    let ansi = |section: &str| -> Result<[String; 8], String> {
+        let mut values: [String; 8] = Default::default();
+        for (value, name) in values.iter_mut().zip(COLOR_NAMES) {
+            *value = color(section, name)?;
+        }
+        Ok(values)
+    };
+
+    let palette = Palette {
+        foreground: color("primary", "foreground")?,
+        background: color("primary", "background")?,
+        normal: ansi("normal")?,
+        bright: ansi("bright")?,
+    };
+    palette.validate()?;
+    Ok(palette)
+}
+
+/// Load `*.toml` themes from `dir`, skipping (and logging) invalid files.
+fn load_user_themes(dir: &Path) -> Vec<Theme> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut themes = Vec::new();
+    for path in entries.flatten().map(|e| e.path()) {
+        if path.extension().is_none_or(|ext| ext != "toml") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        match fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| parse_theme(&contents))
+        {
+            Ok(palette) => themes.push(Theme {
+                name: name.to_string(),
+                builtin: false,
+                palette,
+            }),
+            Err(e) => eprintln!("Skipping theme {}: {}", path.display(), e),
+        }
+    }
+    themes.sort_by(|a, b| a.name.cmp(&b.name));
+    themes
+}
+
+fn merge_themes(user: Vec<Theme>) -> Vec<Theme> {
+    let mut themes = builtin_themes();
+    for theme in user {
+        // A user theme with a built-in name replaces the built-in
+        themes.retain(|t| t.name != theme.name);
+        themes.push(theme);
+    }
+    themes
+}
+
+/// Built-in themes followed by the user's, read fresh so new files show up
+/// without a restart.
+pub fn list_themes() -> Vec<Theme> {
+    let user = config::config_dir()
+        .map(|dir| load_user_themes(&dir.join("themes")))
+        .unwrap_or_default();
+    merge_themes(user)
+}
+
+pub fn find_theme(name: &str) -> Option<Theme> {
+    list_themes().into_iter().find(|t| t.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const THEME_FILE: &str = r##"
+[colors.primary]
+background = "#282828"
+foreground = "#ebdbb2"
+
+[colors.normal]
+black = "#282828"
+red = "#cc241d"
+green = "#98971a"
+yellow = "#d79921"
+blue = "#458588"
+magenta = "#b16286"
+cyan = "#689d6a"
+white = "#a89984"
+
+[colors.bright]
+black = "#928374"
+red = "#fb4934"
+green = "#b8bb26"
+yellow = "#fabd2f"
+blue = "#83a598"
+magenta = "#d3869b"
+cyan = "#8ec07c"
+white = "#ebdbb2"
+"##;
+
+    #[test]
+    fn test_builtin_themes_are_valid() {
+        let themes = builtin_themes();
+        for name in ["dark", "light", "high-contrast"] {
+            assert!(themes.iter().any(|t| t.name == name), "missing {}", name);
+        }
+        for theme in themes {
+            theme.palette.validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_parse_theme() {
+        let palette = parse_theme(THEME_FILE).unwrap();
+        assert_eq!(palette.background, "#282828");
+        assert_eq!(palette.normal[1], "#cc241d");
+        assert_eq!(palette.bright[7], "#ebdbb2");
+    }
+
+    #[test]
+    fn test_parse_theme_errors() {
+        assert!(parse_theme("").unwrap_err().contains("[colors]"));
+        let missing = THEME_FILE.replace("cyan = \"#689d6a\"\n", "");
+        assert!(
+            parse_theme(&missing)
+                .unwrap_err()
+                .contains("colors.normal.cyan")
+        );
+        let invalid = THEME_FILE.replace("#cc241d", "crimson");
+        assert!(parse_theme(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_load_user_themes() {
+        let dir = env::temp_dir().join(format!("vnccc-test-themes-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("gruvbox.toml"), THEME_FILE).unwrap();
+        fs::write(dir.join("light.toml"), THEME_FILE).unwrap();
+        fs::write(dir.join("broken.toml"), "[colors").unwrap();
+        fs::write(dir.join("notes.txt"), "not a theme").unwrap();
+
+        let themes = merge_themes(load_user_themes(&dir));
+        let light: Vec<_> = themes.iter().filter(|t| t.name == "light").collect();
+        assert_eq!(light.len(), 1);
+        assert!(!light[0].builtin);
+        assert!(themes.iter().any(|t| t.name == "gruvbox"));
+        assert!(
+            !themes
+                .iter()
+                .any(|t| t.name == "broken" || t.name == "notes")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
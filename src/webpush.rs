@@ -0,0 +1,399 @@
+//! Web Push (RFC 8030) to browsers that subscribed from the web UI, with
+//! VAPID authentication (RFC 8292) and an encrypted payload (RFC 8291). The
+//! VAPID key and the subscriptions are kept in the state directory.
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes128Gcm, Nonce};
+use base64::Engine as _;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hkdf::Hkdf;
+use p256::ecdsa::signature::Signer as _;
+use p256::ecdsa::{Signature, SigningKey};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::notify;
+
+/// Record size in the encrypted payload's header. The payload is sent as a
+/// single record, so it only has to be larger than that.
+const RECORD_SIZE: u32 = 4096;
+/// How long push services keep a notification for an offline browser.
+const TTL_SECS: u64 = 24 * 60 * 60;
+
+/// What `PushManager.subscribe()` returns in the browser, as JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Subscription {
+    pub endpoint: String,
+    pub keys: Keys,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Keys {
+    /// The browser's public key, base64url.
+    pub p256dh: String,
+    /// Shared authentication secret, base64url.
+    pub auth: String,
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, String> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|e| format!("Invalid base64url: {}", e))
+}
+
+fn hkdf(salt: &[u8], ikm: &[u8], info: &[u8], okm: &mut [u8]) {
+    Hkdf::<Sha256>::new(Some(salt), ikm)
+        .expand(info, okm)
+        .expect("HKDF output length is valid");
+}
+
+/// Encrypt `payload` for the browser behind `keys` as one aes128gcm record,
+/// using the server key `ephemeral` and `salt`, both fresh for each message.
+fn encrypt(
+    keys: &Keys,
+    payload: &[u8],
+    ephemeral: &SecretKey,
+    salt: [u8; 16],
+) -> Result<Vec<u8>, String> {
+    let ua_public = decode(&keys.p256dh)?;
+    let auth = decode(&keys.auth)?;
+    let ua_key = PublicKey::from_sec1_bytes(&ua_public)
+        .map_err(|_| "Invalid subscription key".to_string())?;
+    let as_public = ephemeral.public_key().to_encoded_point(false);
+    let shared = p256::ecdh::diffie_hellman(ephemeral.to_nonzero_scalar(), ua_key.as_affine());
+
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(&ua_public);
+    key_info.extend_from_slice(as_public.as_bytes());
+    let mut ikm = [0u8; 32];
+    hkdf(&auth, shared.raw_secret_bytes(), &key_info, &mut ikm);
+    let mut cek = [0u8; 16];
+    hkdf(&salt, &ikm, b"Content-Encoding: aes128gcm\0", &mut cek);
+    let mut nonce = [0u8; 12];
+    hkdf(&salt, &ikm, b"Content-Encoding: nonce\0", &mut nonce);
+
+    // The 2 marks the last (and only) record
+    let mut plaintext = payload.to_vec();
+    plaintext.push(2);
+    let ciphertext = Aes128Gcm::new_from_slice(&cek)
+        .map_err(|e| e.to_string())?
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+        .map_err(|e| e.to_string())?;
+
+    let mut body = salt.to_vec();
+    body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    body.push(as_public.as_bytes().len() as u8);
+    body.extend_from_slice(as_public.as_bytes());
+    body.extend_from_slice(&ciphertext);
+    Ok(body)
+}
+
+/// `scheme://host[:port]` of `url`, the audience of the VAPID token.
+fn origin(url: &str) -> Option<&str> {
+    let after_scheme = url.find("://")? + 3;
+    let end = url[after_scheme..]
+        .find('/')
+        .map_or(url.len(), |i| after_scheme + i);
+    Some(&url[..end])
+}
+
+pub struct WebPush {
+    key: SigningKey,
+    /// Uncompressed public key, base64url, as `applicationServerKey`.
+    public_key: String,
+    subject: String,
+    file: PathBuf,
+    subscriptions: Mutex<Vec<Subscription>>,
+}
+
+impl WebPush {
+    /// Load the VAPID key from `dir`, creating one the first time, and the
+    /// subscriptions saved there.
+    pub fn load(dir: &Path, subject: &str) -> Result<WebPush, String> {
+        let key_file = dir.join("vapid.key");
+        let secret = match fs::read_to_string(&key_file) {
+            Ok(encoded) => SecretKey::from_slice(&decode(encoded.trim())?)
+                .map_err(|_| format!("{} holds no valid key", key_file.display()))?,
+            Err(_) => {
+                let secret = SecretKey::random(&mut OsRng);
+                config::write_private(&key_file, &URL_SAFE_NO_PAD.encode(secret.to_bytes()))?;
+                println!("Created Web Push key {}", key_file.display());
+                secret
+            }
+        };
+        let public_key =
+            URL_SAFE_NO_PAD.encode(secret.public_key().to_encoded_point(false).as_bytes());
+        let file = dir.join("push_subscriptions.json");
+        let subscriptions = fs::read_to_string(&file)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        Ok(WebPush {
+            key: SigningKey::from(secret),
+            public_key,
+            subject: subject.to_string(),
+            file,
+            subscriptions: Mutex::new(subscriptions),
+        })
+    }
+
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    /// Number of browsers subscribed.
+    pub fn subscribed(&self) -> usize {
+        self.subscriptions.lock().unwrap().len()
+    }
+
+    fn save(&self, subscriptions: &[Subscription]) -> Result<(), String> {
+        let contents = serde_json::to_string_pretty(subscriptions).map_err(|e| e.to_string())?;
+        config::write_atomic(&self.file, &contents)
+    }
+
+    /// Add or replace the subscription for its endpoint.
+    pub fn subscribe(&self, subscription: Subscription) -> Result<(), String> {
+        if origin(&subscription.endpoint).is_none_or(|o| !o.starts_with("https://")) {
+            return Err("The endpoint must be an https URL".to_string());
+        }
+        if decode(&subscription.keys.p256dh)
+            .ok()
+            .and_then(|key| PublicKey::from_sec1_bytes(&key).ok())
+            .is_none()
+            || decode(&subscription.keys.auth).is_err()
+        {
+            return Err("Invalid subscription keys".to_string());
+        }
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.retain(|s| s.endpoint != subscription.endpoint);
+        subscriptions.push(subscription);
+        self.save(&subscriptions)
+    }
+
+    /// Drop the subscription for `endpoint`; false if there was none.
+    pub fn unsubscribe(&self, endpoint: &str) -> Result<bool, String> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let before = subscriptions.len();
+        subscriptions.retain(|s| s.endpoint != endpoint);
+        if subscriptions.len() == before {
+            return Ok(false);
+        }
+        self.save(&subscriptions).map(|()| true)
+    }
+
+    /// `Authorization` header for a push to `endpoint`.
+    fn vapid(&self, endpoint: &str) -> Result<String, String> {
+        let audience = origin(endpoint).ok_or("Invalid endpoint")?;
+        let expires = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+            + 12 * 60 * 60;
+        let header = URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"ES256"}"#);
+        let claims = URL_SAFE_NO_PAD.encode(
+            serde_json::json!({ "aud": audience, "exp": expires, "sub": self.subject }).to_string(),
+        );
+        let unsigned = format!("{}.{}", header, claims);
+        let signature: Signature = self.key.sign(unsigned.as_bytes());
+        Ok(format!(
+            "vapid t={}.{}, k={}",
+            unsigned,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+            self.public_key
+        ))
+    }
+
+    fn send(&self, subscription: &Subscription, payload: &[u8]) -> Result<u16, String> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let body = encrypt(
+            &subscription.keys,
+            payload,
+            &SecretKey::random(&mut OsRng),
+            salt,
+        )?;
+        let headers = [
+            ("Authorization", self.vapid(&subscription.endpoint)?),
+            ("Content-Encoding", "aes128gcm".to_string()),
+            ("Content-Type", "application/octet-stream".to_string()),
+            ("TTL", TTL_SECS.to_string()),
+            ("Urgency", "high".to_string()),
+        ]
+        .map(|(name, value)| (name.to_string(), value));
+        notify::post(&subscription.endpoint, &headers, &body)
+    }
+
+    /// Push `payload` to every subscription. Ones the push service says are
+    /// gone are dropped.
+    pub fn send_all(&self, payload: &[u8]) {
+        let subscriptions = self.subscriptions.lock().unwrap().clone();
+        let mut gone = Vec::new();
+        for subscription in &subscriptions {
+            match self.send(subscription, payload) {
+                Ok(200..=299) => {}
+                Ok(404 | 410) => gone.push(subscription.endpoint.clone()),
+                Ok(status) => eprintln!(
+                    "Push service {} answered {}",
+                    origin(&subscription.endpoint).unwrap_or_default(),
+                    status
+                ),
+                Err(e) => eprintln!("Web Push failed: {}", e),
+            }
+        }
+        for endpoint in gone {
+            println!("Dropping expired push subscription");
+            if let Err(e) = self.unsubscribe(&endpoint) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::VerifyingKey;
+    use p256::ecdsa::signature::Verifier as _;
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// The browser's side of [`encrypt`].
+    fn decrypt(ua_secret: &SecretKey, auth: &[u8], body: &[u8]) -> Vec<u8> {
+        let (salt, rest) = body.split_at(16);
+        assert_eq!(rest[..4], RECORD_SIZE.to_be_bytes());
+        let key_len = rest[4] as usize;
+        let (as_public, ciphertext) = rest[5..].split_at(key_len);
+        let as_key = PublicKey::from_sec1_bytes(as_public).unwrap();
+        let shared = p256::ecdh::diffie_hellman(ua_secret.to_nonzero_scalar(), as_key.as_affine());
+        let mut key_info = b"WebPush: info\0".to_vec();
+        key_info.extend_from_slice(ua_secret.public_key().to_encoded_point(false).as_bytes());
+        key_info.extend_from_slice(as_public);
+        let mut ikm = [0u8; 32];
+        hkdf(auth, shared.raw_secret_bytes(), &key_info, &mut ikm);
+        let mut cek = [0u8; 16];
+        hkdf(salt, &ikm, b"Content-Encoding: aes128gcm\0", &mut cek);
+        let mut nonce = [0u8; 12];
+        hkdf(salt, &ikm, b"Content-Encoding: nonce\0", &mut nonce);
+        let mut plaintext = Aes128Gcm::new_from_slice(&cek)
+            .unwrap()
+            .decrypt(Nonce::from_slice(&nonce), ciphertext)
+            .unwrap();
+        assert_eq!(plaintext.pop(), Some(2));
+        plaintext
+    }
+
+    /// The example in RFC 8291, Appendix A.
+    #[test]
+    fn test_encrypt_rfc8291_example() {
+        let keys = Keys {
+            p256dh: "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4".to_string(),
+            auth: "BTBZMqHH6r4Tts7J_aSIgg".to_string(),
+        };
+        let as_private =
+            SecretKey::from_slice(&decode("yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw").unwrap())
+                .unwrap();
+        let salt: [u8; 16] = decode("DGv6ra1nlYgDCS1FRnbzlw")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let body = encrypt(
+            &keys,
+            b"When I grow up, I want to be a watermelon",
+            &as_private,
+            salt,
+        )
+        .unwrap();
+        assert_eq!(
+            URL_SAFE_NO_PAD.encode(body),
+            "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN"
+        );
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let ua_secret = SecretKey::random(&mut OsRng);
+        let auth = [7u8; 16];
+        let keys = Keys {
+            p256dh: URL_SAFE_NO_PAD
+                .encode(ua_secret.public_key().to_encoded_point(false).as_bytes()),
+            auth: URL_SAFE_NO_PAD.encode(auth),
+        };
+        let body = encrypt(
+            &keys,
+            b"{\"event\":\"done\"}",
+            &SecretKey::random(&mut OsRng),
+            [1; 16],
+        )
+        .unwrap();
+        assert_eq!(decrypt(&ua_secret, &auth, &body), b"{\"event\":\"done\"}");
+
+        let bad = Keys {
+            p256dh: "AAAA".to_string(),
+            auth: keys.auth.clone(),
+        };
+        assert!(encrypt(&bad, b"x", &SecretKey::random(&mut OsRng), [1; 16]).is_err());
+    }
+
+    #[test]
+    fn test_vapid_and_subscriptions() {
+        let dir = env::temp_dir().join(format!("vnccc-test-webpush-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let push = WebPush::load(&dir, "mailto:me@example.com").unwrap();
+        let mode = fs::metadata(dir.join("vapid.key"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // The same key is used after a restart
+        let again = WebPush::load(&dir, "mailto:me@example.com").unwrap();
+        assert_eq!(push.public_key(), again.public_key());
+
+        let header = push.vapid("https://push.example.com/send/abc?x=1").unwrap();
+        let token = header
+            .strip_prefix("vapid t=")
+            .and_then(|rest| rest.split(", k=").next())
+            .unwrap();
+        let (unsigned, signature) = token.rsplit_once('.').unwrap();
+        let claims: serde_json::Value =
+            serde_json::from_slice(&decode(unsigned.split('.').nth(1).unwrap()).unwrap()).unwrap();
+        assert_eq!(claims["aud"], "https://push.example.com");
+        let public = decode(push.public_key()).unwrap();
+        let signature = Signature::from_slice(&decode(signature).unwrap()).unwrap();
+        VerifyingKey::from_sec1_bytes(&public)
+            .unwrap()
+            .verify(unsigned.as_bytes(), &signature)
+            .unwrap();
+
+        let ua_secret = SecretKey::random(&mut OsRng);
+        let subscription = Subscription {
+            endpoint: "https://push.example.com/send/abc".to_string(),
+            keys: Keys {
+                p256dh: URL_SAFE_NO_PAD
+                    .encode(ua_secret.public_key().to_encoded_point(false).as_bytes()),
+                auth: URL_SAFE_NO_PAD.encode([1u8; 16]),
+            },
+        };
+        push.subscribe(subscription.clone()).unwrap();
+        push.subscribe(subscription.clone()).unwrap();
+        let mut insecure = subscription.clone();
+        insecure.endpoint = "http://push.example.com/x".to_string();
+        assert!(push.subscribe(insecure).is_err());
+        let reloaded = WebPush::load(&dir, "mailto:me@example.com").unwrap();
+        assert_eq!(
+            *reloaded.subscriptions.lock().unwrap(),
+            vec![subscription.clone()]
+        );
+        assert!(push.unsubscribe(&subscription.endpoint).unwrap());
+        assert!(!push.unsubscribe(&subscription.endpoint).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,912 @@
+//! Provisioning of workspaces: the first session's from the `WORKSPACE_*`
+//! environment variables, later ones from the API. A workspace is a mounted
+//! directory as is, a fresh clone, or a git worktree of a mounted repo.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Read as _;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::git;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Use the base directory as it is.
+    #[default]
+    Mount,
+    /// Clone `WORKSPACE_REPO` into a new directory under the temp base.
+    #[serde(alias = "checkout")]
+    Clone,
+    /// Add a worktree of the base repo on a new branch.
+    Worktree,
+}
+
+impl Mode {
+    fn parse(value: &str) -> Result<Mode, String> {
+        match value {
+            "" | "mount" => Ok(Mode::Mount),
+            // `checkout` is what provision-workspace.sh called it
+            "clone" | "checkout" => Ok(Mode::Clone),
+            "worktree" => Ok(Mode::Worktree),
+            other => Err(format!(
+                "WORKSPACE_MODE must be mount, clone, checkout or worktree, got '{}'",
+                other
+            )),
+        }
+    }
+}
+
+/// What to provision, read from the environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spec {
+    pub mode: Mode,
+    /// Git URL or GitHub `owner/repo` shorthand, for clone mode.
+    pub repo: Option<String>,
+    /// Branch, tag or full commit hash to check out.
+    pub git_ref: Option<String>,
+    /// New branch for a worktree, `vncaa/<directory name>` when unset.
+    pub branch: Option<String>,
+    /// The mounted directory; the repo path argument unless `WORKSPACE_BASE` is set.
+    pub base: PathBuf,
+    /// Where clones and worktrees are created.
+    pub tmp_base: PathBuf,
+    /// Clone depth, 0 for full history.
+    pub depth: u32,
+    pub submodules: bool,
+    /// Remove what was created on shutdown.
+    pub cleanup: bool,
+}
+
+impl Spec {
+    pub fn from_env(default_base: &str) -> Result<Spec, String> {
+        Spec::from_vars(|name| std::env::var(name).ok(), default_base)
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>, default_base: &str) -> Result<Spec, String> {
+        let var = |name: &str| var(name).filter(|v| !v.is_empty());
+        let flag = |name: &str, default: bool| match var(name).as_deref() {
+            None => Ok(default),
+            Some("1" | "true" | "yes") => Ok(true),
+            Some("0" | "false" | "no") => Ok(false),
+            Some(other) => Err(format!("{} must be 1 or 0, got '{}'", name, other)),
+        };
+        let mode = Mode::parse(var("WORKSPACE_MODE").as_deref().unwrap_or(""))?;
+        let base = PathBuf::from(var("WORKSPACE_BASE").unwrap_or_else(|| default_base.to_string()));
+        let tmp_base = var("WORKSPACE_TMP_BASE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| base.join(".vncaa-checkouts"));
+        let depth = match var("WORKSPACE_DEPTH") {
+            Some(depth) => depth
+                .parse()
+                .map_err(|_| format!("WORKSPACE_DEPTH must be a number, got '{}'", depth))?,
+            None => 1,
+        };
+        let repo = var("WORKSPACE_REPO");
+        if mode == Mode::Clone && repo.is_none() {
+            return Err("WORKSPACE_REPO must be set for clone mode".to_string());
+        }
+        Ok(Spec {
+            mode,
+            repo,
+            git_ref: var("WORKSPACE_REF"),
+            branch: None,
+            base,
+            tmp_base,
+            depth,
+            submodules: flag("WORKSPACE_SUBMODULES", false)?,
+            cleanup: flag("WORKSPACE_CLEANUP", true)?,
+        })
+    }
+
+    /// What `request` asks for, taking the rest from this startup spec.
+    /// A worktree without a path is made of `current`, the repo the
+    /// session works in.
+    pub fn for_request(&self, request: &Request, current: Option<&Path>) -> Result<Spec, String> {
+        let path = match request.path.as_deref().filter(|p| !p.is_empty()) {
+            Some(path) if !Path::new(path).is_absolute() => {
+                return Err(format!("Workspace path '{}' is not absolute", path));
+            }
+            path => path.map(PathBuf::from),
+        };
+        let repo = request.repo.clone().filter(|r| !r.is_empty());
+        let base = match (request.mode, &path) {
+            (Mode::Mount, None) => return Err("A path is needed to open a directory".to_string()),
+            (Mode::Clone, _) if repo.is_none() => {
+                return Err("A repo is needed to clone".to_string());
+            }
+            (Mode::Clone, _) => self.base.clone(),
+            (_, Some(path)) => path.clone(),
+            (Mode::Worktree, None) => current.unwrap_or(&self.base).to_path_buf(),
+        };
+        let tmp_base = match (request.mode, &path) {
+            (Mode::Worktree, Some(path)) if *path != self.base => path.join(".vncaa-checkouts"),
+            _ => self.tmp_base.clone(),
+        };
+        Ok(Spec {
+            mode: request.mode,
+            repo,
+            git_ref: request.git_ref.clone().filter(|r| !r.is_empty()),
+            branch: request.branch.clone().filter(|b| !b.is_empty()),
+            base,
+            tmp_base,
+            depth: request.depth.unwrap_or(self.depth),
+            submodules: request.submodules.unwrap_or(self.submodules),
+            cleanup: self.cleanup,
+        })
+    }
+}
+
+/// A workspace to open at runtime, as sent to the API.
+#[derive(Debug, Default, Deserialize)]
+pub struct Request {
+    #[serde(default)]
+    pub mode: Mode,
+    /// Directory to open, or the repo to add a worktree of.
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub repo: Option<String>,
+    #[serde(default, rename = "ref")]
+    pub git_ref: Option<String>,
+    /// Branch for a worktree.
+    #[serde(default)]
+    pub branch: Option<String>,
+    #[serde(default)]
+    pub depth: Option<u32>,
+    #[serde(default)]
+    pub submodules: Option<bool>,
+}
+
+/// Clone URL for `repo`: URLs and paths as given, `owner/name` on GitHub.
+fn repo_url(repo: &str) -> Result<String, String> {
+    if repo.contains("://")
+        || repo.contains(':')
+        || repo.starts_with('/')
+        || repo.starts_with('.')
+        || repo.starts_with('~')
+    {
+        return Ok(repo.to_string());
+    }
+    let shorthand = repo.split('/').count() == 2
+        && repo.split('/').all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        });
+    if shorthand {
+        Ok(format!(
+            "https://github.com/{}.git",
+            repo.trim_end_matches(".git")
+        ))
+    } else {
+        Err(format!("'{}' is neither a git URL nor owner/repo", repo))
+    }
+}
+
+/// Whether `git_ref` is a full commit hash, which `clone --branch` cannot take.
+fn is_commit(git_ref: &str) -> bool {
+    matches!(git_ref.len(), 40 | 64) && git_ref.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Provisioning,
+    Ready,
+    Failed,
+}
+
+/// Progress of provisioning, for the web UI.
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub mode: Mode,
+    pub state: State,
+    /// Latest progress line, or the error.
+    pub message: String,
+    pub path: Option<String>,
+}
+
+/// A git worktree on its own branch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Worktree {
+    /// The repo it was added to.
+    pub repo: PathBuf,
+    pub path: PathBuf,
+    pub branch: String,
+}
+
+/// What becomes of a session's worktree when the session is stopped.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Teardown {
+    /// Leave the worktree and its branch alone.
+    #[default]
+    Keep,
+    /// Merge the branch into the repo's checked out branch, then remove both.
+    Merge,
+    /// Remove the worktree and its branch, uncommitted work and all.
+    Delete,
+}
+
+impl Worktree {
+    /// Whether the worktree has uncommitted changes, or cannot be checked.
+    pub fn is_dirty(&self) -> bool {
+        git::git(&self.path, &["status", "--porcelain"])
+            .map(|s| !s.is_empty())
+            .unwrap_or(true)
+    }
+
+    /// Merge the branch into the repo's checked out branch, then remove the
+    /// worktree and the branch. Uncommitted changes in the worktree are not
+    /// merged, so they stop it; a conflict leaves everything as it was.
+    pub fn merge(&self) -> Result<String, String> {
+        if self.is_dirty() {
+            return Err(format!(
+                "{} has uncommitted changes; commit or discard them before merging",
+                self.path.display()
+            ));
+        }
+        let target = git::git(&self.repo, &["rev-parse", "--abbrev-ref", "HEAD"])?;
+        let target = target.trim();
+        if target == "HEAD" {
+            return Err(format!(
+                "{} has no branch checked out to merge into",
+                self.repo.display()
+            ));
+        }
+        if let Err(e) = git::git(&self.repo, &["merge", "--no-edit", &self.branch]) {
+            let _ = git::git(&self.repo, &["merge", "--abort"]);
+            return Err(format!("Merging {} failed: {}", self.branch, e.trim()));
+        }
+        let path = self.path.to_string_lossy();
+        git::git(&self.repo, &["worktree", "remove", &path])?;
+        git::git(&self.repo, &["branch", "-d", &self.branch])?;
+        Ok(format!("Merged {} into {}", self.branch, target))
+    }
+
+    /// Remove the worktree and its branch.
+    pub fn delete(&self) -> Result<String, String> {
+        let path = self.path.to_string_lossy();
+        git::git(&self.repo, &["worktree", "remove", "--force", &path])?;
+        git::git(&self.repo, &["branch", "-D", &self.branch])?;
+        Ok(format!(
+            "Deleted worktree {} and branch {}",
+            path, self.branch
+        ))
+    }
+
+    pub fn teardown(&self, how: Teardown) -> Result<String, String> {
+        match how {
+            Teardown::Keep => Ok(format!(
+                "Kept worktree {} on branch {}",
+                self.path.display(),
+                self.branch
+            )),
+            Teardown::Merge => self.merge(),
+            Teardown::Delete => self.delete(),
+        }
+    }
+}
+
+/// Something provisioning created, to remove on shutdown.
+#[derive(Debug)]
+enum Created {
+    Clone(PathBuf),
+    Worktree(Worktree),
+}
+
+#[derive(Debug)]
+pub struct Workspace {
+    pub path: PathBuf,
+    created: Option<Created>,
+}
+
+impl Workspace {
+    /// The worktree, when provisioning added one.
+    pub fn worktree(&self) -> Option<&Worktree> {
+        match &self.created {
+            Some(Created::Worktree(worktree)) => Some(worktree),
+            _ => None,
+        }
+    }
+}
+
+/// A workspace opened before, remembered in the state directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recent {
+    pub path: String,
+    pub mode: Mode,
+    #[serde(default)]
+    pub repo: Option<String>,
+    #[serde(default, rename = "ref")]
+    pub git_ref: Option<String>,
+    /// When it was last opened, in seconds since the epoch.
+    pub opened: u64,
+}
+
+/// How many recent workspaces are kept.
+const MAX_RECENT: usize = 20;
+
+/// Recent workspaces stored in `file`, most recent first.
+pub fn recent(file: &Path) -> Vec<Recent> {
+    fs::read_to_string(file)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// Put `entry` at the top of the recent workspaces in `file`.
+pub fn remember(file: &Path, entry: Recent) -> Result<(), String> {
+    let mut entries = recent(file);
+    entries.retain(|e| e.path != entry.path);
+    entries.insert(0, entry);
+    entries.truncate(MAX_RECENT);
+    let contents = serde_json::to_string_pretty(&entries).map_err(|e| e.to_string())?;
+    crate::config::write_atomic(file, &contents)
+}
+
+/// Tracks provisioning for the web UI and what to clean up afterwards.
+pub struct Provisioning {
+    status: Mutex<Status>,
+    /// Clones and worktrees made so far; mounted directories are not kept.
+    workspaces: Mutex<Vec<Workspace>>,
+    /// The startup spec, which requests at runtime fill in from.
+    spec: Spec,
+    /// Where recent workspaces are remembered, if there is a state directory.
+    recent_file: Option<PathBuf>,
+}
+
+impl Provisioning {
+    pub fn new(spec: &Spec) -> Provisioning {
+        Provisioning {
+            status: Mutex::new(Status {
+                mode: spec.mode,
+                state: State::Provisioning,
+                message: "Starting".to_string(),
+                path: None,
+            }),
+            workspaces: Mutex::new(Vec::new()),
+            spec: spec.clone(),
+            recent_file: crate::config::state_dir().map(|dir| dir.join("workspaces.json")),
+        }
+    }
+
+    pub fn spec(&self) -> &Spec {
+        &self.spec
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Start provisioning another workspace, unless one is on its way.
+    pub fn begin(&self, mode: Mode) -> Result<(), String> {
+        let mut status = self.status.lock().unwrap();
+        if status.state == State::Provisioning {
+            return Err("Another workspace is being provisioned".to_string());
+        }
+        *status = Status {
+            mode,
+            state: State::Provisioning,
+            message: "Starting".to_string(),
+            path: None,
+        };
+        Ok(())
+    }
+
+    pub fn progress(&self, message: &str) {
+        self.status.lock().unwrap().message = message.to_string();
+    }
+
+    /// Record the outcome of provisioning `spec`, returning the workspace
+    /// path. A ready workspace goes to the top of the recent list.
+    pub fn finish(
+        &self,
+        spec: &Spec,
+        result: Result<Workspace, String>,
+    ) -> Result<PathBuf, String> {
+        let mut status = self.status.lock().unwrap();
+        match result {
+            Ok(workspace) => {
+                let path = workspace.path.clone();
+                status.state = State::Ready;
+                status.message = format!("Workspace ready at {}", path.display());
+                status.path = Some(path.to_string_lossy().into_owned());
+                if let Some(file) = &self.recent_file {
+                    let entry = Recent {
+                        path: path.to_string_lossy().into_owned(),
+                        mode: spec.mode,
+                        repo: spec.repo.clone(),
+                        git_ref: spec.git_ref.clone(),
+                        opened: SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .map(|d| d.as_secs())
+                            .unwrap_or(0),
+                    };
+                    if let Err(e) = remember(file, entry) {
+                        eprintln!("Failed to remember workspace: {}", e);
+                    }
+                }
+                if workspace.created.is_some() {
+                    self.workspaces.lock().unwrap().push(workspace);
+                }
+                Ok(path)
+            }
+            Err(e) => {
+                status.state = State::Failed;
+                status.message = e.clone();
+                Err(e)
+            }
+        }
+    }
+
+    /// Recent workspaces, most recent first.
+    pub fn recent(&self) -> Vec<Recent> {
+        self.recent_file.as_deref().map(recent).unwrap_or_default()
+    }
+
+    /// Remove the clones and worktrees, when cleanup is on. Work that exists
+    /// only there is kept: a clone with uncommitted changes or unpushed
+    /// commits stays, and a dirty worktree is not removed.
+    pub fn cleanup(&self) {
+        if !self.spec.cleanup {
+            return;
+        }
+        let workspaces = std::mem::take(&mut *self.workspaces.lock().unwrap());
+        for workspace in workspaces {
+            remove(workspace);
+        }
+    }
+}
+
+/// Remove a clone or worktree unless work would be lost.
+fn remove(workspace: Workspace) {
+    match workspace.created {
+        None => {}
+        Some(Created::Clone(path)) => {
+            let dirty = git::git(&path, &["status", "--porcelain"])
+                .map(|s| !s.is_empty())
+                .unwrap_or(true);
+            let unpushed = git::git(
+                &path,
+                &["log", "--oneline", "-1", "--branches", "--not", "--remotes"],
+            )
+            .map(|s| !s.is_empty())
+            .unwrap_or(true);
+            if dirty || unpushed {
+                println!(
+                    "Keeping {}: it has uncommitted changes or unpushed commits",
+                    path.display()
+                );
+            } else if let Err(e) = fs::remove_dir_all(&path) {
+                eprintln!("Failed to remove {}: {}", path.display(), e);
+            } else {
+                println!("Removed {}", path.display());
+            }
+        }
+        Some(Created::Worktree(Worktree { repo, path, .. })) => {
+            let path_arg = path.to_string_lossy();
+            match git::git(&repo, &["worktree", "remove", &path_arg]) {
+                Ok(_) => println!("Removed worktree {}; its branch is kept", path.display()),
+                Err(e) => println!("Keeping worktree {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+/// Create a new, uniquely named directory under `parent`. A new `parent` gets
+/// a `.gitignore`, since it usually lives inside the mounted repo.
+fn unique_dir(parent: &Path) -> Result<PathBuf, String> {
+    if !parent.is_dir() {
+        fs::create_dir_all(parent).map_err(|e| format!("{}: {}", parent.display(), e))?;
+        fs::write(parent.join(".gitignore"), "*\n")
+            .map_err(|e| format!("{}: {}", parent.display(), e))?;
+    }
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    for n in 0..100u128 {
+        let name = format!(
+            "{:06x}",
+            (seed + n * 7919 + std::process::id() as u128) & 0xffffff
+        );
+        let path = parent.join(name);
+        match fs::create_dir(&path) {
+            Ok(()) => return Ok(path),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+    Err(format!("No free directory name in {}", parent.display()))
+}
+
+/// Run a git command, passing each line of its progress output to `progress`.
+fn run_git(dir: &Path, args: &[&str], progress: &dyn Fn(&str)) -> Result<(), String> {
+    let mut cmd = git::command(dir, args);
+    // Fail instead of waiting for a password nobody can type
+    cmd.env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| format!("Failed to run git: {}", e))?;
+    let mut stderr = child.stderr.take().ok_or("No git output")?;
+    let mut last = String::new();
+    let mut line = Vec::new();
+    let mut buf = [0u8; 4096];
+    // Progress counters are rewritten in place with \r
+    while let Ok(n) = stderr.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+        for &byte in &buf[..n] {
+            if byte == b'\r' || byte == b'\n' {
+                let text = String::from_utf8_lossy(&line).trim().to_string();
+                if !text.is_empty() {
+                    progress(&text);
+                    last = text;
+                }
+                line.clear();
+            } else {
+                line.push(byte);
+            }
+        }
+    }
+    let status = child
+        .wait()
+        .map_err(|e| format!("Failed to run git: {}", e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("git {} failed: {}", args[0], last))
+    }
+}
+
+fn clone_into(spec: &Spec, dest: &Path, progress: &dyn Fn(&str)) -> Result<(), String> {
+    let url = repo_url(spec.repo.as_deref().unwrap_or_default())?;
+    let depth = spec.depth.to_string();
+    let commit = spec.git_ref.as_deref().filter(|r| is_commit(r));
+
+    // Private GitHub repos authenticate through gh, which reads GH_TOKEN or
+    // its own config, as `gh repo clone` did
+    let mut args: Vec<&str> = Vec::new();
+    let path_var = std::env::var("PATH").unwrap_or_default();
+    if url.starts_with("https://github.com/") && crate::terminal::is_on_path("gh", &path_var) {
+        args.extend([
+            "-c",
+            "credential.https://github.com.helper=",
+            "-c",
+            "credential.https://github.com.helper=!gh auth git-credential",
+        ]);
+    }
+    args.extend(["clone", "--progress"]);
+    if spec.depth > 0 {
+        args.extend(["--depth", &depth]);
+    }
+    match (&spec.git_ref, commit) {
+        (_, Some(_)) => args.push("--no-checkout"),
+        (Some(branch), None) => args.extend(["--branch", branch]),
+        (None, None) => {}
+    }
+    if spec.submodules && commit.is_none() {
+        args.push("--recurse-submodules");
+        if spec.depth > 0 {
+            args.push("--shallow-submodules");
+        }
+    }
+    let dest_arg = dest.to_string_lossy();
+    args.extend(["--", &url, &dest_arg]);
+    progress(&format!("Cloning {}", url));
+    run_git(dest.parent().unwrap_or(dest), &args, progress)?;
+
+    // A commit is fetched on its own, since shallow clones only have tips
+    if let Some(commit) = commit {
+        let mut fetch = vec!["fetch", "--progress"];
+        if spec.depth > 0 {
+            fetch.extend(["--depth", &depth]);
+        }
+        fetch.extend(["origin", commit]);
+        run_git(dest, &fetch, progress)?;
+        run_git(dest, &["checkout", "--detach", commit], progress)?;
+        if spec.submodules {
+            run_git(
+                dest,
+                &["submodule", "update", "--init", "--recursive"],
+                progress,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Provision the workspace described by `spec`, reporting progress lines.
+/// Anything half created is removed again on failure.
+pub fn provision(spec: &Spec, progress: &dyn Fn(&str)) -> Result<Workspace, String> {
+    match spec.mode {
+        Mode::Mount => {
+            if !spec.base.is_dir() {
+                return Err(format!("{} is not a directory", spec.base.display()));
+            }
+            progress(&format!("Using mounted workspace {}", spec.base.display()));
+            Ok(Workspace {
+                path: spec.base.clone(),
+                created: None,
+            })
+        }
+        Mode::Clone => {
+            let dest = unique_dir(&spec.tmp_base)?;
+            if let Err(e) = clone_into(spec, &dest, progress) {
+                let _ = fs::remove_dir_all(&dest);
+                return Err(e);
+            }
+            Ok(Workspace {
+                path: dest.clone(),
+                created: Some(Created::Clone(dest)),
+            })
+        }
+        Mode::Worktree => {
+            let repo = git::toplevel(&spec.base)
+                .ok_or_else(|| format!("{} is not in a git repository", spec.base.display()))?;
+            let dest = unique_dir(&spec.tmp_base)?;
+            let name = dest
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let branch = spec
+                .branch
+                .clone()
+                .unwrap_or_else(|| format!("vncaa/{}", name));
+            if git::git(&repo, &["check-ref-format", "--branch", &branch]).is_err()
+                || branch.starts_with('-')
+            {
+                let _ = fs::remove_dir(&dest);
+                return Err(format!("'{}' is not a valid branch name", branch));
+            }
+            let dest_arg = dest.to_string_lossy();
+            let start = spec.git_ref.as_deref().unwrap_or("HEAD");
+            progress(&format!("Adding worktree on branch {}", branch));
+            if let Err(e) = run_git(
+                &repo,
+                &["worktree", "add", "-b", &branch, "--", &dest_arg, start],
+                progress,
+            ) {
+                let _ = fs::remove_dir_all(&dest);
+                return Err(e);
+            }
+            Ok(Workspace {
+                path: dest.clone(),
+                created: Some(Created::Worktree(Worktree {
+                    repo,
+                    path: dest,
+                    branch,
+                })),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::tests::temp_repo;
+    use std::collections::HashMap;
+
+    fn spec(vars: &[(&str, &str)]) -> Result<Spec, String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Spec::from_vars(|name| vars.get(name).cloned(), "/repo")
+    }
+
+    #[test]
+    fn test_spec_from_vars() {
+        let mount = spec(&[]).unwrap();
+        assert_eq!(mount.mode, Mode::Mount);
+        assert_eq!(mount.base, PathBuf::from("/repo"));
+        assert_eq!(mount.tmp_base, PathBuf::from("/repo/.vncaa-checkouts"));
+        assert!(mount.cleanup);
+        assert_eq!(mount.depth, 1);
+
+        let checkout = spec(&[
+            ("WORKSPACE_MODE", "checkout"),
+            ("WORKSPACE_REPO", "toozej/vncaa"),
+            ("WORKSPACE_REF", "main"),
+            ("WORKSPACE_CLEANUP", "0"),
+            ("WORKSPACE_DEPTH", "0"),
+            ("WORKSPACE_BASE", "/work"),
+        ])
+        .unwrap();
+        assert_eq!(checkout.mode, Mode::Clone);
+        assert_eq!(checkout.git_ref.as_deref(), Some("main"));
+        assert_eq!(checkout.tmp_base, PathBuf::from("/work/.vncaa-checkouts"));
+        assert!(!checkout.cleanup);
+        assert_eq!(checkout.depth, 0);
+
+        assert!(spec(&[("WORKSPACE_MODE", "clone")]).is_err());
+        assert!(spec(&[("WORKSPACE_MODE", "copy")]).is_err());
+        assert!(spec(&[("WORKSPACE_DEPTH", "deep")]).is_err());
+    }
+
+    #[test]
+    fn test_spec_for_request() {
+        let defaults = spec(&[]).unwrap();
+        let request = |json: &str| -> Request { serde_json::from_str(json).unwrap() };
+
+        let mount = defaults
+            .for_request(&request(r#"{"path": "/other"}"#), None)
+            .unwrap();
+        assert_eq!(mount.mode, Mode::Mount);
+        assert_eq!(mount.base, PathBuf::from("/other"));
+
+        let clone = defaults
+            .for_request(
+                &request(r#"{"mode": "checkout", "repo": "a/b", "ref": "dev", "depth": 0}"#),
+                None,
+            )
+            .unwrap();
+        assert_eq!(clone.mode, Mode::Clone);
+        assert_eq!(clone.git_ref.as_deref(), Some("dev"));
+        assert_eq!(clone.tmp_base, defaults.tmp_base);
+        assert_eq!(clone.depth, 0);
+
+        let worktree = defaults
+            .for_request(
+                &request(r#"{"mode": "worktree"}"#),
+                Some(Path::new("/current")),
+            )
+            .unwrap();
+        assert_eq!(worktree.base, PathBuf::from("/current"));
+        let worktree = defaults
+            .for_request(&request(r#"{"mode": "worktree", "path": "/x"}"#), None)
+            .unwrap();
+        assert_eq!(worktree.tmp_base, PathBuf::from("/x/.vncaa-checkouts"));
+
+        assert!(defaults.for_request(&request("{}"), None).is_err());
+        assert!(
+            defaults
+                .for_request(&request(r#"{"path": "relative"}"#), None)
+                .is_err()
+        );
+        assert!(
+            defaults
+                .for_request(&request(r#"{"mode": "clone"}"#), None)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_repo_url() {
+        assert_eq!(
+            repo_url("toozej/vncaa").unwrap(),
+            "https://github.com/toozej/vncaa.git"
+        );
+        assert_eq!(
+            repo_url("git@gitlab.com:a/b.git").unwrap(),
+            "git@gitlab.com:a/b.git"
+        );
+        assert_eq!(
+            repo_url("https://example.com/x.git").unwrap(),
+            "https://example.com/x.git"
+        );
+        assert!(repo_url("just-a-name").is_err());
+        assert!(repo_url("a/b/c").is_err());
+        assert!(is_commit(&"a".repeat(40)));
+        assert!(!is_commit("main"));
+    }
+
+    #[test]
+    fn test_clone_and_worktree() {
+        let origin = temp_repo("provision-origin");
+        fs::write(origin.join("README"), "hello\n").unwrap();
+        git::git(&origin, &["add", "README"]).unwrap();
+        git::git(&origin, &["commit", "-qm", "first"]).unwrap();
+        git::git(&origin, &["branch", "feature"]).unwrap();
+
+        let base = temp_repo("provision-base");
+        let mut spec = spec(&[]).unwrap();
+        spec.mode = Mode::Clone;
+        spec.repo = Some(format!("file://{}", origin.display()));
+        spec.git_ref = Some("feature".to_string());
+        spec.tmp_base = base.join(".vncaa-checkouts");
+        let lines = Mutex::new(Vec::new());
+        let workspace =
+            provision(&spec, &|line| lines.lock().unwrap().push(line.to_string())).unwrap();
+        assert_eq!(
+            fs::read_to_string(workspace.path.join("README")).unwrap(),
+            "hello\n"
+        );
+        assert!(lines.lock().unwrap()[0].starts_with("Cloning"));
+        // The checkout directory stays out of the mounted repo's status
+        assert_eq!(git::git(&base, &["status", "--porcelain"]).unwrap(), "");
+
+        let mut provisioning = Provisioning::new(&spec);
+        provisioning.recent_file = Some(base.join("workspaces.json"));
+        let path = provisioning.finish(&spec, Ok(workspace)).unwrap();
+        assert_eq!(provisioning.status().state, State::Ready);
+        let recent = provisioning.recent();
+        assert_eq!(recent[0].path, path.to_string_lossy());
+        assert_eq!(recent[0].git_ref.as_deref(), Some("feature"));
+        assert!(provisioning.begin(Mode::Clone).is_ok());
+        assert!(provisioning.begin(Mode::Clone).is_err());
+        provisioning.cleanup();
+        assert!(!path.exists());
+
+        spec.repo = Some("file:///nonexistent".to_string());
+        assert!(provision(&spec, &|_| {}).is_err());
+        assert_eq!(fs::read_dir(&spec.tmp_base).unwrap().count(), 1);
+
+        spec.mode = Mode::Worktree;
+        spec.base = origin.clone();
+        spec.git_ref = None;
+        let worktree = provision(&spec, &|_| {}).unwrap();
+        assert!(worktree.path.join("README").exists());
+        let mut provisioning = Provisioning::new(&spec);
+        provisioning.recent_file = None;
+        let path = provisioning.finish(&spec, Ok(worktree)).unwrap();
+        provisioning.cleanup();
+        assert!(!path.exists());
+        let branches = git::git(&origin, &["branch", "--list", "vncaa/*"]).unwrap();
+        assert!(!branches.is_empty());
+
+        fs::remove_dir_all(origin).unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_worktree_teardown() {
+        let repo = temp_repo("teardown");
+        fs::write(repo.join("README"), "hello\n").unwrap();
+        git::git(&repo, &["add", "README"]).unwrap();
+        git::git(&repo, &["commit", "-qm", "first"]).unwrap();
+        let mut spec = spec(&[]).unwrap();
+        spec.mode = Mode::Worktree;
+        spec.base = repo.clone();
+        spec.tmp_base = repo.join(".vncaa-checkouts");
+        spec.branch = Some("vncaa/task".to_string());
+        let add = || {
+            provision(&spec, &|_| {})
+                .unwrap()
+                .worktree()
+                .cloned()
+                .unwrap()
+        };
+
+        let worktree = add();
+        assert_eq!(worktree.branch, "vncaa/task");
+        fs::write(worktree.path.join("README"), "changed\n").unwrap();
+        assert!(worktree.merge().unwrap_err().contains("uncommitted"));
+        git::git(&worktree.path, &["commit", "-qam", "change"]).unwrap();
+        worktree.teardown(Teardown::Merge).unwrap();
+        assert!(!worktree.path.exists());
+        assert_eq!(
+            fs::read_to_string(repo.join("README")).unwrap(),
+            "changed\n"
+        );
+        assert_eq!(
+            git::git(&repo, &["branch", "--list", "vncaa/task"]).unwrap(),
+            ""
+        );
+
+        let worktree = add();
+        fs::write(worktree.path.join("scratch"), "x").unwrap();
+        worktree.teardown(Teardown::Delete).unwrap();
+        assert!(!worktree.path.exists());
+        assert_eq!(
+            git::git(&repo, &["branch", "--list", "vncaa/task"]).unwrap(),
+            ""
+        );
+
+        spec.branch = Some("bad..name".to_string());
+        assert!(provision(&spec, &|_| {}).is_err());
+
+        fs::remove_dir_all(repo).unwrap();
+    }
+}
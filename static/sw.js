@@ -0,0 +1,30 @@
+// Shows the notifications vnccc pushes and opens the session on a click.
+
+self.addEventListener('push', event => {
+    let data = {};
+    try {
+        data = event.data ? event.data.json() : {};
+    } catch (e) {
+        data = { message: event.data.text() };
+    }
+    event.waitUntil(self.registration.showNotification(data.title || 'vncaa', {
+        body: data.message || '',
+        icon: '/static/android-chrome-192x192.png',
+        badge: '/static/favicon-32x32.png',
+        // One notification per session and event, replaced by newer ones
+        tag: `${data.session || ''}:${data.event || ''}`,
+        renotify: true,
+        data: { url: data.url || '/' },
+    }));
+});
+
+self.addEventListener('notificationclick', event => {
+    event.notification.close();
+    const url = new URL(event.notification.data.url, self.location.origin).href;
+    event.waitUntil((async () => {
+        const windows = await self.clients.matchAll({ type: 'window', includeUncontrolled: true });
+        const open = windows.find(client => client.url === url);
+        if (open) return open.focus();
+        return self.clients.openWindow(url);
+    })());
+});